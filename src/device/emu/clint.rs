@@ -1,8 +1,10 @@
 use crate::device::glob_timer;
+use crate::device::interrupt::InterruptBits;
 use crate::isa::riscv64::csr::InterruptMask;
 use crate::isa::riscv64::vaddr::MemOperationSize;
 use crate::memory::paddr::PAddr;
 use crate::memory::IOMap;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
 use log::trace;
 use std::sync::atomic::Ordering::{Relaxed, SeqCst};
 use std::sync::atomic::{AtomicBool, AtomicU64};
@@ -19,21 +21,32 @@ const MTIMECMP_OFFSET: usize = 0x4000;
 const MTIME_OFFSET: usize = 0xBFF8;
 
 pub struct CLINT {
-    // cpu_interrupt_bits: Arc<AtomicU64>,
-    mtimecmp_update_notify: Sender<()>,
+    cpu_interrupt_bits: Arc<InterruptBits>,
+    /// None with virtual time, the deadline is checked by the cpu instead of a thread
+    mtimecmp_update_notify: Option<Sender<()>>,
     mtimecmp: Arc<AtomicU64>,
 }
 
 impl CLINT {
-    pub fn new(cpu_interrupt_bits: Arc<AtomicU64>, stopped: Arc<AtomicBool>) -> Self {
-        let (tx, rx) = sync::mpsc::channel::<()>();
-
+    pub fn new(cpu_interrupt_bits: Arc<InterruptBits>, stopped: Arc<AtomicBool>) -> Self {
         let mtimecmp = Arc::new(AtomicU64::new(1145141919810));
+        if glob_timer.is_virtual() {
+            glob_timer.set_deadline_us(mtimecmp.load(SeqCst));
+            return Self {
+                cpu_interrupt_bits,
+                mtimecmp_update_notify: None,
+                mtimecmp,
+            };
+        }
+
+        let (tx, rx) = sync::mpsc::channel::<()>();
         let mtimecmp_clone = mtimecmp.clone();
+        let cpu_interrupt_bits_clone = cpu_interrupt_bits.clone();
         thread::spawn(move || {
+            let cpu_interrupt_bits = cpu_interrupt_bits_clone;
             while !stopped.load(Relaxed) {
                 let mtimecmp = mtimecmp_clone.load(SeqCst);
-                let now = glob_timer.lock().unwrap().since_boot_us();
+                let now = glob_timer.since_boot_us();
                 let wait_res = if mtimecmp > now {
                     trace!(
                         "mtimecmp({}) > now({}), next trigger at {}ms",
@@ -62,11 +75,27 @@ impl CLINT {
         });
 
         Self {
-            // cpu_interrupt_bits,
-            mtimecmp_update_notify: tx,
+            cpu_interrupt_bits,
+            mtimecmp_update_notify: Some(tx),
             mtimecmp,
         }
     }
+
+    fn mtimecmp_updated(&self) {
+        match &self.mtimecmp_update_notify {
+            Some(notify) => notify.send(()).unwrap(),
+            None => self.update_virtual_deadline(),
+        }
+    }
+
+    fn update_virtual_deadline(&self) {
+        let mtip = InterruptMask::MTimerInt as u64;
+        if glob_timer.set_deadline_us(self.mtimecmp.load(SeqCst)) {
+            self.cpu_interrupt_bits.fetch_and(!mtip, SeqCst);
+        } else {
+            self.cpu_interrupt_bits.fetch_or(mtip, SeqCst);
+        }
+    }
 }
 
 impl IOMap for CLINT {
@@ -74,6 +103,10 @@ impl IOMap for CLINT {
         0x10000
     }
 
+    fn nondeterministic(&self) -> bool {
+        true
+    }
+
     fn read(&self, offset: usize, len: MemOperationSize) -> u64 {
         if offset % (len as usize) != 0 {
             panic!("misaligned access of clint")
@@ -83,7 +116,7 @@ impl IOMap for CLINT {
                 todo!("read msip");
             }
             MTIMECMP_OFFSET => len.read_val(self.mtimecmp.load(SeqCst)),
-            MTIME_OFFSET => len.read_val(glob_timer.lock().unwrap().since_boot_us()),
+            MTIME_OFFSET => len.read_val(glob_timer.since_boot_us()),
             _ => 0,
         }
     }
@@ -100,9 +133,9 @@ impl IOMap for CLINT {
             }
             MTIMECMP_OFFSET => {
                 self.mtimecmp.store(len.read_val(data), SeqCst);
-                println!("mtime: {}", glob_timer.lock().unwrap().since_boot_us());
+                println!("mtime: {}", glob_timer.since_boot_us());
                 println!("write mtimecmp val: {}", data);
-                self.mtimecmp_update_notify.send(()).unwrap();
+                self.mtimecmp_updated();
             }
             MTIME_OFFSET => {
                 todo!("write mtime val: {}", data)
@@ -110,4 +143,14 @@ impl IOMap for CLINT {
             _ => {}
         }
     }
+
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_u64(self.mtimecmp.load(SeqCst));
+    }
+
+    fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.mtimecmp.store(r.get_u64()?, SeqCst);
+        self.mtimecmp_updated();
+        Ok(())
+    }
 }
@@ -106,6 +106,11 @@ impl IOMap for Keyboard {
     fn len(&self) -> usize {
         8
     }
+
+    fn nondeterministic(&self) -> bool {
+        true
+    }
+
     fn read(&self, offset: usize, len: MemOperationSize) -> u64 {
         unsafe { len.read_sized(self.mem.get_unchecked(offset)) }
     }
@@ -1,10 +1,11 @@
+use crate::device::interrupt::InterruptBits;
 use crate::isa::riscv64::csr::InterruptMask;
 use crate::isa::riscv64::vaddr::MemOperationSize;
 use crate::memory::paddr::PAddr;
 use crate::memory::IOMap;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
 use log::{debug, trace};
 use std::cell::UnsafeCell;
-use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering::SeqCst;
 use std::sync::Arc;
 
@@ -13,7 +14,7 @@ const PLIC_NDEV: usize = 16;
 const NHART: usize = 1;
 
 pub struct PLIC {
-    cpu_interrupt_bits: Arc<AtomicU64>,
+    cpu_interrupt_bits: Arc<InterruptBits>,
     priority: [u8; PLIC_NDEV],
     device_bits: u64,
     enable_bits: [u64; NHART * 2],
@@ -21,7 +22,7 @@ pub struct PLIC {
 }
 
 impl PLIC {
-    pub fn new(cpu_interrupt_bits: Arc<AtomicU64>) -> Self {
+    pub fn new(cpu_interrupt_bits: Arc<InterruptBits>) -> Self {
         Self {
             cpu_interrupt_bits,
             priority: [0; PLIC_NDEV],
@@ -97,6 +98,10 @@ impl IOMap for PLIC {
         0x4000000
     }
 
+    fn nondeterministic(&self) -> bool {
+        true
+    }
+
     fn read(&self, offset: usize, len: MemOperationSize) -> u64 {
         if offset % (len as usize) != 0 {
             panic!("misaligned access of PLIC")
@@ -158,4 +163,23 @@ impl IOMap for PLIC {
         }
         todo!("Write PLIC offset {:#x} data {:#x}", offset, data);
     }
+
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_bytes(&self.priority);
+        w.put_u64(self.device_bits);
+        for ctx in 0..NHART * 2 {
+            w.put_u64(self.enable_bits[ctx]);
+            w.put_u64(unsafe { (*self.pending_bits.get())[ctx] });
+        }
+    }
+
+    fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        r.get_bytes_into(&mut self.priority)?;
+        self.device_bits = r.get_u64()?;
+        for ctx in 0..NHART * 2 {
+            self.enable_bits[ctx] = r.get_u64()?;
+            self.pending_bits.get_mut()[ctx] = r.get_u64()?;
+        }
+        Ok(())
+    }
 }
@@ -1,5 +1,6 @@
-use chrono::{Datelike, Timelike};
+use chrono::{DateTime, Datelike, Timelike};
 
+use crate::device::glob_timer;
 use crate::memory::IOMap;
 use crate::memory::paddr::PAddr;
 use crate::isa::riscv64::vaddr::MemOperationSize;
@@ -18,9 +19,20 @@ impl IOMap for RTC {
     fn len(&self) -> usize {
         32
     }
+
+    fn nondeterministic(&self) -> bool {
+        true
+    }
+
     fn read(&self, offset: usize, len: MemOperationSize) -> u64 {
-        let now = chrono::offset::Local::now();
-        let micro =  now.timestamp_micros();
+        // with virtual time, the clock starts at unix epoch to keep runs reproducible
+        let (now, micro) = if glob_timer.is_virtual() {
+            let now = DateTime::from_timestamp_micros(glob_timer.since_boot_us() as i64).unwrap();
+            (now.naive_utc(), now.timestamp_micros())
+        } else {
+            let now = chrono::offset::Local::now();
+            (now.naive_local(), now.timestamp_micros())
+        };
         let mem = [now.second(), now.minute(), now.hour(), now.day(), now.month(), now.year() as u32, (micro & 0xffffffff) as u32, (micro >> 32) as u32];
         unsafe {
             let mem: *const u8 = mem.get_unchecked(offset / 4) as *const u32 as *const u8;
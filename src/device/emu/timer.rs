@@ -1,43 +1,121 @@
 use std::ptr::addr_of;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
 use std::time::SystemTime;
-// use std::sync::Arc;
-// use std::sync::atomic::{AtomicBool, AtomicU64};
-// use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
-// use std::thread;
-// use std::time::{Duration, SystemTime};
 
+use crate::device::glob_timer;
 use crate::isa::riscv64::vaddr::MemOperationSize;
 use crate::memory::paddr::PAddr;
 use crate::memory::IOMap;
 
 pub const TIMER_MMIO_START: PAddr = PAddr::new(0xa0000048);
 
+/// Instructions executed by the cpu since boot. Only written by the cpu thread.
+static INST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[inline]
+pub fn inst_count() -> u64 {
+    INST_COUNT.load(Relaxed)
+}
+
+#[inline]
+pub fn inc_inst_count() {
+    INST_COUNT.store(INST_COUNT.load(Relaxed) + 1, Relaxed);
+}
+
+pub fn set_inst_count(count: u64) {
+    INST_COUNT.store(count, Relaxed);
+}
+
 pub struct Timer {
     boot_time: SystemTime,
+    /// instructions per microsecond of virtual time, 0 if time follows the host clock
+    insts_per_us: AtomicU64,
+    /// inst count at which the clint timer interrupt fires, only used with virtual time
+    deadline: AtomicU64,
 }
 
 impl Timer {
     pub fn new() -> Self {
         Self {
             boot_time: SystemTime::now(),
+            insts_per_us: AtomicU64::new(0),
+            deadline: AtomicU64::new(u64::MAX),
         }
     }
 
+    /// Switch to virtual time driven by instruction count. Must be called before devices are created.
+    pub fn set_icount(&self, insts_per_us: Option<u64>) {
+        if let Some(rate) = insts_per_us {
+            assert_ne!(rate, 0, "icount rate must be positive");
+            self.insts_per_us.store(rate, Relaxed);
+        }
+    }
+
+    pub fn is_virtual(&self) -> bool {
+        self.insts_per_us.load(Relaxed) != 0
+    }
+
     pub fn since_boot_us(&self) -> u64 {
+        if let Some(us) = inst_count().checked_div(self.insts_per_us.load(Relaxed)) {
+            return us;
+        }
         SystemTime::now()
             .duration_since(self.boot_time)
             .unwrap()
             .as_micros() as u64
     }
+
+    /// Arm the timer interrupt at virtual time `us`. Returns false if it is already due.
+    pub fn set_deadline_us(&self, us: u64) -> bool {
+        let deadline = us.saturating_mul(self.insts_per_us.load(Relaxed));
+        if deadline <= inst_count() {
+            self.deadline.store(u64::MAX, Relaxed);
+            false
+        } else {
+            self.deadline.store(deadline, Relaxed);
+            true
+        }
+    }
+
+    /// Checked by the cpu after every instruction with virtual time.
+    /// Returns true once when the armed deadline is hit.
+    #[inline]
+    pub fn deadline_reached(&self) -> bool {
+        if inst_count() < self.deadline.load(Relaxed) {
+            return false;
+        }
+        self.deadline.store(u64::MAX, Relaxed);
+        true
+    }
+
+    /// Fast forward virtual time to the armed deadline when the cpu is idle.
+    /// Returns false if no deadline is armed.
+    pub fn skip_to_deadline(&self) -> bool {
+        let deadline = self.deadline.load(Relaxed);
+        if deadline == u64::MAX {
+            return false;
+        }
+        set_inst_count(deadline);
+        self.deadline.store(u64::MAX, Relaxed);
+        true
+    }
 }
 
-impl IOMap for Timer {
+/// MMIO view of `glob_timer`
+pub struct TimerMMIO;
+
+impl IOMap for TimerMMIO {
     fn len(&self) -> usize {
         8
     }
 
+    fn nondeterministic(&self) -> bool {
+        true
+    }
+
     fn read(&self, offset: usize, len: MemOperationSize) -> u64 {
-        let time = self.since_boot_us();
+        let time = glob_timer.since_boot_us();
         let res = len.read_sized(unsafe { (addr_of!(time) as *const u8).offset(offset as isize) });
         res
     }
@@ -2,12 +2,14 @@ use crate::device::emu::plic::PLIC;
 use crate::isa::riscv64::vaddr::MemOperationSize;
 use crate::memory::paddr::PAddr;
 use crate::memory::IOMap;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
 use bitfield_struct::bitfield;
 use log::{info, trace};
 use ringbuf::storage::Heap;
 use ringbuf::traits::*;
 use ringbuf::{CachingCons, CachingProd, HeapRb, SharedRb};
-use std::cell::UnsafeCell;
+use std::cell::{RefCell, UnsafeCell};
+use std::collections::VecDeque;
 use std::process::Command;
 use std::sync::atomic::Ordering::{Relaxed, SeqCst};
 use std::sync::atomic::{AtomicBool, AtomicU8};
@@ -41,6 +43,8 @@ pub struct ISR {
 
 pub struct UART16550 {
     in_rb: UnsafeCell<CachingCons<Arc<SharedRb<Heap<u8>>>>>,
+    /// rx bytes restored from a snapshot, consumed before in_rb
+    in_restored: RefCell<VecDeque<u8>>,
     out_rb: UnsafeCell<CachingProd<Arc<SharedRb<Heap<u8>>>>>,
     out_notify: Arc<tokio::sync::Notify>,
     lcr: LCR,
@@ -146,6 +150,7 @@ impl UART16550 {
 
         Self {
             in_rb: UnsafeCell::new(in_cons),
+            in_restored: RefCell::new(VecDeque::new()),
             out_rb: UnsafeCell::new(out_prod),
             out_notify: notify_clone,
             lcr: LCR {
@@ -159,6 +164,16 @@ impl UART16550 {
         }
     }
 
+    fn rx_pop(&self) -> Option<u8> {
+        let rb = unsafe { &mut *self.in_rb.get() };
+        self.in_restored.borrow_mut().pop_front().or_else(|| rb.try_pop())
+    }
+
+    fn rx_is_empty(&self) -> bool {
+        let rb = unsafe { &*self.in_rb.get() };
+        self.in_restored.borrow().is_empty() && rb.is_empty()
+    }
+
     fn clear_interrupt(&self) {
         if self.ier.load(SeqCst) & 2 == 0 {
             self.isr.store(0b1, SeqCst);
@@ -174,19 +189,23 @@ impl IOMap for UART16550 {
     fn len(&self) -> usize {
         8
     }
+
+    fn nondeterministic(&self) -> bool {
+        true
+    }
+
     fn read(&self, offset: usize, len: MemOperationSize) -> u64 {
         assert!(len == MemOperationSize::Byte);
-        let rb = unsafe { &mut *self.in_rb.get() };
         // info!("UART read. ofs: {}", offset);
         match offset {
             0 => {
-                let res = rb.try_pop().unwrap_or(0) as u64;
+                let res = self.rx_pop().unwrap_or(0) as u64;
                 trace!(
                     "UART read. INT: {}, HAS_VALUE: {}",
                     self.ier.load(SeqCst) & 1,
-                    !rb.is_empty()
+                    !self.rx_is_empty()
                 );
-                if self.ier.load(SeqCst) & 1 == 1 && rb.is_empty() {
+                if self.ier.load(SeqCst) & 1 == 1 && self.rx_is_empty() {
                     self.clear_interrupt();
                 }
                 res
@@ -196,7 +215,7 @@ impl IOMap for UART16550 {
             3 => (self.lcr.word_len | ((self.lcr.dlab_en as u8) << 7)) as u64,
             5 => {
                 let mut res = 0x20;
-                if !rb.is_empty() {
+                if !self.rx_is_empty() {
                     res |= 0x1;
                 }
                 res
@@ -256,4 +275,33 @@ impl IOMap for UART16550 {
             _ => panic!("write to UART16550+{:#x} should not happen", offset),
         }
     }
+
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_u8(self.lcr.word_len);
+        w.put_bool(self.lcr.dlab_en);
+        w.put_u8(self.ier.load(SeqCst));
+        w.put_u8(self.isr.load(SeqCst));
+        w.put_bytes(&self.mem);
+        let rb = unsafe { &*self.in_rb.get() };
+        let fifo: Vec<u8> = self
+            .in_restored
+            .borrow()
+            .iter()
+            .chain(rb.iter())
+            .copied()
+            .collect();
+        w.put_bytes(&fifo);
+    }
+
+    fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.lcr.word_len = r.get_u8()?;
+        self.lcr.dlab_en = r.get_bool()?;
+        self.ier.store(r.get_u8()?, SeqCst);
+        self.isr.store(r.get_u8()?, SeqCst);
+        r.get_bytes_into(&mut self.mem)?;
+        // bytes received but not yet read by the guest in this session are dropped
+        self.in_rb.get_mut().clear();
+        *self.in_restored.get_mut() = r.get_bytes()?.iter().copied().collect();
+        Ok(())
+    }
 }
@@ -1,18 +1,57 @@
 use std::cell::RefCell;
 use std::ptr::{addr_of, addr_of_mut};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
 
 use crate::isa::riscv64::vaddr::MemOperationSize;
 use crate::isa::riscv64::vaddr::MemOperationSize::WORD;
 use crate::memory::paddr::PAddr;
 use crate::memory::IOMap;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
 
 pub const SCREEN_W: u32 = 320;
 pub const SCREEN_H: u32 = 200;
 pub const VGA_FRAME_BUF_MMIO_START: PAddr = PAddr::new(0xa1000000);
 pub const VGA_CTL_MMIO_START: PAddr = PAddr::new(0xa0000100);
 
+/// Frame buffer shared with the SDL thread without locking. Pixels are atomics, so a frame
+/// copied out while the cpu draws may mix two frames, but nothing races.
+pub struct FrameBuffer(Box<[AtomicU32]>);
+
+impl FrameBuffer {
+    fn new() -> Self {
+        Self(
+            (0..SCREEN_W * SCREEN_H)
+                .map(|_| AtomicU32::new(0))
+                .collect(),
+        )
+    }
+
+    /// Copy the pixels out as ARGB8888 bytes.
+    pub fn copy_to(&self, bytes: &mut [u8]) {
+        for (pixel, out) in self.0.iter().zip(bytes.chunks_exact_mut(4)) {
+            out.copy_from_slice(&pixel.load(Relaxed).to_le_bytes());
+        }
+    }
+
+    /// Only the cpu thread writes, so a partial pixel write can load and store.
+    fn write(&self, offset: usize, data: u64, len: usize) {
+        if len == 4 && offset & 0b11 == 0 {
+            self.0[offset / 4].store(data as u32, Relaxed);
+            return;
+        }
+        for (i, byte) in data.to_le_bytes()[..len].iter().enumerate() {
+            let pixel = &self.0[(offset + i) / 4];
+            let shift = (offset + i) % 4 * 8;
+            let old = pixel.load(Relaxed);
+            pixel.store(old & !(0xff << shift) | (*byte as u32) << shift, Relaxed);
+        }
+    }
+}
+
 pub struct VGA {
-    pub(crate) mem: Box<[u8]>,
+    pub(crate) fb: Arc<FrameBuffer>,
 }
 
 pub struct VGACtrl {
@@ -22,7 +61,7 @@ pub struct VGACtrl {
 impl VGA {
     pub fn new() -> Self {
         Self {
-            mem: vec![0u8; (SCREEN_W * SCREEN_H * 4) as usize].into_boxed_slice(),
+            fb: Arc::new(FrameBuffer::new()),
         }
     }
 }
@@ -56,6 +95,14 @@ impl IOMap for VGACtrl {
         }
         unsafe { len.write_sized(data, self.mem.borrow_mut().get_unchecked_mut(offset)) }
     }
+
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_bytes(&*self.mem.borrow());
+    }
+
+    fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        r.get_bytes_into(&mut *self.mem.borrow_mut())
+    }
 }
 
 impl IOMap for VGA {
@@ -64,6 +111,21 @@ impl IOMap for VGA {
     }
 
     fn write(&mut self, offset: usize, data: u64, len: MemOperationSize) {
-        unsafe { len.write_sized(data, self.mem.get_unchecked_mut(offset)) }
+        self.fb.write(offset, data, len as usize)
+    }
+
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        let mut bytes = vec![0u8; self.len()];
+        self.fb.copy_to(&mut bytes);
+        w.put_bytes(&bytes);
+    }
+
+    fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let mut bytes = vec![0u8; self.len()];
+        r.get_bytes_into(&mut bytes)?;
+        for (pixel, chunk) in self.fb.0.iter().zip(bytes.chunks_exact(4)) {
+            pixel.store(u32::from_le_bytes(chunk.try_into().unwrap()), Relaxed);
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Pending interrupt bits (mip layout) raised by devices and consumed by the cpu thread.
+///
+/// Works like an `AtomicU64`, but lets a cpu in `wfi` sleep until the bits change
+/// instead of spinning.
+pub struct InterruptBits {
+    bits: AtomicU64,
+    lock: Mutex<()>,
+    changed: Condvar,
+}
+
+impl InterruptBits {
+    pub fn new(val: u64) -> Self {
+        Self {
+            bits: AtomicU64::new(val),
+            lock: Mutex::new(()),
+            changed: Condvar::new(),
+        }
+    }
+
+    #[inline]
+    pub fn load(&self, order: Ordering) -> u64 {
+        self.bits.load(order)
+    }
+
+    pub fn store(&self, val: u64, order: Ordering) {
+        self.bits.store(val, order);
+        self.wake();
+    }
+
+    pub fn fetch_or(&self, val: u64, order: Ordering) -> u64 {
+        let old = self.bits.fetch_or(val, order);
+        if old | val != old {
+            self.wake();
+        }
+        old
+    }
+
+    pub fn fetch_and(&self, val: u64, order: Ordering) -> u64 {
+        let old = self.bits.fetch_and(val, order);
+        if old & val != old {
+            self.wake();
+        }
+        old
+    }
+
+    /// Wake up the sleeping cpu, e.g. when the emulator is stopping.
+    pub fn wake(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    /// Block until the bits differ from `observed`, `wake` is called or `timeout` passed.
+    pub fn wait_change(&self, observed: u64, timeout: Duration) {
+        let guard = self.lock.lock().unwrap();
+        if self.bits.load(Ordering::SeqCst) != observed {
+            return;
+        }
+        let _ = self.changed.wait_timeout(guard, timeout).unwrap();
+    }
+}
@@ -3,45 +3,52 @@ use crate::device::emu::keyboard::{Keyboard, KEYBOARD_MMIO_START};
 use crate::device::emu::plic::{PLIC, PLIC_MMIO_START};
 use crate::device::emu::rtc::{RTC, RTC_MMIO_START};
 use crate::device::emu::serial::{Serial, SERIAL_MMIO_START};
-use crate::device::emu::timer::{Timer, TIMER_MMIO_START};
+use crate::device::emu::timer::{Timer, TimerMMIO, TIMER_MMIO_START};
 use crate::device::emu::uart16550::{UART16550, UART16550_MMIO_START};
 use crate::device::emu::vga::{
     VGACtrl, SCREEN_H, SCREEN_W, VGA, VGA_CTL_MMIO_START, VGA_FRAME_BUF_MMIO_START,
 };
+use crate::device::interrupt::InterruptBits;
 use crate::memory::Memory;
 use crate::monitor::Args;
 use lazy_static::lazy_static;
 use sdl2::event::Event;
 use sdl2::pixels::PixelFormatEnum;
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
 
 mod emu;
+pub mod interrupt;
+pub mod replay;
 
 pub struct Devices {
     stopped: Arc<AtomicBool>,
     update_thread: JoinHandle<()>,
-    pub cpu_interrupt_bits: Arc<AtomicU64>,
+    pub cpu_interrupt_bits: Arc<InterruptBits>,
 }
 
+pub use emu::timer::{inc_inst_count, inst_count, set_inst_count};
+
 lazy_static! {
-    pub static ref glob_timer: Arc<Mutex<Timer>> = Arc::new(Mutex::new(Timer::new()));
+    pub static ref glob_timer: Timer = Timer::new();
 }
 
 impl Devices {
     pub fn new(stopped: Arc<AtomicBool>, memory: &mut Memory, args: &Args) -> Self {
+        glob_timer.set_icount(args.icount);
+        replay::init(args.record.as_ref(), args.replay.as_ref());
+
         let stopped_clone = stopped.clone();
-        let cpu_interrupt_bits = Arc::new(AtomicU64::new(0));
+        let cpu_interrupt_bits = Arc::new(InterruptBits::new(0));
+        let interrupt_bits_clone = cpu_interrupt_bits.clone();
 
-        let vga = Arc::new(Mutex::new(VGA::new()));
-        let vga_ctrl = Arc::new(Mutex::new(VGACtrl::new()));
+        let vga = VGA::new();
+        let frame_buf = vga.fb.clone();
         let keyboard = Arc::new(Mutex::new(Keyboard::new()));
-        let serial = Arc::new(Mutex::new(Serial::new()));
-        let rtc = Arc::new(Mutex::new(RTC::new()));
         let plic = Arc::new(Mutex::new(PLIC::new(cpu_interrupt_bits.clone())));
         let clint = Arc::new(Mutex::new(CLINT::new(
             cpu_interrupt_bits.clone(),
@@ -53,15 +60,15 @@ impl Devices {
             args.term_timeout,
         )));
 
-        memory.add_mmio(VGA_FRAME_BUF_MMIO_START, vga.clone());
-        memory.add_mmio(VGA_CTL_MMIO_START, vga_ctrl.clone());
-        memory.add_mmio(KEYBOARD_MMIO_START, keyboard.clone());
-        memory.add_mmio(SERIAL_MMIO_START, serial.clone());
-        memory.add_mmio(UART16550_MMIO_START, uart16550.clone());
-        memory.add_mmio(TIMER_MMIO_START, glob_timer.clone());
-        memory.add_mmio(RTC_MMIO_START, rtc.clone());
-        memory.add_mmio(PLIC_MMIO_START, plic.clone());
-        memory.add_mmio(CLINT_MMIO_START, clint.clone());
+        memory.add_local_mmio("vga-fb", VGA_FRAME_BUF_MMIO_START, Box::new(vga));
+        memory.add_local_mmio("vga-ctl", VGA_CTL_MMIO_START, Box::new(VGACtrl::new()));
+        memory.add_mmio("keyboard", KEYBOARD_MMIO_START, keyboard.clone());
+        memory.add_local_mmio("serial", SERIAL_MMIO_START, Box::new(Serial::new()));
+        memory.add_mmio("uart", UART16550_MMIO_START, uart16550.clone());
+        memory.add_local_mmio("timer", TIMER_MMIO_START, Box::new(TimerMMIO));
+        memory.add_local_mmio("rtc", RTC_MMIO_START, Box::new(RTC::new()));
+        memory.add_mmio("plic", PLIC_MMIO_START, plic.clone());
+        memory.add_mmio("clint", CLINT_MMIO_START, clint.clone());
 
         let update_thread = thread::spawn(move || {
             let sdl_context = sdl2::init().unwrap();
@@ -81,6 +88,7 @@ impl Devices {
             let mut event_pump = sdl_context.event_pump().unwrap();
 
             let stopped = stopped.clone();
+            let mut frame = vec![0u8; (SCREEN_W * SCREEN_H * 4) as usize];
             'outer: while !stopped.load(Relaxed) {
                 for event in event_pump.poll_iter() {
                     // println!("{}", event.type_id());
@@ -104,18 +112,17 @@ impl Devices {
                 }
 
                 keyboard.lock().unwrap().update();
-                {
-                    let vga_mem = &vga.lock().unwrap().mem;
-                    texture
-                        .update(None, vga_mem, (SCREEN_W * 4) as usize)
-                        .unwrap();
-                }
+                frame_buf.copy_to(&mut frame);
+                texture
+                    .update(None, &frame, (SCREEN_W * 4) as usize)
+                    .unwrap();
                 canvas.clear();
                 canvas.copy(&texture, None, None).unwrap();
                 canvas.present();
                 thread::sleep(Duration::from_millis(10));
             }
             stopped.store(true, Relaxed);
+            interrupt_bits_clone.wake();
         });
 
         Self {
@@ -127,9 +134,20 @@ impl Devices {
 
     pub fn stop(self) {
         self.stopped.store(true, Relaxed);
+        self.cpu_interrupt_bits.wake();
         self.update_thread.join().unwrap();
     }
 
+    /// No devices and no SDL window, for tests.
+    #[cfg(test)]
+    pub fn detached(stopped: Arc<AtomicBool>) -> Self {
+        Self {
+            cpu_interrupt_bits: Arc::new(InterruptBits::new(0)),
+            stopped,
+            update_thread: thread::spawn(|| {}),
+        }
+    }
+
     pub fn has_stopped(&self) -> bool {
         self.stopped.load(Relaxed)
     }
@@ -0,0 +1,275 @@
+//! Record and replay of nondeterministic inputs (`--record` / `--replay`).
+//!
+//! Everything the guest can observe from the host goes through one of three points on the cpu
+//! thread: reads of nondeterministic devices, the `time` csr and the pending interrupt bits. While
+//! recording, each observed value is logged together with the instruction count. While replaying,
+//! the logged values are returned instead of asking the devices, so the run is reproduced exactly.
+//!
+//! The log is a text file with one event per line: `<inst count> mmio <paddr> <value>`,
+//! `<inst count> time <value>` or `<inst count> irq <bits>`.
+//!
+//! Reverse debugging keeps the events in memory as well ([`enable_history`]), so after restoring
+//! a checkpoint the cpu can re-execute the same path by rewinding to the log [`position`] taken
+//! with it.
+
+use crate::device::inst_count;
+use log::{error, info};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+
+#[derive(PartialEq, Debug)]
+enum EventKind {
+    Mmio(u64),
+    Time,
+    Irq,
+}
+
+struct Event {
+    inst_count: u64,
+    kind: EventKind,
+    value: u64,
+}
+
+struct ReplayLog {
+    events: Vec<Event>,
+    /// next event to replay, `events.len()` while running live
+    pos: usize,
+    /// append live events to `events`, so they can be replayed after a rewind
+    keep_history: bool,
+    /// `events` were loaded by --replay, report when the guest runs past them
+    from_file: bool,
+    out: Option<BufWriter<File>>,
+    /// pending interrupt bits last seen by the cpu
+    irq: u64,
+}
+
+/// fast path check, the cpu observes interrupt bits every instruction
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static REPLAY: RefCell<ReplayLog> = const {
+        RefCell::new(ReplayLog {
+            events: Vec::new(),
+            pos: 0,
+            keep_history: false,
+            from_file: false,
+            out: None,
+            irq: 0,
+        })
+    };
+}
+
+fn parse_event(line: &str) -> Option<Event> {
+    let parse_num = |s: &str| match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    };
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let inst_count = parse_num(fields.first()?)?;
+    let (kind, value) = match (*fields.get(1)?, fields.len()) {
+        ("mmio", 4) => (EventKind::Mmio(parse_num(fields[2])?), fields[3]),
+        ("time", 3) => (EventKind::Time, fields[2]),
+        ("irq", 3) => (EventKind::Irq, fields[2]),
+        _ => return None,
+    };
+    Some(Event {
+        inst_count,
+        kind,
+        value: parse_num(value)?,
+    })
+}
+
+/// Must be called on the cpu thread before the cpu starts.
+pub fn init(record: Option<&String>, replay: Option<&String>) {
+    REPLAY.with_borrow_mut(|log| {
+        if let Some(path) = record {
+            let file =
+                File::create(path).unwrap_or_else(|e| panic!("couldn't create {}: {}", path, e));
+            info!("Recording nondeterministic inputs to {}", path);
+            log.out = Some(BufWriter::new(file));
+        } else if let Some(path) = replay {
+            let content = std::fs::read_to_string(path)
+                .unwrap_or_else(|e| panic!("couldn't read {}: {}", path, e));
+            for (lineno, line) in content.lines().enumerate() {
+                match parse_event(line) {
+                    Some(event) => log.events.push(event),
+                    None => panic!("{}:{}: bad replay event \"{}\"", path, lineno + 1, line),
+                }
+            }
+            info!("Replaying {} events from {}", log.events.len(), path);
+            log.from_file = true;
+        } else {
+            return;
+        }
+        ACTIVE.store(true, Relaxed);
+    })
+}
+
+/// Keep live events in memory from now on, needed to re-execute from a checkpoint.
+pub fn enable_history() {
+    REPLAY.with_borrow_mut(|log| log.keep_history = true);
+    ACTIVE.store(true, Relaxed);
+}
+
+/// Current position in the event log, to be saved together with a checkpoint.
+pub fn position() -> usize {
+    REPLAY.with_borrow(|log| log.pos)
+}
+
+/// Replay from `pos` again, after the checkpoint taken at that position has been restored.
+pub fn rewind(pos: usize) {
+    REPLAY.with_borrow_mut(|log| {
+        log.pos = pos.min(log.events.len());
+        log.irq = log.events[..log.pos]
+            .iter()
+            .rev()
+            .find(|event| event.kind == EventKind::Irq)
+            .map_or(0, |event| event.value);
+    })
+}
+
+impl ReplayLog {
+    fn is_replaying(&self) -> bool {
+        self.pos < self.events.len()
+    }
+
+    fn divergence(&mut self, expected: &EventKind) {
+        let now = inst_count();
+        if self.is_replaying() {
+            let event = &self.events[self.pos];
+            error!(
+                "Replay diverged at inst {}: guest wants {:?}, log has {:?} at inst {}. Continuing live.",
+                now, expected, event.kind, event.inst_count
+            );
+            // the rest of the log belongs to a different future
+            self.events.truncate(self.pos);
+        } else if self.from_file {
+            error!(
+                "Replay log exhausted at inst {}, guest wants {:?}. Continuing live.",
+                now, expected
+            );
+        }
+        self.from_file = false;
+        if !self.keep_history && self.out.is_none() {
+            ACTIVE.store(false, Relaxed);
+        }
+    }
+
+    /// Returns the logged value, or None if the guest has left the recorded path.
+    fn replay_value(&mut self, kind: EventKind) -> Option<u64> {
+        if let Some(event) = self.events.get(self.pos) {
+            if event.inst_count == inst_count() && event.kind == kind {
+                self.pos += 1;
+                return Some(event.value);
+            }
+        }
+        if self.is_replaying() || self.from_file {
+            self.divergence(&kind);
+        }
+        None
+    }
+
+    fn record(&mut self, kind: EventKind, value: u64) {
+        if let Some(out) = &mut self.out {
+            let res = match kind {
+                EventKind::Mmio(addr) => {
+                    writeln!(out, "{} mmio {:#x} {:#x}", inst_count(), addr, value)
+                }
+                EventKind::Time => writeln!(out, "{} time {}", inst_count(), value),
+                EventKind::Irq => writeln!(out, "{} irq {:#x}", inst_count(), value),
+            };
+            res.expect("failed to write record log");
+        }
+        if self.keep_history {
+            self.events.push(Event {
+                inst_count: inst_count(),
+                kind,
+                value,
+            });
+            self.pos = self.events.len();
+        }
+    }
+}
+
+/// A read of a nondeterministic device.
+#[inline]
+pub fn observe_mmio(paddr: u64, read: impl FnOnce() -> u64) -> u64 {
+    if !ACTIVE.load(Relaxed) {
+        return read();
+    }
+    REPLAY.with_borrow_mut(|log| {
+        if let Some(value) = log.replay_value(EventKind::Mmio(paddr)) {
+            return value;
+        }
+        let value = read();
+        log.record(EventKind::Mmio(paddr), value);
+        value
+    })
+}
+
+/// A read of the `time` csr.
+#[inline]
+pub fn observe_time(read: impl FnOnce() -> u64) -> u64 {
+    if !ACTIVE.load(Relaxed) {
+        return read();
+    }
+    REPLAY.with_borrow_mut(|log| {
+        if let Some(value) = log.replay_value(EventKind::Time) {
+            return value;
+        }
+        let value = read();
+        log.record(EventKind::Time, value);
+        value
+    })
+}
+
+/// Pending interrupt bits as seen by the cpu. `live` is the current value set by devices.
+/// Only changes are logged, so this is cheap to call every instruction.
+#[inline]
+pub fn observe_irq(live: u64) -> u64 {
+    if !ACTIVE.load(Relaxed) {
+        return live;
+    }
+    REPLAY.with_borrow_mut(|log| {
+        if log.is_replaying() {
+            let event = &log.events[log.pos];
+            if event.kind == EventKind::Irq && event.inst_count <= inst_count() {
+                log.irq = event.value;
+                log.pos += 1;
+            }
+            return log.irq;
+        }
+        if log.irq != live {
+            log.irq = live;
+            log.record(EventKind::Irq, live);
+        }
+        live
+    })
+}
+
+/// True if the replay log has an event due at the current instruction,
+/// so a cpu in wfi must not wait for the host.
+pub fn has_due_event() -> bool {
+    if !ACTIVE.load(Relaxed) {
+        return false;
+    }
+    REPLAY.with_borrow(|log| {
+        log.events
+            .get(log.pos)
+            .is_some_and(|event| event.inst_count <= inst_count())
+    })
+}
+
+/// Flush the record log.
+pub fn finish() {
+    REPLAY.with_borrow_mut(|log| {
+        if let Some(out) = &mut log.out {
+            if let Err(e) = out.flush() {
+                error!("failed to flush record log: {}", e);
+            }
+        }
+    })
+}
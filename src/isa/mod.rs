@@ -1,9 +1,13 @@
+use crate::device::interrupt::InterruptBits;
 use crate::isa::riscv64::vaddr::MemOperationSize;
+use crate::isa::riscv64::watch::{AccessWatch, WatchHit};
 use crate::memory::Memory;
-use crate::monitor::sdb::difftest_qemu::DifftestInfo;
+use crate::memory::paddr::PAddr;
+use crate::monitor::sdb::difftest::DifftestInfo;
 use crate::monitor::Args;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
 use riscv64::vaddr::VAddr;
-use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 pub(crate) mod riscv64;
@@ -12,7 +16,7 @@ pub trait Isa {
     fn new(
         stopped: Arc<AtomicBool>,
         memory: Memory,
-        cpu_interrupt_bits: Arc<AtomicU64>,
+        cpu_interrupt_bits: Arc<InterruptBits>,
         args: &Args,
     ) -> Self;
 
@@ -22,7 +26,12 @@ pub trait Isa {
     // fn cpu_state() -> Box<T>;
     fn isa_reg_display(&self);
     fn isa_get_reg_by_name(&self, name: &str) -> Result<u64, String>;
+    fn isa_set_reg_by_name(&mut self, name: &str, val: u64) -> Result<(), String>;
     fn isa_get_pc(&self) -> u64;
+    /// Physical address of the pc, None if it doesn't translate.
+    fn isa_get_pc_paddr(&self) -> Option<u64>;
+    /// Current privilege mode, e.g. "M".
+    fn isa_priv_name(&self) -> &'static str;
     // exec, true if not terminate
     fn isa_exec_once(&mut self) -> bool;
 
@@ -35,16 +44,66 @@ pub trait Isa {
     // fn isa_get_prev_inst_info(&mut self, prev_pc: &VAddr) -> Result<InstInfo, ()>;
 
     fn isa_disassemble_inst(&mut self, addr: &VAddr) -> String;
+    /// Disassemble `inst` as if it was at `pc`.
+    fn isa_disassemble(&mut self, inst: u32, pc: u64) -> String;
     // mmu
     fn read_vaddr(&mut self, addr: &VAddr, len: MemOperationSize) -> Result<u64, String>;
+    fn read_paddr(&mut self, addr: &PAddr, len: MemOperationSize) -> Result<u64, String>;
+    fn write_vaddr(&mut self, addr: &VAddr, data: u64, len: MemOperationSize)
+        -> Result<(), String>;
+    /// Read memory for the debugger without touching the TLB, page tables, traces or
+    /// devices. Device addresses are refused.
+    fn peek_vaddr(&self, addr: &VAddr, len: MemOperationSize) -> Result<u64, String>;
+    fn peek_paddr(&self, addr: &PAddr, len: MemOperationSize) -> Result<u64, String>;
+    /// `peek_vaddr` translated like an instruction fetch.
+    fn peek_inst_vaddr(&self, addr: &VAddr, len: MemOperationSize) -> Result<u64, String>;
     // todo
     // interrupt/exception
     // fn isa_raise_interrupt(no: u64, epc: VAddr) -> VAddr;
     // fn isa_query_interrupt() -> u64;
     fn isa_get_backtrace(&self) -> String;
+    /// Where the current function returns to, ra at its entry, else from the unwinder.
+    fn isa_return_address(&self) -> Option<u64>;
+    /// The last executed instructions and traps, oldest first.
+    fn isa_itrace(&mut self) -> String;
+    /// Switch the function call trace on or off, returns whether it is on now.
+    fn isa_toggle_ftrace(&mut self) -> bool;
+    /// Run an mtrace command: `on FILE|ring:N`, `off`, `filter KIND=VALUE`, `clear`, `dump`.
+    fn isa_mtrace(&mut self, cmd: &str) -> Result<String, String>;
+    /// Replace the watchpoints checked on every load and store.
+    fn isa_set_watches(&mut self, watches: Vec<AccessWatch>);
+    /// The first watched access since the last call.
+    fn isa_take_watch_hit(&mut self) -> Option<WatchHit>;
+    /// The valid mappings of the current page table.
+    fn isa_info_mem(&self) -> String;
+    /// The page table walk for `vaddr`, step by step.
+    fn isa_vtop(&self, vaddr: u64) -> String;
+    /// The valid TLB entries.
+    fn isa_info_tlb(&self) -> String;
+    /// One control register decoded, or all of them without a name.
+    fn isa_info_csr(&self, name: Option<&str>) -> Result<String, String>;
+    /// The privilege mode, trap vectors and the last traps.
+    fn isa_info_priv(&self) -> String;
+
+    // snapshot
+    fn isa_save_state(&self, w: &mut SnapshotWriter);
+    fn isa_load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String>;
+
+    // gdb stub
+    fn isa_gdb_target_xml(&self) -> String;
+    /// registers sent in a `g` packet, numbered from 0
+    fn isa_gdb_reg_count(&self) -> usize;
+    fn isa_gdb_read_reg(&self, regno: usize) -> Option<u64>;
+    fn isa_gdb_write_reg(&mut self, regno: usize, val: u64) -> Result<(), String>;
 
     // difftest
     // fn isa_difftest_check_regs(ref_r: T, pc: VAddr) -> bool;
     fn isa_difftest_init(&mut self) -> DifftestInfo;
     fn isa_difftest_check_regs(&self, difftest_regs: &Vec<u64>) -> Result<(), String>;
+    /// Whether the last instructions read devices or nondeterministic csrs, so the reference
+    /// has to be synced instead of compared. Clears the flag.
+    fn isa_difftest_take_skip(&mut self) -> bool;
+    /// Whether an interrupt was taken after the last instructions, the reference can't follow
+    /// it and has to be synced. Clears the flag.
+    fn isa_difftest_take_interrupt(&mut self) -> bool;
 }
@@ -0,0 +1,126 @@
+use crate::device::inst_count;
+use crate::isa::riscv64::csr::CSRName;
+use crate::isa::riscv64::RISCV64Privilege;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Writes retired instructions in spike's `--log-commits` format, so runs can be diffed with
+/// spike or RTL simulators offline. Instructions that trap are not logged, like in spike.
+pub struct CommitLog {
+    out: Option<Box<dyn Write>>,
+    /// instruction counts [start, end) that are logged
+    window: (u64, u64),
+    /// an instruction is executing and inside the window
+    in_flight: bool,
+    /// loads and stores of the in-flight instruction
+    mem: String,
+    line: String,
+}
+
+impl CommitLog {
+    /// Compressed with gzip if `path` ends with `.gz`.
+    pub fn new(path: &str, window: Option<(u64, u64)>) -> Self {
+        let file = File::create(path).unwrap_or_else(|e| panic!("couldn't create {}: {}", path, e));
+        let out: Box<dyn Write> = if path.ends_with(".gz") {
+            Box::new(BufWriter::new(GzEncoder::new(file, Compression::fast())))
+        } else {
+            Box::new(BufWriter::new(file))
+        };
+        let window = window.unwrap_or((0, u64::MAX));
+        info!(
+            "Commit log {}, instructions {}..{}",
+            path, window.0, window.1
+        );
+        Self {
+            out: Some(out),
+            window,
+            in_flight: false,
+            mem: String::new(),
+            line: String::new(),
+        }
+    }
+
+    /// Called before executing an instruction, whether it is inside the window.
+    #[inline]
+    pub fn begin(&mut self) -> bool {
+        let count = inst_count();
+        self.in_flight = self.out.is_some() && count >= self.window.0 && count < self.window.1;
+        self.mem.clear();
+        self.in_flight
+    }
+
+    /// The in-flight instruction trapped.
+    pub fn abort(&mut self) {
+        self.in_flight = false;
+    }
+
+    pub fn load(&mut self, vaddr: u64) {
+        if self.in_flight {
+            write!(self.mem, " mem 0x{:016x}", vaddr).unwrap();
+        }
+    }
+
+    pub fn store(&mut self, vaddr: u64, data: u64, size: usize) {
+        if self.in_flight {
+            write!(
+                self.mem,
+                " mem 0x{:016x} 0x{:0w$x}",
+                vaddr,
+                data,
+                w = size * 2
+            )
+            .unwrap();
+        }
+    }
+
+    /// Log the in-flight instruction unless it trapped.
+    pub fn commit(
+        &mut self,
+        privilege: RISCV64Privilege,
+        pc: u64,
+        inst: u64,
+        rd: Option<(u64, u64)>,
+        csr: Option<(CSRName, u64)>,
+    ) {
+        if !self.in_flight {
+            return;
+        }
+        self.in_flight = false;
+        self.line.clear();
+        write!(
+            self.line,
+            "core   0: {} 0x{:016x} (0x{:08x})",
+            privilege as u8, pc, inst
+        )
+        .unwrap();
+        if let Some((rd, val)) = rd {
+            write!(self.line, " x{:<2} 0x{:016x}", rd, val).unwrap();
+        }
+        if let Some((csr, val)) = csr {
+            let name: &'static str = csr.into();
+            write!(self.line, " c{}_{} 0x{:016x}", csr as u64, name, val).unwrap();
+        }
+        self.line.push_str(&self.mem);
+        self.line.push('\n');
+        if let Some(out) = &mut self.out {
+            if let Err(e) = out.write_all(self.line.as_bytes()) {
+                error!("commit log stopped: {}", e);
+                self.out = None;
+            }
+        }
+    }
+}
+
+impl Drop for CommitLog {
+    fn drop(&mut self) {
+        if let Some(out) = &mut self.out {
+            if let Err(e) = out.flush() {
+                error!("couldn't flush the commit log: {}", e);
+            }
+        }
+    }
+}
@@ -70,3 +70,43 @@ impl CSR for MIE {
         CSRName::mie
     }
 }
+
+/// Cause number, name, pending and enabled of each interrupt.
+fn interrupts(mip: MIP, mie: MIE) -> [(u64, &'static str, bool, bool); 7] {
+    [
+        (1, "SSI", mip.SSIP(), mie.SSIE()),
+        (3, "MSI", mip.MSIP(), mie.MSIE()),
+        (5, "STI", mip.STIP(), mie.STIE()),
+        (7, "MTI", mip.MTIP(), mie.MTIE()),
+        (9, "SEI", mip.SEIP(), mie.SEIE()),
+        (11, "MEI", mip.MEIP(), mie.MEIE()),
+        (13, "LCOFI", mip.LCOFIP(), mie.LCOFIE()),
+    ]
+}
+
+/// The names of the interrupts set in a mip, mie or mideleg value.
+pub fn interrupt_names(bits: u64) -> String {
+    let names: Vec<&str> = interrupts(MIP::from_bits(bits), MIE::new())
+        .iter()
+        .filter(|(_, _, set, _)| *set)
+        .map(|(_, name, _, _)| *name)
+        .collect();
+    names.join(", ")
+}
+
+/// Whether each interrupt is pending and enabled, and the mode handling it.
+pub fn describe_interrupts(mip: u64, mie: u64, mideleg: u64) -> String {
+    let mut res = format!("{:<8}{:<9}{:<9}handled in", "irq", "pending", "enabled");
+    for (cause, name, pending, enabled) in interrupts(MIP::from_bits(mip), MIE::from_bits(mie)) {
+        let yes_no = |b: bool| if b { "yes" } else { "no" };
+        let mode = if mideleg >> cause & 1 != 0 { "S" } else { "M" };
+        res.push_str(&format!(
+            "\n{:<8}{:<9}{:<9}{}",
+            name,
+            yes_no(pending),
+            yes_no(enabled),
+            mode
+        ));
+    }
+    res
+}
@@ -5,18 +5,21 @@ pub mod mstatus;
 pub mod satp;
 
 use crate::device::glob_timer;
+use crate::device::interrupt::InterruptBits;
+use crate::device::replay;
 use crate::isa::riscv64::csr::mstatus::MStatus;
 use crate::isa::riscv64::csr::CSRAccessLevel::RW;
 use crate::isa::riscv64::csr::CSRAccessLevel::{NotSupported, ROnly};
 use crate::isa::riscv64::reg::Reg;
 use crate::isa::riscv64::{RISCV64CpuState, RISCV64Privilege};
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
 use log::{trace, warn};
 use nohash_hasher::IntMap;
 use std::cell::UnsafeCell;
 use std::fmt::{Display, Formatter};
 use std::ops::Index;
 use std::rc::Rc;
-use std::sync::atomic::AtomicU64;
+use std::str::FromStr;
 use std::sync::atomic::Ordering::SeqCst;
 use std::sync::Arc;
 use strum::IntoEnumIterator;
@@ -51,7 +54,9 @@ pub struct CSRs {
     write_hooks: IntMap<u64, WriteHook>,
     time: Reg, // hack!
     cycles: Rc<UnsafeCell<u64>>,
-    interrupt_bits: Arc<AtomicU64>,
+    interrupt_bits: Arc<InterruptBits>,
+    /// time, cycle or pending interrupts were accessed, their values differ from the difftest ref
+    nondet_accessed: bool,
 } // name => (csr, write_mask)
 
 trait CSR: Into<u64> {
@@ -84,7 +89,7 @@ impl CSROpResult {
 }
 
 impl CSRs {
-    pub fn new(cycles: Rc<UnsafeCell<u64>>, interrupt_bits: Arc<AtomicU64>) -> Self {
+    pub fn new(cycles: Rc<UnsafeCell<u64>>, interrupt_bits: Arc<InterruptBits>) -> Self {
         #[allow(unused_mut)]
         let mut map: IntMap<u64, (u64, CSRInfo)> = IntMap::default();
         let mut write_hooks: IntMap<u64, WriteHook> = IntMap::default();
@@ -232,6 +237,7 @@ impl CSRs {
         Self {
             csrs: map,
             time: 0,
+            nondet_accessed: false,
             cycles,
             write_hooks,
             interrupt_bits,
@@ -278,15 +284,18 @@ impl CSRs {
 
         match idx {
             TIME_CSR_IDX => {
-                self.time = glob_timer.lock().unwrap().since_boot_us();
+                self.nondet_accessed = true;
+                self.time = replay::observe_time(|| glob_timer.since_boot_us());
                 Ok((&mut self.time, &info.write_mask, hook))
             }
             MCYCLE_CSR_IDX | CYCLE_CSR_IDX => unsafe {
+                self.nondet_accessed = true;
                 Ok((&mut *self.cycles.get(), &info.write_mask, hook))
             },
 
             MIP_IDX | SIP_IDX => {
-                let int = self.interrupt_bits.load(SeqCst);
+                self.nondet_accessed = true;
+                let int = replay::observe_irq(self.interrupt_bits.load(SeqCst));
                 *csr = info.write_mask & int;
                 Ok((csr, &info.write_mask, hook))
             }
@@ -295,6 +304,11 @@ impl CSRs {
         }
     }
 
+    /// Whether a nondeterministic csr was accessed since the last call.
+    pub fn take_nondet_accessed(&mut self) -> bool {
+        std::mem::take(&mut self.nondet_accessed)
+    }
+
     fn set_fast(&mut self, idx: CSRName, val: u64) {
         trace!(
             "set_fast csr {:?}: from {:#x} to {:#x}",
@@ -383,12 +397,128 @@ impl CSRs {
         res
     }
 
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        let mut idxs: Vec<&u64> = self.csrs.keys().collect();
+        idxs.sort();
+        w.put_u64(idxs.len() as u64);
+        for idx in idxs {
+            w.put_u64(*idx);
+            w.put_u64(self.csrs[idx].0);
+        }
+        w.put_u64(unsafe { *self.cycles.get() });
+    }
+
+    /// Write hooks are not called, the caller should sync derived state (e.g. MMU) itself.
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let cnt = r.get_u64()?;
+        for _ in 0..cnt {
+            let idx = r.get_u64()?;
+            let val = r.get_u64()?;
+            match self.csrs.get_mut(&idx) {
+                Some((csr, _)) => *csr = val,
+                None => return Err(format!("unknown csr {:#x} in snapshot", idx)),
+            }
+        }
+        unsafe {
+            *self.cycles.get() = r.get_u64()?;
+        }
+        Ok(())
+    }
+
     fn get_csr_name(idx: u64) -> String {
         match CSRName::from_repr(idx as usize) {
             Some(csr) => format!("{:?}", csr),
             None => format!("{:#x}", idx),
         }
     }
+
+    /// The value a read would return, without the side effects of `get_csr_mut`.
+    fn peek(&self, name: CSRName) -> u64 {
+        match name {
+            CSRName::mip | CSRName::sip => {
+                self.interrupt_bits.load(SeqCst) & self.csrs[&(name as u64)].1.write_mask
+            }
+            CSRName::mcycle | CSRName::cycle => unsafe { *self.cycles.get() },
+            CSRName::time => glob_timer.since_boot_us(),
+            _ => self[name],
+        }
+    }
+
+    /// The fields of a csr, empty for csrs that are plain numbers.
+    pub fn describe(&self, name: CSRName) -> String {
+        let val = self.peek(name);
+        match name {
+            CSRName::mstatus => MStatus::from_bits(val).describe(false),
+            CSRName::sstatus => MStatus::from_bits(val).describe(true),
+            CSRName::satp => satp::Satp::from_bits(val).describe(),
+            CSRName::mie | CSRName::mip | CSRName::sie | CSRName::sip | CSRName::mideleg => {
+                mie::interrupt_names(val)
+            }
+            CSRName::medeleg => {
+                let causes: Vec<String> = (0..64)
+                    .filter(|i| val >> i & 1 != 0)
+                    .map(cause_name)
+                    .collect();
+                causes.join(", ")
+            }
+            CSRName::mcause | CSRName::scause => cause_name(val),
+            CSRName::mtvec | CSRName::stvec => {
+                let mode = match val & 0b11 {
+                    0 => "Direct".to_string(),
+                    1 => "Vectored".to_string(),
+                    mode => mode.to_string(),
+                };
+                format!("base={:#x}, mode={}", val & !0b11, mode)
+            }
+            CSRName::misa => {
+                let extensions: String = (0..26)
+                    .filter(|i| val >> i & 1 != 0)
+                    .map(|i| (b'A' + i as u8) as char)
+                    .collect();
+                format!("RV{}{}", 16 << (val >> 62), extensions)
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// `info csr [name]`: one csr or all of them, decoded. The interrupt csrs also show
+    /// which interrupts are pending, enabled and delegated.
+    pub fn info(&self, name: Option<&str>) -> Result<String, String> {
+        let line = |csr: CSRName| {
+            let name: &'static str = csr.into();
+            let line = format!(
+                "{:<11}{:#018x}  {}",
+                name,
+                self.peek(csr),
+                self.describe(csr)
+            );
+            line.trim_end().to_string()
+        };
+        let Some(name) = name else {
+            let lines: Vec<String> = CSRName::iter().map(line).collect();
+            return Ok(lines.join("\n"));
+        };
+        let csr = CSRName::from_str(name).map_err(|_| format!("Unknown CSR: {}", name))?;
+        let mut res = line(csr);
+        if let CSRName::mie | CSRName::mip | CSRName::sie | CSRName::sip | CSRName::mideleg = csr {
+            res.push('\n');
+            res.push_str(&mie::describe_interrupts(
+                self.peek(CSRName::mip),
+                self[CSRName::mie],
+                self[CSRName::mideleg],
+            ));
+        }
+        Ok(res)
+    }
+}
+
+/// The name of an mcause or scause value, as in `MCauseCode` where it has the code.
+pub fn cause_name(code: u64) -> String {
+    match MCauseCode::from_repr(code) {
+        Some(cause) => <&'static str>::from(cause).to_string(),
+        None if code >> 63 != 0 => format!("interrupt {}", code & !(1 << 63)),
+        None => format!("exception {}", code),
+    }
 }
 
 impl Display for CSRs {
@@ -402,13 +532,14 @@ impl Display for CSRs {
     }
 }
 
-#[derive(PartialEq, IntoStaticStr, Copy, Clone, Debug)]
+#[derive(PartialEq, IntoStaticStr, EnumString, FromRepr, Copy, Clone, Debug)]
 #[repr(u64)]
 pub enum MCauseCode {
-    None = 0,
+    InstMisaligned = 0,
     InstAccessFault = 1,
     IllegalInst = 2,
     Breakpoint = 3,
+    LoadMisaligned = 4,
     LoadAccessFault = 5,
     StoreAMOMisaligned = 6,  // Store/AMO address misaligned
     StoreAMOAccessFault = 7, // Support misaligned access for store
@@ -419,10 +550,13 @@ pub enum MCauseCode {
     LoadPageFault = 13,
     StoreAMOPageFault = 15,
     DeadLoop = 128, // custom
+    SSoftInt = 0x8000000000000001,
+    MSoftInt = 0x8000000000000003,
     STimerInt = 0x8000000000000005,
     MTimerInt = 0x8000000000000007,
     SExtInt = 0x8000000000000009,
     MExtInt = 0x800000000000000b,
+    LCOFInt = 0x800000000000000d,
 }
 
 pub enum InterruptMask {
@@ -496,3 +630,42 @@ impl Index<CSRName> for CSRs {
         &self.csrs[&(index as u64)].0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describe_csrs() {
+        assert_eq!(cause_name(0), "InstMisaligned");
+        assert_eq!(cause_name(MCauseCode::STimerInt as u64), "STimerInt");
+        assert_eq!(cause_name(24), "exception 24");
+        assert_eq!(cause_name(1 << 63 | 13), "LCOFInt");
+        assert_eq!(cause_name(1 << 63 | 16), "interrupt 16");
+
+        let satp = satp::Satp::from_bits(8 << 60 | 1 << 44 | 0x80200);
+        assert_eq!(
+            satp.describe(),
+            "mode=Sv39, asid=0x1, ppn=0x80200 (root table at 0x80200000)"
+        );
+
+        // MTI pending and enabled, STI enabled and delegated
+        let table = mie::describe_interrupts(1 << 7, 1 << 7 | 1 << 5, 1 << 5);
+        let rows: Vec<Vec<&str>> = table
+            .lines()
+            .map(|l| l.split_whitespace().collect())
+            .collect();
+        assert_eq!(rows[3], ["STI", "no", "yes", "S"]);
+        assert_eq!(rows[4], ["MTI", "yes", "yes", "M"]);
+        assert_eq!(mie::interrupt_names(1 << 5 | 1 << 9), "STI, SEI");
+
+        let csrs = CSRs::new(Rc::new(UnsafeCell::new(0)), Arc::new(InterruptBits::new(0)));
+        assert!(csrs.info(Some("mstatus")).unwrap().contains("MPP=M"));
+        assert!(csrs
+            .info(Some("medeleg"))
+            .unwrap()
+            .ends_with("0x0000000000000000"));
+        assert!(csrs.info(Some("mideleg")).unwrap().contains("handled in"));
+        assert!(csrs.info(Some("nope")).is_err());
+    }
+}
@@ -84,6 +84,58 @@ impl MStatus {
         }
         to
     }
+
+    /// The set bits and the fields, like `MPP=S, SPIE, SUM, FS=Dirty`. `supervisor` leaves out
+    /// what sstatus doesn't show.
+    pub fn describe(&self, supervisor: bool) -> String {
+        fn privilege(bits: usize) -> String {
+            match RISCV64Privilege::from_repr(bits) {
+                Some(p) => format!("{:?}", p),
+                None => bits.to_string(),
+            }
+        }
+        fn ext_state(bits: usize) -> &'static str {
+            ["Off", "Initial", "Clean", "Dirty"][bits]
+        }
+        fn xlen(bits: usize) -> u32 {
+            16 << bits
+        }
+        let m = !supervisor;
+        let mut res = Vec::new();
+        let mut flag = |set: bool, name: &str| {
+            if set {
+                res.push(name.to_string())
+            }
+        };
+        flag(self.SIE(), "SIE");
+        flag(m && self.MIE(), "MIE");
+        flag(self.SPIE(), "SPIE");
+        flag(self.UBE(), "UBE");
+        flag(m && self.MPIE(), "MPIE");
+        flag(m && self.MPRV(), "MPRV");
+        flag(self.SUM(), "SUM");
+        flag(self.MXR(), "MXR");
+        flag(m && self.TVM(), "TVM");
+        flag(m && self.TW(), "TW");
+        flag(m && self.TSR(), "TSR");
+        flag(m && self.SBE(), "SBE");
+        flag(m && self.MBE(), "MBE");
+        flag(self.SD(), "SD");
+        res.push(format!("SPP={}", privilege(self.SPP() as usize)));
+        if m {
+            res.push(format!("MPP={}", privilege(self.MPP())));
+        }
+        for (name, bits) in [("FS", self.FS()), ("VS", self.VS()), ("XS", self.XS())] {
+            if bits != 0 {
+                res.push(format!("{}={}", name, ext_state(bits)));
+            }
+        }
+        res.push(format!("UXL={}", xlen(self.UXL())));
+        if m {
+            res.push(format!("SXL={}", xlen(self.SXL())));
+        }
+        res.join(", ")
+    }
 }
 
 impl CSR for MStatus {
@@ -114,4 +166,14 @@ mod tests {
         let t = MStatus::from_bits(0xa00000000);
         println!("{:?}", t);
     }
+
+    #[test]
+    fn describe() {
+        let t = MStatus::from_bits(0x8000000a00006000 | 1 << 18 | 1 << 11 | 1 << 5);
+        assert_eq!(
+            t.describe(false),
+            "SPIE, SUM, SD, SPP=U, MPP=S, FS=Dirty, UXL=64, SXL=64"
+        );
+        assert_eq!(t.describe(true), "SPIE, SUM, SD, SPP=U, FS=Dirty, UXL=64");
+    }
 }
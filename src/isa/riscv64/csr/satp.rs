@@ -5,7 +5,7 @@ use crate::isa::riscv64::RISCV64CpuState;
 use bitfield_struct::bitfield;
 use strum_macros::FromRepr;
 
-#[derive(FromRepr, PartialEq)]
+#[derive(FromRepr, PartialEq, Debug)]
 pub enum SATPMode {
     Bare = 0,
     Sv39 = 8,
@@ -26,6 +26,20 @@ impl Satp {
         let satp: Satp = (*csr).into();
         state.memory.update_translation_ctrl(&satp);
     }
+
+    pub fn describe(&self) -> String {
+        let mode = match SATPMode::from_repr(self.mode()) {
+            Some(mode) => format!("{:?}", mode),
+            None => self.mode().to_string(),
+        };
+        format!(
+            "mode={}, asid={:#x}, ppn={:#x} (root table at {:#x})",
+            mode,
+            self.asid(),
+            self.ppn(),
+            self.ppn() << 12
+        )
+    }
 }
 
 impl CSR for Satp {
@@ -0,0 +1,102 @@
+use crate::isa::riscv64::csr::MCauseCode;
+use crate::utils::symbols::symbols;
+use log::{error, info};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Function call trace, indented by call depth. Traps count as calls of their handler.
+pub struct FTrace {
+    enabled: bool,
+    depth: usize,
+    /// None logs with info!
+    out: Option<BufWriter<File>>,
+}
+
+impl FTrace {
+    /// `path` "-" traces to the log.
+    pub fn new(path: Option<&String>) -> Self {
+        let out = path.filter(|p| p.as_str() != "-").map(|p| {
+            let file = File::create(p).unwrap_or_else(|e| panic!("couldn't create {}: {}", p, e));
+            BufWriter::new(file)
+        });
+        Self {
+            enabled: path.is_some(),
+            depth: 0,
+            out,
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.depth = 0;
+    }
+
+    fn emit(&mut self, pc: u64, event: String) {
+        let line = format!(
+            "{:#x}: {:indent$}{}",
+            pc,
+            "",
+            event,
+            indent = self.depth * 2
+        );
+        match &mut self.out {
+            Some(out) => {
+                if let Err(e) = writeln!(out, "{}", line) {
+                    error!("ftrace stopped: {}", e);
+                    self.enabled = false;
+                }
+            }
+            None => info!("{}", line),
+        }
+    }
+
+    pub fn call(&mut self, pc: u64, target: u64) {
+        self.emit(
+            pc,
+            format!("call {} [{:#x}]", symbols().format(target), target),
+        );
+        self.depth += 1;
+    }
+
+    pub fn tail_call(&mut self, pc: u64, target: u64) {
+        self.emit(
+            pc,
+            format!("tail {} [{:#x}]", symbols().format(target), target),
+        );
+    }
+
+    pub fn ret(&mut self, pc: u64, target: u64) {
+        self.depth = self.depth.saturating_sub(1);
+        let from = symbols().format(pc);
+        self.emit(pc, format!("ret {} -> {}", from, symbols().format(target)));
+    }
+
+    pub fn trap(&mut self, pc: u64, cause: MCauseCode, handler: u64) {
+        let kind = if cause as u64 >> 63 != 0 {
+            "interrupt"
+        } else {
+            "trap"
+        };
+        let handler = symbols().format(handler);
+        self.emit(pc, format!("{} {:?} -> {}", kind, cause, handler));
+        self.depth += 1;
+    }
+
+    pub fn trap_ret(&mut self, pc: u64, target: u64) {
+        self.depth = self.depth.saturating_sub(1);
+        self.emit(pc, format!("xret -> {}", symbols().format(target)));
+    }
+}
+
+impl Drop for FTrace {
+    fn drop(&mut self) {
+        if let Some(out) = &mut self.out {
+            let _ = out.flush();
+        }
+    }
+}
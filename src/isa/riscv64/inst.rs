@@ -1,6 +1,6 @@
 #![allow(unused_imports)]
 
-use crate::isa::riscv64::csr::{CSRs, MCauseCode};
+use crate::isa::riscv64::csr::{CSRName, CSRs, MCauseCode};
 use crate::isa::riscv64::inst::InstType::{Zicsr, B, I, J, R, S, U};
 use crate::isa::riscv64::reg::{Reg, RegName};
 use crate::isa::riscv64::vaddr::MemOperationSize::{Byte, DWORD, QWORD, WORD};
@@ -114,7 +114,35 @@ impl Pattern {
                 .get_mut(&(self as *const Pattern))
                 .unwrap() += 1;
         });
+        if state.memory.commit_log.is_some() {
+            self.exec_logged(decode, state);
+        } else {
+            (self.op)(decode, state);
+        }
+    }
+
+    #[cold]
+    fn exec_logged(&self, decode: &Decode, state: &mut RISCV64CpuState) {
+        let log = state.memory.commit_log.as_mut().unwrap();
+        if !log.begin() {
+            (self.op)(decode, state);
+            return;
+        }
+        let privilege = state.current_priv();
+        let pc = state.pc.value();
         (self.op)(decode, state);
+
+        let rd =
+            (decode.rd != RegName::fake_zero as u64).then(|| (decode.rd, state.regs[decode.rd]));
+        // csrrw(i) always write, the others only with a nonzero rs1/uimm
+        let csr = match self.inst_type {
+            Zicsr if bits!(decode.inst, 13, 12) == 1 || decode.rs1 != 0 => {
+                CSRName::from_repr(decode.imm as usize).map(|csr| (csr, state.csrs[csr]))
+            }
+            _ => None,
+        };
+        let log = state.memory.commit_log.as_mut().unwrap();
+        log.commit(privilege, pc, decode.inst, rd, csr);
     }
 }
 
@@ -616,9 +644,8 @@ make_pattern(
         |inst, state| {
             state.regs[inst.rd] = state.pc.value() + 4;
             state.dyn_pc = Some(VAddr::new(state.pc.value().wrapping_add(inst.imm)));
-            if inst.rd == RegName::ra as u64 {
-                state.backtrace.push(state.pc.value() + 4);
-                // info!("call {:#x}", state.dyn_pc.unwrap().value());
+            if state.ftrace.enabled() {
+                state.ftrace_jump(inst.rd, None);
             }
         },
     ),
@@ -629,21 +656,9 @@ make_pattern(
         |inst, state| {
             state.dyn_pc = Some(VAddr::new(inst.src1(state).wrapping_add(inst.imm)));
             state.regs[inst.rd] = state.pc.value() + 4;
-            if inst.rs1 == RegName::ra as u64 && inst.rd == RegName::fake_zero as u64 {
-                state.backtrace.pop();
-                // info!("return to {:#x}", state.dyn_pc.unwrap().value());
+            if state.ftrace.enabled() {
+                state.ftrace_jump(inst.rd, Some(inst.rs1));
             }
-            if inst.rd == RegName::ra as u64 {
-                state.backtrace.push(state.pc.value() + 4);
-                // info!("call {:#x}", state.dyn_pc.unwrap().value());
-            }
-
-            // if let Some(addr) = state.backtrace.last() {
-            //     if state.dyn_pc.unwrap().value() == *addr {
-            //         state.backtrace.pop();
-            //     }
-            // }
-            //
         },
     ),
 
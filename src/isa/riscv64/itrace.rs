@@ -0,0 +1,143 @@
+use crate::isa::riscv64::csr::MCauseCode;
+use crate::isa::riscv64::RISCV64Privilege;
+use std::fmt::Write;
+
+#[derive(Copy, Clone)]
+enum ITraceEntry {
+    Inst {
+        pc: u64,
+        inst: u32,
+        privilege: RISCV64Privilege,
+    },
+    Trap {
+        pc: u64,
+        cause: MCauseCode,
+        to: RISCV64Privilege,
+    },
+}
+
+/// Ring buffer of the last executed instructions and traps, dumped post-mortem.
+/// Disassembly is only done when dumping.
+pub struct ITrace {
+    entries: Vec<ITraceEntry>,
+    size: usize,
+    /// index of the oldest entry once the buffer is full
+    next: usize,
+    dump_on: Vec<MCauseCode>,
+    pending_dump: bool,
+}
+
+impl ITrace {
+    pub fn new(size: usize, dump_on: Vec<MCauseCode>) -> Self {
+        Self {
+            entries: Vec::with_capacity(size),
+            size,
+            next: 0,
+            dump_on,
+            pending_dump: false,
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, entry: ITraceEntry) {
+        if self.entries.len() < self.size {
+            self.entries.push(entry);
+        } else if self.size != 0 {
+            self.entries[self.next] = entry;
+            self.next = (self.next + 1) % self.size;
+        }
+    }
+
+    #[inline]
+    pub fn push_inst(&mut self, pc: u64, inst: u64, privilege: RISCV64Privilege) {
+        self.push(ITraceEntry::Inst {
+            pc,
+            inst: inst as u32,
+            privilege,
+        });
+    }
+
+    /// Marks a trap or interrupt, requests a dump if `cause` is one of `--itrace-dump-on`.
+    pub fn push_trap(&mut self, pc: u64, cause: MCauseCode, to: RISCV64Privilege) {
+        self.push(ITraceEntry::Trap { pc, cause, to });
+        if self.dump_on.contains(&cause) {
+            self.pending_dump = true;
+        }
+    }
+
+    pub fn take_pending_dump(&mut self) -> bool {
+        std::mem::take(&mut self.pending_dump)
+    }
+
+    /// Oldest first, the last instruction is marked with an arrow.
+    pub fn format(&self, mut disassemble: impl FnMut(u32, u64) -> String) -> String {
+        if self.size == 0 {
+            return "itrace disabled\n".to_string();
+        }
+        let (newer, older) = self.entries.split_at(self.next);
+        let entries: Vec<&ITraceEntry> = older.iter().chain(newer).collect();
+        let last_inst = entries
+            .iter()
+            .rposition(|e| matches!(e, ITraceEntry::Inst { .. }));
+        let mut res = String::new();
+        for (i, entry) in entries.iter().enumerate() {
+            match **entry {
+                ITraceEntry::Inst {
+                    pc,
+                    inst,
+                    privilege,
+                } => {
+                    let arrow = if Some(i) == last_inst { "-->" } else { "   " };
+                    let asm = disassemble(inst, pc);
+                    writeln!(
+                        res,
+                        "{} {:#018x}: {:08x}  {:?}  {}",
+                        arrow,
+                        pc,
+                        inst,
+                        privilege,
+                        asm.trim()
+                    )
+                    .unwrap();
+                }
+                ITraceEntry::Trap { pc, cause, to } => {
+                    let kind = if cause as u64 >> 63 != 0 {
+                        "interrupt"
+                    } else {
+                        "trap"
+                    };
+                    writeln!(
+                        res,
+                        "    ---- {} {:?} at {:#x}, to {:?}",
+                        kind, cause, pc, to
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_entries_in_order() {
+        let mut itrace = ITrace::new(3, vec![MCauseCode::IllegalInst]);
+        for pc in 0..4 {
+            itrace.push_inst(pc * 4, 0x13, RISCV64Privilege::M);
+        }
+        itrace.push_trap(12, MCauseCode::IllegalInst, RISCV64Privilege::M);
+        assert!(itrace.take_pending_dump());
+        assert!(!itrace.take_pending_dump());
+
+        let dump = itrace.format(|_, _| "nop".to_string());
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("0x0000000000000008"));
+        assert!(lines[1].starts_with("-->") && lines[1].contains("0x000000000000000c"));
+        assert!(lines[2].contains("trap IllegalInst"));
+    }
+}
@@ -1,23 +1,35 @@
+use crate::device::interrupt::InterruptBits;
+use crate::device::replay;
+use crate::device::{glob_timer, inc_inst_count, inst_count, set_inst_count};
+use crate::isa::riscv64::commit_log::CommitLog;
 use crate::isa::riscv64::csr::mstatus::MStatus;
+use crate::isa::riscv64::csr::satp::Satp;
 use crate::isa::riscv64::csr::CSRName::{
-    mcause, medeleg, mepc, mideleg, mie, mstatus, mtval, mtvec, scause, sepc, sie, stval, stvec,
+    mcause, medeleg, mepc, mideleg, mie, mstatus, mtval, mtvec, satp, scause, sepc, sie, stval,
+    stvec,
 };
 use crate::isa::riscv64::csr::MCauseCode::{MExtInt, MTimerInt, SExtInt, STimerInt};
-use crate::isa::riscv64::csr::{CSRName, CSRs, MCauseCode};
+use crate::isa::riscv64::csr::{cause_name, CSRName, CSRs, InterruptMask, MCauseCode};
+use crate::isa::riscv64::ftrace::FTrace;
 use crate::isa::riscv64::ibuf::SetAssociativeIBuf;
 use crate::isa::riscv64::inst::{Pattern, PATTERNS};
+use crate::isa::riscv64::itrace::ITrace;
 use crate::isa::riscv64::logo::RISCV_LOGO;
 use crate::isa::riscv64::reg::RegName::{a0, a1, a2, a7, t0};
 use crate::isa::riscv64::reg::{format_regs, RegName, Registers};
-use crate::isa::riscv64::vaddr::{MemOperationSize, MMU};
+use crate::isa::riscv64::unwind::{format_frames, return_address, unwind, FrameRegs, TrapFrame};
+use crate::isa::riscv64::vaddr::{MemOperationSize, MemoryAccessType, MMU};
+use crate::isa::riscv64::watch::{AccessWatch, WatchHit};
 use crate::isa::Isa;
 use crate::memory::paddr::PAddr;
 use crate::memory::Memory;
-use crate::monitor::sdb::difftest_qemu::DifftestInfo;
+use crate::monitor::sdb::difftest::DifftestInfo;
 use crate::monitor::Args;
 use crate::utils::cfg_if_feat;
 use crate::utils::configs::CONFIG_MEM_BASE;
 use crate::utils::disasm::LLVMDisassembler;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::utils::symbols::symbols;
 use cfg_if::cfg_if;
 use log::{debug, error, info, warn};
 use std::cell::UnsafeCell;
@@ -26,26 +38,47 @@ use std::fmt::Write;
 use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::atomic::Ordering::{Relaxed, SeqCst};
-use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fs, thread};
 use strum::IntoEnumIterator;
 use strum_macros::FromRepr;
 use vaddr::MemOperationSize::DWORD;
 use vaddr::VAddr;
 
+mod commit_log;
 pub mod csr;
+mod ftrace;
 mod ibuf;
 mod inst;
+mod itrace;
 mod logo;
+mod mtrace;
 pub mod reg;
+mod unwind;
 pub mod vaddr;
+pub mod watch;
+
+/// gdb numbers csrs from 65, after x0-x31, pc and f0-f31
+const GDB_FIRST_CSR_REGNUM: usize = 65;
+
+/// Registers at the reset vector, as left by the boot rom of qemu virt: t0 holds the start
+/// address, a1 the device tree and a2 the fw_dynamic info.
+const DIFFTEST_RESET_REGS: [(RegName, u64); 3] = [(t0, 0x80000000), (a1, 0x8fe00000), (a2, 0x1028)];
+
+/// Upper bound of a single wfi sleep, so a stop request is noticed even without a wake up.
+const WFI_MAX_SLEEP: Duration = Duration::from_millis(10);
+/// interrupted contexts kept for backtraces
+const MAX_TRAP_FRAMES: usize = 16;
 
 pub struct RISCV64 {
     state: RISCV64CpuState,
     disassembler: LLVMDisassembler,
     ibuf: SetAssociativeIBuf,
     stop_at_ebreak: bool,
+    virtual_time: bool,
+    wfi_nop: bool,
     stopped: Arc<AtomicBool>,
     sorted_patterns: Vec<&'static Pattern>,
 }
@@ -64,14 +97,29 @@ pub struct RISCV64CpuState {
     dyn_pc: Option<VAddr>,
     memory: MMU,
     privilege: Rc<UnsafeCell<RISCV64Privilege>>,
-    backtrace: Vec<u64>,
+    /// contexts interrupted by traps, innermost last
+    trap_frames: Vec<TrapFrame>,
     stopping: bool,
     wfi: bool,
     cycles: Rc<UnsafeCell<u64>>,
     pub inst_counter: HashMap<*const Pattern, u64>,
-    interrupt_bits: Arc<AtomicU64>,
+    interrupt_bits: Arc<InterruptBits>,
     prev_interrupt_bits: u64,
     interrupt_cond_dirty: bool,
+    /// for difftest, the reference doesn't see the interrupts
+    interrupt_taken: bool,
+    itrace: ITrace,
+    ftrace: FTrace,
+}
+
+impl Drop for RISCV64 {
+    fn drop(&mut self) {
+        // before the state is dropped and prints registers
+        if thread::panicking() {
+            eprintln!("Last instructions:");
+            eprint!("{}", self.format_itrace());
+        }
+    }
 }
 
 impl Drop for RISCV64CpuState {
@@ -86,13 +134,14 @@ impl Drop for RISCV64CpuState {
             );
             eprintln!("Backtrace: ");
             eprint!("{}", self.get_backtrace_string());
+            replay::finish();
         }
     }
 }
 
 impl RISCV64CpuState {
     #[allow(unused_mut)]
-    fn new(memory: Memory, reset_vector: &PAddr, interrupt_bits: Arc<AtomicU64>) -> Self {
+    fn new(memory: Memory, reset_vector: &PAddr, interrupt_bits: Arc<InterruptBits>) -> Self {
         let privilege = Rc::new(UnsafeCell::new(RISCV64Privilege::M));
         let mmu = MMU::new(memory, privilege.clone());
         let cycles = Rc::new(UnsafeCell::new(0));
@@ -112,7 +161,7 @@ impl RISCV64CpuState {
             dyn_pc: None,
             memory: mmu,
             privilege,
-            backtrace: Vec::new(),
+            trap_frames: Vec::new(),
             stopping: false,
             wfi: false,
             cycles,
@@ -120,11 +169,14 @@ impl RISCV64CpuState {
             interrupt_bits,
             prev_interrupt_bits: 0,
             interrupt_cond_dirty: true,
+            interrupt_taken: false,
+            itrace: ITrace::new(0, Vec::new()),
+            ftrace: FTrace::new(None),
         }
     }
     #[inline(never)]
     pub(crate) fn handle_interrupt(&mut self) {
-        let interrupt_bits = self.interrupt_bits.load(SeqCst);
+        let interrupt_bits = replay::observe_irq(self.interrupt_bits.load(SeqCst));
         if interrupt_bits == 0 {
             return;
         }
@@ -139,7 +191,7 @@ impl RISCV64CpuState {
         // interrupt handle
         let prev_priv = self.current_priv();
         let mut goto_s_mode = false;
-        let mut cause = MCauseCode::None;
+        let mut cause = None;
 
         let mideleg_set = self.csrs[mideleg] & interrupt_bits != 0;
         let mstatus_val = MStatus::from_bits(self.csrs[mstatus]);
@@ -151,9 +203,9 @@ impl RISCV64CpuState {
             {
                 goto_s_mode = true;
                 if interrupt_bits & (1 << 5) != 0 {
-                    cause = STimerInt;
+                    cause = Some(STimerInt);
                 } else if interrupt_bits & (1 << 9) != 0 {
-                    cause = SExtInt;
+                    cause = Some(SExtInt);
                 } else {
                     panic!(
                         "Unknown S mode interrupt: {}, sie: {}",
@@ -173,9 +225,9 @@ impl RISCV64CpuState {
             }
 
             if interrupt_bits & (1 << 7) != 0 {
-                cause = MTimerInt;
+                cause = Some(MTimerInt);
             } else if interrupt_bits & (1 << 11) != 0 {
-                cause = MExtInt;
+                cause = Some(MExtInt);
             } else {
                 panic!(
                     "Unknown M mode interrupt: {}, mie: {}",
@@ -184,12 +236,12 @@ impl RISCV64CpuState {
             }
         }
 
-        if cause == MCauseCode::None {
+        let Some(cause) = cause else {
             panic!(
                 "Unknown interrupt: {}, mie: {}",
                 interrupt_bits, self.csrs[mie]
             )
-        }
+        };
 
         let next_priv = if goto_s_mode {
             RISCV64Privilege::S
@@ -206,6 +258,7 @@ impl RISCV64CpuState {
         );
         self.set_interrupt_cond_dirty();
         self.wfi = false;
+        self.interrupt_taken = true;
         self.trap_update_csrs(cause, prev_priv, next_priv, None);
     }
 
@@ -224,6 +277,16 @@ impl RISCV64CpuState {
                 }
             };
         }
+        self.itrace.push_trap(self.pc.value(), cause, next_priv);
+        if self.trap_frames.len() == MAX_TRAP_FRAMES {
+            // handlers that never return, e.g. on a context switch
+            self.trap_frames.remove(0);
+        }
+        self.trap_frames.push(TrapFrame {
+            cause,
+            privilege: prev_priv,
+            regs: self.frame_regs(),
+        });
         // update mstatus
         let mut mstatus_reg: MStatus = self.csrs[mstatus].into();
         mstatus_reg.update_when_trap(prev_priv, next_priv);
@@ -245,6 +308,11 @@ impl RISCV64CpuState {
             }
         }
 
+        if self.ftrace.enabled() {
+            let handler = self.dyn_pc.as_ref().map_or(0, |pc| pc.value());
+            self.ftrace.trap(self.pc.value(), cause, handler);
+        }
+
         // info!(
         //     "trap_update_csrs by trap/intr at pc {:#x}, cause {:?}({:#x}) from {:?} to {:?}",
         //     self.pc.value(),
@@ -257,6 +325,9 @@ impl RISCV64CpuState {
     }
 
     fn trap(&mut self, cause: MCauseCode, mtval_val: Option<u64>) {
+        if let Some(log) = &mut self.memory.commit_log {
+            log.abort();
+        }
         if cause != MCauseCode::ECallM && cause != MCauseCode::ECallS {
             let cause_name: &'static str = (&cause).into();
             debug!("trap at {:#x}, caused by {}", self.pc.value(), cause_name);
@@ -334,6 +405,10 @@ impl RISCV64CpuState {
             sepc
         };
         self.dyn_pc = Some(VAddr::new(self.csrs[xepc].into()));
+        self.trap_frames.pop();
+        if self.ftrace.enabled() {
+            self.ftrace.trap_ret(self.pc.value(), self.csrs[xepc]);
+        }
 
         // info!(
         //     "Ret at {:#x} epc: v({:#x}) p({:#x}), from {:?} to {:?}",
@@ -349,10 +424,44 @@ impl RISCV64CpuState {
         self.set_priv(next_priv);
     }
 
+    /// Classify a jal/jalr for ftrace: a call if it links to ra or t0, a return if it jumps to
+    /// ra without linking, a tail call if it jumps to the start of a function without linking.
+    fn ftrace_jump(&mut self, rd: u64, rs1: Option<u64>) {
+        let pc = self.pc.value();
+        let target = self.dyn_pc.as_ref().map_or(0, |pc| pc.value());
+        if rd == RegName::ra as u64 || rd == t0 as u64 {
+            self.ftrace.call(pc, target);
+        } else if rd == RegName::fake_zero as u64 {
+            if rs1 == Some(RegName::ra as u64) {
+                self.ftrace.ret(pc, target);
+            } else if symbols().func_at(target).is_some() {
+                self.ftrace.tail_call(pc, target);
+            }
+        }
+    }
+
+    fn frame_regs(&self) -> FrameRegs {
+        FrameRegs {
+            pc: self.pc.value(),
+            sp: self.regs[RegName::sp],
+            fp: self.regs[RegName::s0],
+            ra: Some(self.regs[RegName::ra]),
+        }
+    }
+
+    /// The stack of the current context, then those of the contexts interrupted by traps.
     fn get_backtrace_string(&self) -> String {
         let mut res = String::new();
-        for (i, addr) in self.backtrace.iter().rev().enumerate() {
-            write!(&mut res, "#{}: {:#x}\n", i, addr - 4).unwrap();
+        let mut contexts = vec![(self.current_priv(), self.frame_regs())];
+        contexts.extend(self.trap_frames.iter().rev().map(|t| (t.privilege, t.regs)));
+        for (i, (privilege, regs)) in contexts.into_iter().enumerate() {
+            match self.trap_frames.iter().rev().nth(i) {
+                Some(trap) => writeln!(res, "{:?} mode, handling {:?}:", privilege, trap.cause),
+                None if i > 0 => writeln!(res, "{:?} mode, interrupted:", privilege),
+                None => Ok(()),
+            }
+            .unwrap();
+            format_frames(&unwind(regs, |addr| self.memory.peek(addr)), &mut res);
         }
         res
     }
@@ -375,17 +484,41 @@ impl RISCV64CpuState {
     }
 }
 
+impl RISCV64 {
+    fn format_itrace(&mut self) -> String {
+        let disassembler = &mut self.disassembler;
+        self.state
+            .itrace
+            .format(|inst, pc| disassembler.disassemble(inst, pc))
+    }
+}
+
 impl Isa for RISCV64 {
     fn new(
         stopped: Arc<AtomicBool>,
         memory: Memory,
-        cpu_interrupt_bits: Arc<AtomicU64>,
+        cpu_interrupt_bits: Arc<InterruptBits>,
         args: &Args,
     ) -> Self {
         // let reset_addr: PAddr = CONFIG_MBASE + CONFIG_PC_RESET_OFFSET;
         let reset_addr: PAddr = PAddr::new(CONFIG_MEM_BASE.value());
         // let reset_addr: PAddr = PAddr::new(CONFIG_FIRMWARE_BASE.value());
-        let state = RISCV64CpuState::new(memory, &reset_addr, cpu_interrupt_bits);
+        let mut state = RISCV64CpuState::new(memory, &reset_addr, cpu_interrupt_bits);
+        state.itrace = ITrace::new(args.itrace, args.itrace_dump_on.clone());
+        state.ftrace = FTrace::new(args.ftrace.as_ref());
+        if let Some(dest) = &args.mtrace {
+            let cmds = std::iter::once(format!("on {}", dest))
+                .chain(args.mtrace_filter.iter().map(|f| format!("filter {}", f)));
+            for cmd in cmds {
+                if let Err(err) = state.memory.mtrace_command(&cmd) {
+                    error!("--mtrace: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        if let Some(path) = &args.commit_log {
+            state.memory.commit_log = Some(CommitLog::new(path, args.commit_log_window));
+        }
 
         let mut sorted_patterns = Vec::new();
         for line in fs::read_to_string("./inst_perf.txt").unwrap().lines().rev() {
@@ -403,6 +536,8 @@ impl Isa for RISCV64 {
             ),
             ibuf: SetAssociativeIBuf::new(),
             stop_at_ebreak: !args.ignore_isa_breakpoint,
+            virtual_time: args.icount.is_some(),
+            wfi_nop: args.wfi_nop,
             stopped,
             sorted_patterns,
         }
@@ -425,6 +560,9 @@ impl Isa for RISCV64 {
         if name == "pc" {
             return Ok(self.state.pc.value());
         }
+        if name == "priv" {
+            return Ok(self.state.current_priv() as u64);
+        }
         if let Ok(reg) = RegName::from_str(name) {
             return Ok(self.state.regs[reg]);
         }
@@ -434,15 +572,120 @@ impl Isa for RISCV64 {
         Err("Reg not found".to_string())
     }
 
+    fn isa_set_reg_by_name(&mut self, name: &str, val: u64) -> Result<(), String> {
+        if name == "pc" {
+            return self.isa_gdb_write_reg(32, val);
+        }
+        if name == "priv" {
+            let privilege = RISCV64Privilege::from_repr(val as usize)
+                .ok_or(format!("bad privilege {}", val))?;
+            self.state.set_priv(privilege);
+            return Ok(());
+        }
+        if let Ok(reg) = RegName::from_str(name) {
+            return self.isa_gdb_write_reg(reg as usize, val);
+        }
+        if let Ok(csr) = CSRName::from_str(name) {
+            return self.isa_gdb_write_reg(GDB_FIRST_CSR_REGNUM + csr as usize, val);
+        }
+        Err("Reg not found".to_string())
+    }
+
+    fn isa_itrace(&mut self) -> String {
+        self.format_itrace()
+    }
+
+    fn isa_toggle_ftrace(&mut self) -> bool {
+        let enabled = !self.state.ftrace.enabled();
+        self.state.ftrace.set_enabled(enabled);
+        enabled
+    }
+
+    fn isa_mtrace(&mut self, cmd: &str) -> Result<String, String> {
+        self.state.memory.mtrace_command(cmd)
+    }
+
+    fn isa_set_watches(&mut self, watches: Vec<AccessWatch>) {
+        self.state.memory.watches.set(watches);
+    }
+
+    fn isa_take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.state.memory.watches.take_hit()
+    }
+
+    fn isa_info_mem(&self) -> String {
+        self.state.memory.info_mem()
+    }
+
+    fn isa_vtop(&self, vaddr: u64) -> String {
+        self.state.memory.vtop(vaddr)
+    }
+
+    fn isa_info_tlb(&self) -> String {
+        self.state.memory.info_tlb()
+    }
+
+    fn isa_info_csr(&self, name: Option<&str>) -> Result<String, String> {
+        self.state.csrs.info(name)
+    }
+
+    fn isa_info_priv(&self) -> String {
+        let state = &self.state;
+        let csrs = &state.csrs;
+        let mut res = format!("priv: {:?}\n", state.current_priv());
+        for csr in [mstatus, mtvec, stvec, medeleg, mideleg] {
+            writeln!(res, "{:?}: {}", csr, csrs.describe(csr)).unwrap();
+        }
+        for (mode, cause, epc, tval) in [("M", mcause, mepc, mtval), ("S", scause, sepc, stval)] {
+            writeln!(
+                res,
+                "last {} trap: {} at {:#x}, {:?} {:#x}",
+                mode,
+                cause_name(csrs[cause]),
+                csrs[epc],
+                tval,
+                csrs[tval]
+            )
+            .unwrap();
+        }
+        match state.trap_frames.last() {
+            Some(trap) => write!(
+                res,
+                "handling {:?} from {:?} mode at {:#x}",
+                trap.cause, trap.privilege, trap.regs.pc
+            )
+            .unwrap(),
+            None => res.push_str("not in a trap handler"),
+        }
+        res
+    }
+
     fn isa_get_pc(&self) -> u64 {
         self.state.pc.value()
     }
 
+    fn isa_get_pc_paddr(&self) -> Option<u64> {
+        self.state
+            .memory
+            .peek_paddr(self.state.pc.value(), MemoryAccessType::X)
+    }
+
+    fn isa_priv_name(&self) -> &'static str {
+        match self.state.current_priv() {
+            RISCV64Privilege::M => "M",
+            RISCV64Privilege::S => "S",
+            RISCV64Privilege::U => "U",
+        }
+    }
+
     #[inline]
     fn isa_exec_once(&mut self) -> bool {
         if self.state.stopping || self.stopped.load(Relaxed) {
             return false;
         }
+        if self.state.itrace.take_pending_dump() {
+            error!("itrace:\n{}", self.format_itrace());
+        }
 
         let inst = self.state.memory.ifetch(&self.state.pc, DWORD);
         match inst {
@@ -450,6 +693,8 @@ impl Isa for RISCV64 {
                 self.state.trap(err, Some(self.state.pc.value()));
             }
             Ok((inst, pc_paddr)) => {
+                let privilege = self.state.current_priv();
+                self.state.itrace.push_inst(self.state.pc.value(), inst, privilege);
                 if inst == 0xff1ff06f && pc_paddr.value() == 0x80000050 {
                     // riscv-test fail
                     error!("riscv-test write-to-host triggered");
@@ -461,6 +706,7 @@ impl Isa for RISCV64 {
                     None => {
                         if inst == 0x0000006f {
                             error!("dead loop at pc {:#x}", self.state.pc.value());
+                            error!("itrace:\n{}", self.format_itrace());
                             self.state.regs[a0] = 1;
                             return false;
                         }
@@ -478,6 +724,7 @@ impl Isa for RISCV64 {
                                         .disassemble(inst as u32, self.state.pc.value())
                                 );
                                 info!("bt:\n{}", self.isa_get_backtrace());
+                                error!("itrace:\n{}", self.format_itrace());
                                 self.state.regs[a0] = 1;
                                 return false;
                             }
@@ -503,6 +750,13 @@ impl Isa for RISCV64 {
             None => self.state.pc.inc(DWORD),
         }
 
+        inc_inst_count();
+        if self.virtual_time && glob_timer.deadline_reached() {
+            self.state
+                .interrupt_bits
+                .fetch_or(InterruptMask::MTimerInt as u64, SeqCst);
+        }
+
         if self.stop_at_ebreak && self.state.csrs[mcause] == MCauseCode::Breakpoint as u64 {
             info!("ebreak at pc {:#x}", self.state.csrs[mepc]);
             info!("a0: {:#x}", self.state.regs[a0]);
@@ -510,6 +764,7 @@ impl Isa for RISCV64 {
         }
 
         while !self.stopped.load(Relaxed) {
+            let observed_bits = self.state.interrupt_bits.load(SeqCst);
             self.state.handle_interrupt();
             if let Some(pc) = &self.state.dyn_pc {
                 // TODO: fix dup code
@@ -528,9 +783,27 @@ impl Isa for RISCV64 {
             if !self.state.wfi {
                 break;
             }
+            if self.wfi_nop {
+                self.state.wfi = false;
+                break;
+            }
             unsafe {
                 *self.state.cycles.get() += 1;
             }
+            if replay::has_due_event() {
+                continue;
+            }
+            if self.virtual_time && glob_timer.skip_to_deadline() {
+                // nothing to do until the next timer event, jump ahead in virtual time
+                self.state
+                    .interrupt_bits
+                    .fetch_or(InterruptMask::MTimerInt as u64, SeqCst);
+                continue;
+            }
+            // sleep until some device raises or clears an interrupt instead of spinning
+            self.state
+                .interrupt_bits
+                .wait_change(observed_bits, WFI_MAX_SLEEP);
         }
 
         true
@@ -577,27 +850,209 @@ impl Isa for RISCV64 {
         }
     }
 
+    fn isa_disassemble(&mut self, inst: u32, pc: u64) -> String {
+        self.disassembler.disassemble(inst, pc).trim_end().to_string()
+    }
+
     fn read_vaddr(&mut self, addr: &VAddr, len: MemOperationSize) -> Result<u64, String> {
         self.state
             .memory
-            .read(addr, len)
+            .unwatched(|mmu| mmu.read(addr, len))
             .map_err(|e| format!("{:?}", e))
     }
 
+    fn read_paddr(&mut self, addr: &PAddr, len: MemOperationSize) -> Result<u64, String> {
+        self.state
+            .memory
+            .read_paddr(addr, len)
+            .ok_or(format!("{:?}", MCauseCode::LoadAccessFault))
+    }
+
+    fn write_vaddr(
+        &mut self,
+        addr: &VAddr,
+        data: u64,
+        len: MemOperationSize,
+    ) -> Result<(), String> {
+        self.state
+            .memory
+            .unwatched(|mmu| mmu.write(addr, data, len))
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn peek_vaddr(&self, addr: &VAddr, len: MemOperationSize) -> Result<u64, String> {
+        let paddr = self
+            .state
+            .memory
+            .peek_paddr(addr.value(), MemoryAccessType::R)
+            .ok_or("no valid mapping")?;
+        self.peek_paddr(&PAddr::new(paddr), len)
+    }
+
+    fn peek_paddr(&self, addr: &PAddr, len: MemOperationSize) -> Result<u64, String> {
+        self.state
+            .memory
+            .peek_pmem(addr.value(), len)
+            .ok_or("not pmem, devices aren't read by the debugger".to_string())
+    }
+
+    fn peek_inst_vaddr(&self, addr: &VAddr, len: MemOperationSize) -> Result<u64, String> {
+        let paddr = self
+            .state
+            .memory
+            .peek_paddr(addr.value(), MemoryAccessType::X)
+            .ok_or("no valid mapping")?;
+        self.peek_paddr(&PAddr::new(paddr), len)
+    }
+
     fn isa_get_backtrace(&self) -> String {
         self.state.get_backtrace_string()
     }
 
+    fn isa_return_address(&self) -> Option<u64> {
+        let state = &self.state;
+        return_address(state.frame_regs(), |addr| state.memory.peek(addr))
+    }
+
+    fn isa_gdb_target_xml(&self) -> String {
+        let mut xml = String::from(concat!(
+            r#"<?xml version="1.0"?>"#,
+            r#"<!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+            r#"<target version="1.0"><architecture>riscv:rv64</architecture>"#,
+            r#"<feature name="org.gnu.gdb.riscv.cpu">"#
+        ));
+        for reg in RegName::iter().take(32) {
+            let name: &str = reg.into();
+            let typ = match name {
+                "ra" => "code_ptr",
+                "sp" | "gp" | "tp" => "data_ptr",
+                _ => "int",
+            };
+            write!(xml, r#"<reg name="{}" bitsize="64" type="{}"/>"#, name, typ).unwrap();
+        }
+        xml.push_str(r#"<reg name="pc" bitsize="64" type="code_ptr"/></feature>"#);
+        xml.push_str(r#"<feature name="org.gnu.gdb.riscv.csr">"#);
+        for csr in CSRName::iter() {
+            let idx = csr as usize;
+            let name: &str = csr.into();
+            write!(
+                xml,
+                r#"<reg name="{}" bitsize="64" regnum="{}" group="csr"/>"#,
+                name,
+                GDB_FIRST_CSR_REGNUM + idx
+            )
+            .unwrap();
+        }
+        xml.push_str("</feature></target>");
+        xml
+    }
+
+    fn isa_gdb_reg_count(&self) -> usize {
+        33
+    }
+
+    fn isa_gdb_read_reg(&self, regno: usize) -> Option<u64> {
+        match regno {
+            0..32 => Some(self.state.regs[regno as u64]),
+            32 => Some(self.state.pc.value()),
+            _ => {
+                let idx = regno.checked_sub(GDB_FIRST_CSR_REGNUM)?;
+                CSRName::from_repr(idx).map(|csr| self.state.csrs[csr])
+            }
+        }
+    }
+
+    fn isa_gdb_write_reg(&mut self, regno: usize, val: u64) -> Result<(), String> {
+        match regno {
+            0 => Ok(()),
+            1..32 => {
+                self.state.regs[regno as u64] = val;
+                Ok(())
+            }
+            32 => {
+                self.state.pc = VAddr::new(val);
+                Ok(())
+            }
+            _ => {
+                let csr = regno
+                    .checked_sub(GDB_FIRST_CSR_REGNUM)
+                    .and_then(CSRName::from_repr)
+                    .ok_or(format!("no register {}", regno))?;
+                let res = self
+                    .state
+                    .csrs
+                    .set_n(csr, val)
+                    .map_err(|_| format!("csr {:?} is read-only", csr))?;
+                res.call_hook(&mut self.state);
+                Ok(())
+            }
+        }
+    }
+
+    fn isa_save_state(&self, w: &mut SnapshotWriter) {
+        let state = &self.state;
+        for reg in state.regs.0.iter() {
+            w.put_u64(*reg);
+        }
+        w.put_u64(state.pc.value());
+        w.put_u8(state.current_priv() as u8);
+        w.put_bool(state.wfi);
+        w.put_u64(inst_count());
+        w.put_u64(state.interrupt_bits.load(SeqCst));
+        state.csrs.save_state(w);
+        state.memory.save_state(w);
+    }
+
+    fn isa_load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let state = &mut self.state;
+        for reg in state.regs.0.iter_mut() {
+            *reg = r.get_u64()?;
+        }
+        state.pc = VAddr::new(r.get_u64()?);
+        let privilege = r.get_u8()?;
+        let privilege = RISCV64Privilege::from_repr(privilege as usize)
+            .ok_or(format!("bad privilege {} in snapshot", privilege))?;
+        state.set_priv(privilege);
+        state.wfi = r.get_bool()?;
+        set_inst_count(r.get_u64()?);
+        state.interrupt_bits.store(r.get_u64()?, SeqCst);
+        state.csrs.load_state(r)?;
+        let satp_val = Satp::from_bits(state.csrs[satp]);
+        let mstatus_val = MStatus::from_bits(state.csrs[mstatus]);
+        state.memory.load_state(r, &satp_val, &mstatus_val)?;
+
+        state.dyn_pc = None;
+        state.stopping = false;
+        state.trap_frames.clear();
+        state.prev_interrupt_bits = 0;
+        state.set_interrupt_cond_dirty();
+        Ok(())
+    }
+
     fn isa_difftest_init(&mut self) -> DifftestInfo {
-        self.state.regs[t0] = 0x80000000;
-        self.state.regs[a1] = 0x8fe00000;
-        self.state.regs[a2] = 0x1028;
+        for (reg, val) in DIFFTEST_RESET_REGS {
+            self.state.regs[reg] = val;
+        }
         DifftestInfo {
-            qemu_bin: "/opt/qemu/bin/qemu-system-riscv64".to_string(),
-            reset_vec: 0x80000000,
+            reset_vec: self.state.pc.value(),
+            reset_regs: DIFFTEST_RESET_REGS
+                .iter()
+                .map(|(reg, val)| (*reg as usize, *val))
+                .collect(),
         }
     }
 
+    fn isa_difftest_take_skip(&mut self) -> bool {
+        // evaluate both, each call clears its flag
+        let mmio = self.state.memory.take_mmio_accessed();
+        let csr = self.state.csrs.take_nondet_accessed();
+        mmio || csr
+    }
+
+    fn isa_difftest_take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.state.interrupt_taken)
+    }
+
     fn isa_difftest_check_regs(&self, difftest_regs: &Vec<u64>) -> Result<(), String> {
         if difftest_regs.len() != 33 {
             return Err(format!(
@@ -0,0 +1,339 @@
+use crate::device::inst_count;
+use crate::isa::riscv64::RISCV64Privilege;
+use crate::memory::Memory;
+use log::error;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MTraceType {
+    Read,
+    Write,
+    Fetch,
+    /// page table entry read by the page table walk
+    PtWalk,
+}
+
+impl MTraceType {
+    fn from_char(c: char) -> Option<Self> {
+        match c.to_ascii_uppercase() {
+            'R' => Some(MTraceType::Read),
+            'W' => Some(MTraceType::Write),
+            'X' => Some(MTraceType::Fetch),
+            'P' => Some(MTraceType::PtWalk),
+            _ => None,
+        }
+    }
+
+    fn as_char(&self) -> char {
+        match self {
+            MTraceType::Read => 'R',
+            MTraceType::Write => 'W',
+            MTraceType::Fetch => 'X',
+            MTraceType::PtWalk => 'P',
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct MTraceEntry {
+    inst_count: u64,
+    /// pc of the last fetched instruction
+    pc: u64,
+    vaddr: u64,
+    paddr: u64,
+    size: usize,
+    value: u64,
+    typ: MTraceType,
+    privilege: RISCV64Privilege,
+}
+
+impl MTraceEntry {
+    fn format(&self, out: &mut String) {
+        writeln!(
+            out,
+            "{:>12} pc {:#x} {:?} {} v {:#x} p {:#x} {} {:#x}",
+            self.inst_count,
+            self.pc,
+            self.privilege,
+            self.typ.as_char(),
+            self.vaddr,
+            self.paddr,
+            self.size,
+            self.value
+        )
+        .unwrap();
+    }
+}
+
+enum MTraceOut {
+    File(BufWriter<File>),
+    Ring(VecDeque<MTraceEntry>, usize),
+}
+
+/// Accesses are logged if they match every kind of filter given, an empty kind matches all.
+#[derive(Default)]
+struct MTraceFilter {
+    /// [lo, hi) physical ranges, device filters are resolved into these
+    paddr: Vec<(u64, u64)>,
+    /// [lo, hi) virtual ranges
+    vaddr: Vec<(u64, u64)>,
+    privs: Vec<RISCV64Privilege>,
+    types: Vec<MTraceType>,
+}
+
+impl MTraceFilter {
+    #[inline]
+    fn matches(
+        &self,
+        vaddr: u64,
+        paddr: u64,
+        typ: MTraceType,
+        privilege: RISCV64Privilege,
+    ) -> bool {
+        let in_ranges =
+            |ranges: &Vec<(u64, u64)>, addr| ranges.iter().any(|&(lo, hi)| lo <= addr && addr < hi);
+        (self.types.is_empty() || self.types.contains(&typ))
+            && (self.paddr.is_empty() || in_ranges(&self.paddr, paddr))
+            && (self.vaddr.is_empty() || in_ranges(&self.vaddr, vaddr))
+            && (self.privs.is_empty() || self.privs.contains(&privilege))
+    }
+
+    fn describe(&self) -> String {
+        let ranges = |ranges: &Vec<(u64, u64)>| {
+            ranges
+                .iter()
+                .map(|(lo, hi)| format!("{:#x}-{:#x}", lo, hi))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let mut res = String::new();
+        if !self.paddr.is_empty() {
+            write!(res, " paddr={}", ranges(&self.paddr)).unwrap();
+        }
+        if !self.vaddr.is_empty() {
+            write!(res, " vaddr={}", ranges(&self.vaddr)).unwrap();
+        }
+        if !self.privs.is_empty() {
+            write!(res, " priv={:?}", self.privs).unwrap();
+        }
+        if !self.types.is_empty() {
+            let types: String = self.types.iter().map(MTraceType::as_char).collect();
+            write!(res, " type={}", types).unwrap();
+        }
+        if res.is_empty() {
+            res.push_str(" none");
+        }
+        res
+    }
+}
+
+fn parse_addr(s: &str) -> Result<u64, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("bad address {}: {}", s, e))
+}
+
+/// `LO-HI` (exclusive) or `LO+LEN`
+fn parse_range(s: &str) -> Result<(u64, u64), String> {
+    if let Some((lo, len)) = s.split_once('+') {
+        let lo = parse_addr(lo)?;
+        let hi = lo
+            .checked_add(parse_addr(len)?)
+            .ok_or(format!("range {} overflows", s))?;
+        return Ok((lo, hi));
+    }
+    let (lo, hi) = s.split_once('-').ok_or("expected LO-HI or LO+LEN")?;
+    Ok((parse_addr(lo)?, parse_addr(hi)?))
+}
+
+/// Memory access trace, enabled with --mtrace or the sdb `mtrace` command.
+pub struct MTrace {
+    out: Option<MTraceOut>,
+    filter: MTraceFilter,
+    pc: u64,
+}
+
+impl MTrace {
+    pub fn new() -> Self {
+        Self {
+            out: None,
+            filter: MTraceFilter::default(),
+            pc: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.out.is_some()
+    }
+
+    #[inline]
+    pub fn access(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        size: usize,
+        value: u64,
+        typ: MTraceType,
+        privilege: RISCV64Privilege,
+    ) {
+        if typ == MTraceType::Fetch {
+            self.pc = vaddr;
+        }
+        if !self.filter.matches(vaddr, paddr, typ, privilege) {
+            return;
+        }
+        let entry = MTraceEntry {
+            inst_count: inst_count(),
+            pc: self.pc,
+            vaddr,
+            paddr,
+            size,
+            value,
+            typ,
+            privilege,
+        };
+        match &mut self.out {
+            Some(MTraceOut::File(w)) => {
+                let mut line = String::new();
+                entry.format(&mut line);
+                if let Err(err) = w.write_all(line.as_bytes()) {
+                    error!("mtrace stopped, couldn't write the trace: {}", err);
+                    self.out = None;
+                }
+            }
+            Some(MTraceOut::Ring(ring, size)) => {
+                if ring.len() == *size {
+                    ring.pop_front();
+                }
+                ring.push_back(entry);
+            }
+            None => {}
+        }
+    }
+
+    /// Run an mtrace command, shared by the command line options and sdb:
+    /// `on FILE|ring:N`, `off`, `filter KIND=VALUE[,VALUE]`, `clear`, `dump` or nothing for the
+    /// status. Filter kinds are paddr and vaddr (LO-HI or LO+LEN), dev (device name), priv (M, S
+    /// or U) and type (letters of RWXP, P for page table walks).
+    pub fn command(&mut self, cmd: &str, memory: &Memory) -> Result<String, String> {
+        let (op, arg) = cmd.trim().split_once(' ').unwrap_or((cmd.trim(), ""));
+        let arg = arg.trim();
+        match op {
+            "" => Ok(format!(
+                "mtrace {}, filters:{}",
+                match &self.out {
+                    None => "off".to_string(),
+                    Some(MTraceOut::File(_)) => "to file".to_string(),
+                    Some(MTraceOut::Ring(ring, size)) => format!("to ring {}/{}", ring.len(), size),
+                },
+                self.filter.describe()
+            )),
+            "on" => {
+                self.out = Some(match arg.strip_prefix("ring:") {
+                    Some(size) => {
+                        let size = size.parse::<usize>().map_err(|e| e.to_string())?;
+                        if size == 0 {
+                            return Err("ring size must not be 0".to_string());
+                        }
+                        MTraceOut::Ring(VecDeque::with_capacity(size), size)
+                    }
+                    None if arg.is_empty() => return Err("expected FILE or ring:N".to_string()),
+                    None => {
+                        let file = File::create(arg)
+                            .map_err(|e| format!("couldn't create {}: {}", arg, e))?;
+                        MTraceOut::File(BufWriter::new(file))
+                    }
+                });
+                Ok(format!("mtrace on, to {}", arg))
+            }
+            "off" => {
+                if let Some(MTraceOut::File(w)) = &mut self.out {
+                    w.flush().map_err(|e| e.to_string())?;
+                }
+                self.out = None;
+                Ok("mtrace off".to_string())
+            }
+            "filter" => {
+                let (kind, values) = arg.split_once('=').ok_or("expected KIND=VALUE")?;
+                for value in values.split(',') {
+                    self.add_filter(kind, value.trim(), memory)?;
+                }
+                Ok(format!("filters:{}", self.filter.describe()))
+            }
+            "clear" => {
+                self.filter = MTraceFilter::default();
+                Ok("filters cleared".to_string())
+            }
+            "dump" => match &self.out {
+                Some(MTraceOut::Ring(ring, _)) => {
+                    let mut res = String::new();
+                    ring.iter().for_each(|entry| entry.format(&mut res));
+                    Ok(res)
+                }
+                _ => Err("mtrace isn't writing to a ring buffer".to_string()),
+            },
+            _ => Err(format!("unknown mtrace command {}", op)),
+        }
+    }
+
+    fn add_filter(&mut self, kind: &str, value: &str, memory: &Memory) -> Result<(), String> {
+        match kind {
+            "paddr" => self.filter.paddr.push(parse_range(value)?),
+            "vaddr" => self.filter.vaddr.push(parse_range(value)?),
+            "dev" => {
+                let range = memory.find_device(value).ok_or(format!(
+                    "no device {}, devices: {}",
+                    value,
+                    memory.device_names().join(", ")
+                ))?;
+                self.filter.paddr.push(range);
+            }
+            "priv" => self.filter.privs.push(match value {
+                "M" | "m" => RISCV64Privilege::M,
+                "S" | "s" => RISCV64Privilege::S,
+                "U" | "u" => RISCV64Privilege::U,
+                _ => return Err(format!("bad privilege {}", value)),
+            }),
+            "type" => {
+                for c in value.chars() {
+                    let typ = MTraceType::from_char(c).ok_or(format!("bad access type {}", c))?;
+                    self.filter.types.push(typ);
+                }
+            }
+            _ => return Err(format!("unknown filter kind {}", kind)),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MTrace {
+    fn drop(&mut self) {
+        if let Some(MTraceOut::File(w)) = &mut self.out {
+            let _ = w.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_combine() {
+        let mut filter = MTraceFilter::default();
+        assert!(filter.matches(0x1000, 0x1000, MTraceType::Read, RISCV64Privilege::U));
+        filter.paddr.push(parse_range("0x10000000+0x100").unwrap());
+        filter.types.push(MTraceType::Write);
+        assert!(filter.matches(0, 0x10000005, MTraceType::Write, RISCV64Privilege::S));
+        assert!(!filter.matches(0, 0x10000005, MTraceType::Read, RISCV64Privilege::S));
+        assert!(!filter.matches(0, 0x10000100, MTraceType::Write, RISCV64Privilege::S));
+        filter.privs.push(RISCV64Privilege::M);
+        assert!(!filter.matches(0, 0x10000005, MTraceType::Write, RISCV64Privilege::S));
+        assert!(parse_range("0xffffffffffffff00+0x100").is_err());
+    }
+}
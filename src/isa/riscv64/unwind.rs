@@ -0,0 +1,185 @@
+//! Guest stack unwinding, with DWARF call frame information when an ELF provides it and
+//! frame pointers otherwise.
+
+use crate::isa::riscv64::csr::MCauseCode;
+use crate::isa::riscv64::RISCV64Privilege;
+use crate::utils::dwarf::{FrameRule, RegRule};
+use crate::utils::symbols::symbols;
+use std::fmt::Write;
+
+const MAX_FRAMES: usize = 64;
+const RA: u16 = 1;
+const SP: u16 = 2;
+const FP: u16 = 8;
+
+/// The registers needed to unwind from a pc. Only the innermost frame knows ra.
+#[derive(Clone, Copy)]
+pub struct FrameRegs {
+    pub pc: u64,
+    pub sp: u64,
+    pub fp: u64,
+    pub ra: Option<u64>,
+}
+
+/// The context a trap interrupted, kept until the matching xret.
+pub struct TrapFrame {
+    pub cause: MCauseCode,
+    pub privilege: RISCV64Privilege,
+    pub regs: FrameRegs,
+}
+
+fn step_cfi(
+    regs: &FrameRegs,
+    rule: &FrameRule,
+    read: &impl Fn(u64) -> Option<u64>,
+) -> Option<FrameRegs> {
+    let base = match rule.cfa_reg {
+        SP => regs.sp,
+        FP => regs.fp,
+        _ => return None,
+    };
+    let cfa = base.wrapping_add(rule.cfa_offset as u64);
+    let load = |rule: RegRule, current: Option<u64>| match rule {
+        RegRule::Unchanged => current,
+        RegRule::AtCfa(ofs) => read(cfa.wrapping_add(ofs as u64)),
+        RegRule::Undefined => None,
+    };
+    Some(FrameRegs {
+        pc: load(rule.regs[0], regs.ra)?,
+        sp: cfa,
+        fp: load(rule.regs[1], Some(regs.fp)).unwrap_or(0),
+        ra: None,
+    })
+}
+
+/// The standard frame record: ra at fp-8 and the caller's fp at fp-16. A leaf function only
+/// saves fp at fp-8 and keeps ra in its register, which is told apart like Linux does: the
+/// value looks like a frame pointer a bit further up the stack.
+fn step_fp(regs: &FrameRegs, read: &impl Fn(u64) -> Option<u64>) -> Option<FrameRegs> {
+    let fp = regs.fp;
+    // at reset or without frame pointers fp may be 0, there is no record below it
+    if fp < 16 || fp < regs.sp || !fp.is_multiple_of(16) {
+        return None;
+    }
+    let saved = read(fp - 8)?;
+    if let Some(ra) = regs.ra {
+        if saved > fp && saved - fp < 0x10000 && saved.is_multiple_of(16) {
+            return Some(FrameRegs {
+                pc: ra,
+                sp: fp,
+                fp: saved,
+                ra: None,
+            });
+        }
+    }
+    Some(FrameRegs {
+        pc: saved,
+        sp: fp,
+        fp: read(fp - 16)?,
+        ra: None,
+    })
+}
+
+/// The pcs of the frames, innermost first. `read` loads a qword from the guest.
+pub fn unwind(mut regs: FrameRegs, read: impl Fn(u64) -> Option<u64>) -> Vec<u64> {
+    let mut pcs = vec![regs.pc];
+    while pcs.len() < MAX_FRAMES {
+        let innermost = pcs.len() == 1;
+        // a return address may be just past the end of a function calling a noreturn one
+        let lookup = if innermost { regs.pc } else { regs.pc - 1 };
+        let next = match symbols().frame_rule(lookup, &[RA, FP]) {
+            Some(rule) => step_cfi(&regs, &rule, &read),
+            None => step_fp(&regs, &read),
+        };
+        let Some(next) = next else {
+            break;
+        };
+        // only a leaf may have an empty frame, anything else would loop
+        if next.pc == 0 || next.sp < regs.sp || (next.sp == regs.sp && !innermost) {
+            break;
+        }
+        pcs.push(next.pc);
+        regs = next;
+    }
+    pcs
+}
+
+/// Where the innermost function returns to. Without CFI, s0 only describes the function
+/// after its prologue, so at its entry or without CFI this is ra, unless ra points back into
+/// the function because it has called something already.
+pub fn return_address(regs: FrameRegs, read: impl Fn(u64) -> Option<u64>) -> Option<u64> {
+    let ra = regs.ra?;
+    let func_start = |addr| symbols().lookup(addr).map(|(_, ofs)| addr - ofs);
+    let start = func_start(regs.pc);
+    let at_entry = start == Some(regs.pc);
+    let has_cfi = symbols().frame_rule(regs.pc, &[RA, FP]).is_some();
+    let ra_inside = start.is_some() && func_start(ra) == start;
+    if at_entry || (!has_cfi && !ra_inside) {
+        return (ra != 0).then_some(ra);
+    }
+    unwind(regs, read).get(1).copied()
+}
+
+/// `#1: 0x80000123 main+0x12 at main.c:7`, callers are looked up by their call instruction.
+pub fn format_frames(pcs: &[u64], out: &mut String) {
+    for (i, &pc) in pcs.iter().enumerate() {
+        let at = if i == 0 { pc } else { pc - 1 };
+        write!(out, "#{}: {:#x} {}", i, pc, symbols().format(pc)).unwrap();
+        if let Some(line) = symbols().line(at) {
+            write!(out, " at {}", line).unwrap();
+        }
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn frame_pointer_chain() {
+        // a leaf saving only fp, called by a function with a full frame record
+        let stack = HashMap::from([
+            (0x8008, 0x8040),
+            (0x8038, 0x3008),
+            (0x8030, 0x8080),
+            (0x8078, 0),
+            (0x8070, 0),
+        ]);
+        let regs = FrameRegs {
+            pc: 0x1000,
+            sp: 0x8000,
+            fp: 0x8010,
+            ra: Some(0x2004),
+        };
+        let pcs = unwind(regs, |addr| stack.get(&addr).copied());
+        assert_eq!(pcs, vec![0x1000, 0x2004, 0x3008]);
+    }
+
+    #[test]
+    fn return_address_at_entry() {
+        // just called, s0 is still the caller's frame whose record holds the caller's ra
+        let stack = HashMap::from([(0x8038, 0x3008), (0x8030, 0x8080)]);
+        let regs = FrameRegs {
+            pc: 0x1000,
+            sp: 0x8000,
+            fp: 0x8040,
+            ra: Some(0x2004),
+        };
+        let read = |addr| stack.get(&addr).copied();
+        assert_eq!(unwind(regs, read)[1], 0x3008);
+        assert_eq!(return_address(regs, read), Some(0x2004));
+    }
+
+    #[test]
+    fn zeroed_registers() {
+        let regs = FrameRegs {
+            pc: 0,
+            sp: 0,
+            fp: 0,
+            ra: Some(0),
+        };
+        assert_eq!(unwind(regs, |_| Some(0)), vec![0]);
+    }
+}
@@ -2,6 +2,9 @@
 
 use crate::isa::riscv64::csr::mstatus::MStatus;
 use crate::isa::riscv64::csr::satp::{SATPMode, Satp};
+use crate::isa::riscv64::commit_log::CommitLog;
+use crate::isa::riscv64::mtrace::{MTrace, MTraceType};
+use crate::isa::riscv64::watch::{WatchHit, Watches};
 use crate::isa::riscv64::csr::MCauseCode;
 use crate::isa::riscv64::csr::MCauseCode::{
     InstAccessFault, InstPageFault, LoadAccessFault, LoadPageFault, StoreAMOAccessFault,
@@ -12,17 +15,19 @@ use crate::isa::riscv64::RISCV64Privilege;
 use crate::memory::paddr::PAddr;
 use crate::memory::Memory;
 use crate::utils::cfg_if_feat;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
 use bitfield_struct::bitfield;
 use cfg_if::cfg_if;
 use log::{debug, trace, warn};
 use std::cell::UnsafeCell;
 use std::cmp::PartialEq;
+use std::fmt::Write;
 use std::rc::Rc;
 
 #[derive(Copy, Clone)]
 pub struct VAddr(u64);
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum MemOperationSize {
     Byte = 1,
     WORD = 2,
@@ -120,6 +125,9 @@ pub(crate) struct MMU {
     tlb: [TLBEntry; 2048],
     pub miss: u64,
     pub hit: u64,
+    pub commit_log: Option<CommitLog>,
+    pub mtrace: MTrace,
+    pub watches: Watches,
 }
 
 #[bitfield(u64)]
@@ -164,6 +172,28 @@ impl SV39PTE {
     }
 }
 
+/// `RWXUGAD`, with `-` for clear bits.
+fn pte_flags(pte: SV39PTE) -> String {
+    let bits = [pte.R(), pte.W(), pte.X(), pte.U(), pte.G(), pte.A(), pte.D()];
+    bits.iter()
+        .zip("RWXUGAD".chars())
+        .map(|(&set, c)| if set { c } else { '-' })
+        .collect()
+}
+
+/// Sv39 addresses copy bit 38 into the upper bits.
+fn sext_sv39(vaddr: u64) -> u64 {
+    ((vaddr << 25) as i64 >> 25) as u64
+}
+
+/// A range of `info mem`, with the flags of its leaves.
+struct Mapping {
+    vaddr: u64,
+    paddr: u64,
+    size: u64,
+    pte: SV39PTE,
+}
+
 pub enum TranslationErr {
     AccessFault,
     PageFault,
@@ -184,6 +214,9 @@ impl MMU {
             tlb: [TLBEntry::new(); 2048],
             miss: 0,
             hit: 0,
+            commit_log: None,
+            mtrace: MTrace::new(),
+            watches: Watches::default(),
         }
     }
 
@@ -256,6 +289,7 @@ impl MMU {
                     .read_mem(&PAddr::new(a + vpn[lvl] * 8), MemOperationSize::DWORD)
                     .ok_or(AccessFault)?,
             );
+            self.trace_access(vaddr, a + vpn[lvl] * 8, 8, pte.0, MTraceType::PtWalk);
             // info!("pte {} {:#x}", i, pte.0);
             if pte.0 == 0x0 {
                 self.pt_walk_debug(vaddr);
@@ -395,7 +429,10 @@ impl MMU {
     ) -> Result<(u64, PAddr), MCauseCode> {
         match self.translate(vaddr, MemoryAccessType::X) {
             Ok(paddr) => match self.mem.read(&paddr, len) {
-                Some(v) => Ok((v, paddr)),
+                Some(v) => {
+                    self.trace_access(vaddr.value(), paddr.value(), 4, v, MTraceType::Fetch);
+                    Ok((v, paddr))
+                }
                 None => Err(InstAccessFault),
             },
             Err(e) => match e {
@@ -405,9 +442,19 @@ impl MMU {
         }
     }
     pub fn read(&mut self, vaddr: &VAddr, len: MemOperationSize) -> Result<u64, MCauseCode> {
+        if let Some(log) = &mut self.commit_log {
+            log.load(vaddr.value());
+        }
         match self.translate(vaddr, MemoryAccessType::R) {
             Ok(paddr) => match self.mem.read(&paddr, len) {
-                Some(v) => Ok(v),
+                Some(v) => {
+                    let size = len as usize;
+                    self.trace_access(vaddr.value(), paddr.value(), size, v, MTraceType::Read);
+                    if self.watches.enabled() {
+                        self.watch_access(vaddr.value(), paddr.value(), size, false, None, v);
+                    }
+                    Ok(v)
+                }
                 None => Err(LoadAccessFault),
             },
             Err(e) => match e {
@@ -417,17 +464,263 @@ impl MMU {
         }
     }
 
+    /// Read physical memory or a device, bypassing translation.
+    pub fn read_paddr(&self, paddr: &PAddr, len: MemOperationSize) -> Option<u64> {
+        self.mem.read(paddr, len)
+    }
+
+    /// Read a qword the way a load would see it, but without permission checks, TLB fills,
+    /// A/D updates, tracing or device accesses, so the debugger can't disturb the guest.
+    pub fn peek(&self, vaddr: u64) -> Option<u64> {
+        self.peek_pmem(self.peek_paddr(vaddr, MemoryAccessType::R)?, MemOperationSize::QWORD)
+    }
+
+    /// Read `len` bytes of pmem with no side effects, None for devices and unbacked addresses.
+    pub fn peek_pmem(&self, paddr: u64, len: MemOperationSize) -> Option<u64> {
+        let last = PAddr::new(paddr.checked_add(len as u64 - 1)?);
+        let paddr = PAddr::new(paddr);
+        if !Memory::in_pmem(&paddr) || !Memory::in_pmem(&last) {
+            return None;
+        }
+        self.mem.read_mem(&paddr, len)
+    }
+
+    /// Translate like an access of type `typ` would, with the same lack of side effects.
+    pub fn peek_paddr(&self, vaddr: u64, typ: MemoryAccessType) -> Option<u64> {
+        let ctrl = &self.translation_ctrl;
+        if ctrl.is_bare
+            || (ctrl.current_priv() == RISCV64Privilege::M
+                && !(typ != MemoryAccessType::X && ctrl.translate_in_m))
+        {
+            return Some(vaddr);
+        }
+        self.peek_translate(vaddr)
+    }
+
+    fn peek_translate(&self, vaddr: u64) -> Option<u64> {
+        let mut a = self.translation_ctrl.sv39.lvl1_base;
+        for lvl in (0..3).rev() {
+            let pte_addr = PAddr::new(a + ((vaddr >> (12 + 9 * lvl)) & 0b111111111) * 8);
+            if !Memory::in_pmem(&pte_addr) {
+                return None;
+            }
+            let pte = SV39PTE::from(self.mem.read_mem(&pte_addr, MemOperationSize::QWORD)?);
+            if pte.is_invalid() {
+                return None;
+            }
+            if !pte.is_next_lvl_ptr() {
+                let ofs_mask = (1u64 << (12 + 9 * lvl)) - 1;
+                return Some((pte.PPN() << 12) & !ofs_mask | vaddr & ofs_mask);
+            }
+            a = pte.PPN() << 12;
+        }
+        None
+    }
+
+    fn read_pte(&self, addr: u64) -> Option<SV39PTE> {
+        let addr = PAddr::new(addr);
+        if !Memory::in_pmem(&addr) {
+            return None;
+        }
+        let pte = self.mem.read_mem(&addr, MemOperationSize::QWORD)?;
+        Some(SV39PTE::from(pte))
+    }
+
+    /// Leaf mappings below the table at `table` of level `lvl`, in address order. Neighbours
+    /// with the same flags are merged.
+    fn collect_mappings(&self, table: u64, lvl: u32, vaddr: u64, maps: &mut Vec<Mapping>) {
+        for idx in 0..512u64 {
+            let Some(pte) = self.read_pte(table + idx * 8) else {
+                return;
+            };
+            if pte.is_invalid() {
+                continue;
+            }
+            let vaddr = vaddr | idx << (12 + 9 * lvl);
+            if pte.is_next_lvl_ptr() {
+                if lvl > 0 {
+                    self.collect_mappings(pte.PPN() << 12, lvl - 1, vaddr, maps);
+                }
+                continue;
+            }
+            let (paddr, size) = (pte.PPN() << 12, 1u64 << (12 + 9 * lvl));
+            match maps.last_mut() {
+                Some(m)
+                    if m.vaddr + m.size == vaddr
+                        && m.paddr + m.size == paddr
+                        && pte_flags(m.pte) == pte_flags(pte) =>
+                {
+                    m.size += size
+                }
+                _ => maps.push(Mapping {
+                    vaddr,
+                    paddr,
+                    size,
+                    pte,
+                }),
+            }
+        }
+    }
+
+    /// The valid mappings of the page table in satp, for `info mem`.
+    pub fn info_mem(&self) -> String {
+        if self.translation_ctrl.is_bare {
+            return "Paging is off, satp.MODE is Bare".to_string();
+        }
+        let mut maps = Vec::new();
+        self.collect_mappings(self.translation_ctrl.sv39.lvl1_base, 2, 0, &mut maps);
+        let mut res = format!(
+            "{:<37} {:<18} {:<12} flags",
+            "vaddr", "paddr", "size"
+        );
+        for m in maps {
+            let (start, end) = (sext_sv39(m.vaddr), sext_sv39(m.vaddr + m.size - 1));
+            write!(
+                res,
+                "\n{:#018x}-{:#018x} {:#018x} {:#012x} {}",
+                start,
+                end,
+                m.paddr,
+                m.size,
+                pte_flags(m.pte)
+            )
+            .unwrap();
+        }
+        res
+    }
+
+    /// Whether the current mode may access a leaf, the checks of `translate`.
+    fn leaf_fault(&self, pte: SV39PTE, typ: MemoryAccessType) -> Option<&'static str> {
+        let ctrl = &self.translation_ctrl;
+        let privilege = ctrl.current_priv();
+        if privilege == RISCV64Privilege::U && !pte.U() {
+            Some("not a U page")
+        } else if pte.U() && privilege != RISCV64Privilege::U && !ctrl.SUM {
+            Some("U page without SUM")
+        } else if !pte.check_access_type(typ, ctrl.MXR) {
+            Some("not permitted")
+        } else {
+            None
+        }
+    }
+
+    /// Each step of the walk for `vaddr`, for `vtop`.
+    pub fn vtop(&self, vaddr: u64) -> String {
+        let ctrl = &self.translation_ctrl;
+        if ctrl.is_bare {
+            return format!("{:#x} -> {:#x}, paging is off", vaddr, vaddr);
+        }
+        if sext_sv39(vaddr) != vaddr {
+            return format!("{:#x}: page fault, bits 63-39 don't copy bit 38", vaddr);
+        }
+        let mut res = String::new();
+        let mut table = ctrl.sv39.lvl1_base;
+        for lvl in (0..3).rev() {
+            let pte_addr = table + ((vaddr >> (12 + 9 * lvl)) & 0x1ff) * 8;
+            let Some(pte) = self.read_pte(pte_addr) else {
+                let err = "access fault, not in memory";
+                write!(res, "L{} pte at {:#x}: {}", lvl, pte_addr, err).unwrap();
+                return res;
+            };
+            writeln!(
+                res,
+                "L{} pte at {:#x}: {:#018x} {}",
+                lvl,
+                pte_addr,
+                pte.into_bits(),
+                pte_flags(pte)
+            )
+            .unwrap();
+            if !pte.valid() {
+                write!(res, "page fault, V is clear").unwrap();
+                return res;
+            }
+            if pte.is_invalid() {
+                write!(res, "page fault, W without R is reserved").unwrap();
+                return res;
+            }
+            if pte.is_next_lvl_ptr() {
+                table = pte.PPN() << 12;
+                continue;
+            }
+            let ofs_mask = (1u64 << (12 + 9 * lvl)) - 1;
+            if (pte.PPN() << 12) & ofs_mask != 0 {
+                write!(res, "page fault, misaligned superpage").unwrap();
+                return res;
+            }
+            let paddr = (pte.PPN() << 12) | vaddr & ofs_mask;
+            write!(res, "{:#x} -> {:#x}, {:#x} byte page", vaddr, paddr, ofs_mask + 1).unwrap();
+            let access = [
+                ("R", MemoryAccessType::R),
+                ("W", MemoryAccessType::W),
+                ("X", MemoryAccessType::X),
+            ];
+            write!(res, "\nin {:?} mode:", ctrl.current_priv()).unwrap();
+            for (name, typ) in access {
+                match self.leaf_fault(pte, typ) {
+                    Some(why) => write!(res, " {} page fault ({})", name, why),
+                    None => write!(res, " {} ok", name),
+                }
+                .unwrap();
+            }
+            return res;
+        }
+        res + "page fault, no leaf at level 0"
+    }
+
+    /// The valid TLB entries, for `info tlb`.
+    pub fn info_tlb(&self) -> String {
+        let mut res = String::new();
+        let mut valid = 0;
+        for (idx, entry) in self.tlb.iter().enumerate() {
+            if entry.pte & 1 == 0 {
+                continue;
+            }
+            valid += 1;
+            let pte = SV39PTE::from_bits(entry.pte);
+            writeln!(
+                res,
+                "[{:4}] {:#018x} -> {:#x} L{} {}",
+                idx,
+                sext_sv39(entry.vpn << 12),
+                pte.PPN() << 12,
+                entry.pte_addrs_len,
+                pte_flags(pte)
+            )
+            .unwrap();
+        }
+        res + &format!("{} of {} entries valid", valid, self.tlb.len())
+    }
+
     pub fn write(
         &mut self,
         vaddr: &VAddr,
         data: u64,
         len: MemOperationSize,
     ) -> Result<(), MCauseCode> {
+        if let Some(log) = &mut self.commit_log {
+            log.store(vaddr.value(), len.read_val(data), len as usize);
+        }
         match self.translate(vaddr, MemoryAccessType::W) {
-            Ok(paddr) => match self.mem.write(&paddr, data, len) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(StoreAMOAccessFault),
-            },
+            Ok(paddr) => {
+                // devices can't be read back without side effects
+                let old = match self.watches.enabled() && Memory::in_pmem(&paddr) {
+                    true => self.mem.read_mem(&paddr, len),
+                    false => None,
+                };
+                match self.mem.write(&paddr, data, len) {
+                    Ok(_) => {
+                        let (size, data) = (len as usize, len.read_val(data));
+                        let (vaddr, paddr) = (vaddr.value(), paddr.value());
+                        self.trace_access(vaddr, paddr, size, data, MTraceType::Write);
+                        if self.watches.enabled() {
+                            self.watch_access(vaddr, paddr, size, true, old, data);
+                        }
+                        Ok(())
+                    }
+                    Err(_) => Err(StoreAMOAccessFault),
+                }
+            }
             Err(e) => match e {
                 AccessFault => Err(StoreAMOAccessFault),
                 PageFault => Err(StoreAMOPageFault),
@@ -435,6 +728,53 @@ impl MMU {
         }
     }
 
+    /// `old` is the value a store overwrote, if it could be read.
+    fn watch_access(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        size: usize,
+        write: bool,
+        old: Option<u64>,
+        value: u64,
+    ) {
+        if let Some(id) = self.watches.matching(vaddr, paddr, size, write) {
+            self.watches.record(WatchHit {
+                id,
+                vaddr,
+                paddr,
+                size,
+                write,
+                old,
+                value,
+            });
+        }
+    }
+
+    /// Run a debugger access without it triggering watchpoints.
+    pub fn unwatched<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let hit = self.watches.take_hit();
+        let res = f(self);
+        self.watches.restore_hit(hit);
+        res
+    }
+
+    #[inline]
+    fn trace_access(&mut self, vaddr: u64, paddr: u64, size: usize, value: u64, typ: MTraceType) {
+        if self.mtrace.enabled() {
+            let privilege = self.translation_ctrl.current_priv();
+            self.mtrace.access(vaddr, paddr, size, value, typ, privilege);
+        }
+    }
+
+    pub fn mtrace_command(&mut self, cmd: &str) -> Result<String, String> {
+        self.mtrace.command(cmd, &self.mem)
+    }
+
+    pub fn take_mmio_accessed(&self) -> bool {
+        self.mem.take_mmio_accessed()
+    }
+
     pub fn is_aligned(&self, vaddr: &VAddr, len: MemOperationSize) -> bool {
         vaddr.value() % (len as u64) == 0
     }
@@ -456,6 +796,24 @@ impl MMU {
         self.translation_ctrl.translate_in_m = mstatus.MPRV() && !mstatus.MPP_is_m_mode();
     }
 
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        self.mem.save_state(w);
+    }
+
+    /// Translation control is rebuilt from satp and mstatus, and the TLB is flushed.
+    pub fn load_state(
+        &mut self,
+        r: &mut SnapshotReader,
+        satp: &Satp,
+        mstatus: &MStatus,
+    ) -> Result<(), String> {
+        self.mem.load_state(r)?;
+        self.update_translation_ctrl(satp);
+        self.update_priv(mstatus);
+        self.sfence_vma();
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn print_tlb_statistics(&self) {
         let total = (self.miss + self.hit) as f64;
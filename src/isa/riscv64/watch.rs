@@ -0,0 +1,112 @@
+//! Access watchpoints, checked by the MMU on every load and store so watching memory doesn't
+//! need the debugger to compare values after each instruction.
+
+/// Which accesses trigger a watchpoint, like gdb's `watch`, `rwatch` and `awatch`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+/// `len` bytes at `addr`, a physical address if `physical`.
+#[derive(Clone, Copy)]
+pub struct AccessWatch {
+    pub id: u32,
+    pub kind: WatchKind,
+    pub addr: u64,
+    pub len: u64,
+    pub physical: bool,
+}
+
+/// The first watched access of an instruction.
+#[derive(Clone, Copy)]
+pub struct WatchHit {
+    pub id: u32,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub size: usize,
+    pub write: bool,
+    /// the value before a store, None for loads and devices
+    pub old: Option<u64>,
+    /// the value loaded or stored
+    pub value: u64,
+}
+
+#[derive(Default)]
+pub struct Watches {
+    points: Vec<AccessWatch>,
+    hit: Option<WatchHit>,
+}
+
+impl Watches {
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        !self.points.is_empty()
+    }
+
+    pub fn set(&mut self, points: Vec<AccessWatch>) {
+        self.points = points;
+        self.hit = None;
+    }
+
+    /// The watchpoint an access hits, unless an earlier access of the instruction did.
+    pub fn matching(&self, vaddr: u64, paddr: u64, size: usize, write: bool) -> Option<u32> {
+        if self.hit.is_some() {
+            return None;
+        }
+        self.points
+            .iter()
+            .find(|w| {
+                let addr = if w.physical { paddr } else { vaddr };
+                let kind_ok = match w.kind {
+                    WatchKind::Write => write,
+                    WatchKind::Read => !write,
+                    WatchKind::Access => true,
+                };
+                kind_ok
+                    && addr < w.addr.wrapping_add(w.len)
+                    && w.addr < addr.wrapping_add(size as u64)
+            })
+            .map(|w| w.id)
+    }
+
+    pub fn record(&mut self, hit: WatchHit) {
+        self.hit = Some(hit);
+    }
+
+    pub fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    /// Put back a hit taken around a debugger access.
+    pub fn restore_hit(&mut self, hit: Option<WatchHit>) {
+        self.hit = hit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_accesses() {
+        let mut watches = Watches::default();
+        let watch = |id, kind, addr, physical| AccessWatch {
+            id,
+            kind,
+            addr,
+            len: 4,
+            physical,
+        };
+        watches.set(vec![
+            watch(1, WatchKind::Write, 0x1000, false),
+            watch(2, WatchKind::Read, 0x80002000, true),
+        ]);
+        assert_eq!(watches.matching(0xffc, 0, 8, true), Some(1));
+        assert_eq!(watches.matching(0x1004, 0, 8, true), None);
+        assert_eq!(watches.matching(0x1000, 0, 4, false), None);
+        assert_eq!(watches.matching(0x7000, 0x80002003, 1, false), Some(2));
+        assert_eq!(watches.matching(u64::MAX - 3, 0, 8, true), None);
+    }
+}
@@ -1,16 +1,19 @@
-use crate::device::Devices;
+use crate::device::{replay, Devices};
 use crate::isa::riscv64::RISCV64;
 use crate::isa::Isa;
 use crate::memory::Memory;
-use crate::monitor::init_log;
-use crate::monitor::sdb::difftest_qemu::DifftestContext;
-use crate::monitor::sdb::{exec_once, sdb_loop};
+use crate::monitor::sdb::difftest::{DifftestConfig, DifftestContext};
+use crate::monitor::sdb::{exec_once, gdb_stub_loop, sdb_loop};
+use crate::monitor::{init_log, Loaded};
 use crate::utils::cfg_if_feat;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::utils::symbols::{set_symbols, SymbolTable};
 use cfg_if::cfg_if;
 use clap::Parser;
-use log::info;
+use log::{error, info};
 use std::process::ExitCode;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
 
 mod device;
@@ -25,35 +28,102 @@ pub struct Emulator<T: Isa> {
     device: Devices,
     difftest_ctx: Option<DifftestContext>,
     batch: bool,
+    #[cfg_attr(not(feature = "difftest"), allow(dead_code))]
+    difftest_interval: u64,
+    checkpoint_interval: Option<u64>,
+    gdb_port: Option<u16>,
+    sdb_script: Option<String>,
     exitcode: u8,
 }
 
 impl<T: Isa> Emulator<T> {
-    pub fn new() -> Self {
+    pub fn new() -> Self
+    where
+        T: 'static,
+    {
         let args = crate::monitor::Args::parse();
         init_log(&args);
 
         let mut memory = Memory::new(); // init mem
-        if let Some(path) = &args.image {
-            monitor::load_img(path, &mut memory);
-        }
-        let firm_size = monitor::load_firmware(&args.firmware, args.image.is_some(), &mut memory);
-        info!("Firmware size: {:#x}", firm_size);
-
         let stopped = Arc::new(AtomicBool::new(false));
+        if args.record.is_some() && args.batch {
+            // stop cleanly on Ctrl-C so the record log of a hanging run is complete
+            let stopped = stopped.clone();
+            ctrlc::set_handler(move || stopped.store(true, Relaxed))
+                .expect("Failed to set Ctrl-C handler");
+        }
+        // map MMIO first so ELF segments overlapping a device are refused
         let device = Devices::new(stopped.clone(), &mut memory, &args); // init device
+
+        let exit_on_err = |res: Result<Loaded, String>| {
+            res.unwrap_or_else(|err| {
+                error!("{}", err);
+                std::process::exit(1);
+            })
+        };
+        let image = args
+            .image
+            .as_ref()
+            .map(|path| exit_on_err(monitor::load_img(path, &mut memory)));
+        let firmware = exit_on_err(monitor::load_firmware(
+            &args.firmware,
+            args.image.is_some(),
+            &mut memory,
+        ));
+        info!("Firmware size: {:#x}", firmware.size);
+
+        let mut symbols = SymbolTable::default();
+        for elf in [Some(&firmware), image.as_ref()].into_iter().flatten() {
+            if let Some(elf) = &elf.elf {
+                symbols.add_elf(elf, 0);
+            }
+        }
+        for spec in &args.symbols {
+            if let Err(err) = symbols.add_file(spec) {
+                error!("--symbols: {}", err);
+                std::process::exit(1);
+            }
+        }
+        set_symbols(symbols);
+
         let mut cpu = T::new(
             stopped.clone(),
             memory,
             device.cpu_interrupt_bits.clone(),
             &args,
         );
+        if let Some(elf) = &firmware.elf {
+            cpu.isa_set_reg_by_name("pc", elf.entry).unwrap();
+        }
+
+        if let Some(path) = &args.restore {
+            let mut r = SnapshotReader::load(path)
+                .unwrap_or_else(|e| panic!("couldn't restore snapshot: {}", e));
+            if let Err(e) = cpu.isa_load_state(&mut r) {
+                panic!("couldn't restore snapshot {}: {}", path, e);
+            }
+            info!("Snapshot {} restored", path);
+        }
 
         let difftest_ctx = if args.difftest {
-            Some(DifftestContext::init(
+            Some(DifftestContext::init::<T>(
                 cpu.isa_difftest_init(),
+                DifftestConfig {
+                    reference: args.difftest_ref,
+                    qemu_bin: args.qemu.clone(),
+                    qemu_args: args
+                        .qemu_args
+                        .split_whitespace()
+                        .map(String::from)
+                        .collect(),
+                    port: args.difftest_port,
+                    trace: args.difftest_trace.clone(),
+                    image: args.image.clone(),
+                    csrs: args.difftest_csrs.clone(),
+                    mem: args.difftest_mem.clone(),
+                },
                 &args.firmware,
-                &"".to_string(), // &args.image.unwrap(),
+                &args,
             ))
         } else {
             None
@@ -64,12 +134,20 @@ impl<T: Isa> Emulator<T> {
             device,
             difftest_ctx,
             batch: args.batch,
+            difftest_interval: args.difftest_interval,
+            checkpoint_interval: args.checkpoint_interval,
+            gdb_port: args.gdb,
+            sdb_script: args.sdb_script,
             exitcode: 0,
         }
     }
 
     pub fn run(&mut self) {
-        let cnt = if !self.batch {
+        let cnt = if let Some(port) = self.gdb_port {
+            let (inst_cont, exitcode) = gdb_stub_loop(self, port);
+            self.exitcode = exitcode;
+            inst_cont
+        } else if !self.batch || self.sdb_script.is_some() {
             let (inst_cont, exitcode) = sdb_loop(self);
             self.exitcode = exitcode;
             inst_cont
@@ -81,10 +159,21 @@ impl<T: Isa> Emulator<T> {
                     inst_count += 1;
                 });
                 let (not_halt, _, sdl_quit) = exec_once(self);
+                // the reference would step past the end of the program
                 if !not_halt {
                     self.exitcode = self.cpu.isa_get_exit_code();
                     break;
                 }
+                cfg_if_feat!("difftest", {
+                    if let Some(ctx) = self.difftest_ctx.as_mut() {
+                        let compare = device::inst_count().is_multiple_of(self.difftest_interval);
+                        if let Err(err) = ctx.step(&mut self.cpu, compare) {
+                            log::error!("{}", err);
+                            self.exitcode = 1;
+                            break;
+                        }
+                    }
+                });
                 if sdl_quit {
                     break;
                 }
@@ -95,10 +184,22 @@ impl<T: Isa> Emulator<T> {
         self.cpu.isa_print_icache_info();
     }
 
+    pub fn save_snapshot(&self, path: &str) -> Result<(), String> {
+        let mut w = SnapshotWriter::new();
+        self.cpu.isa_save_state(&mut w);
+        w.save(path)
+    }
+
+    pub fn load_snapshot(&mut self, path: &str) -> Result<(), String> {
+        let mut r = SnapshotReader::load(path)?;
+        self.cpu.isa_load_state(&mut r)
+    }
+
     pub fn exit(mut self) -> ExitCode {
         if let Some(ctx) = &mut self.difftest_ctx {
             ctx.exit();
         }
+        replay::finish();
         self.device.stop();
         ExitCode::from(self.exitcode)
     }
@@ -1,11 +1,15 @@
+use std::cell::Cell;
 use std::sync::{Arc, Mutex};
 
 use crate::isa::riscv64::vaddr::MemOperationSize;
 use crate::memory::paddr::{init_mem, PAddr, PMEM_LEFT, PMEM_RIGHT};
 use crate::utils::configs::CONFIG_MEM_SIZE;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
 
 pub mod paddr;
 
+const SNAPSHOT_PAGE_SIZE: usize = 4096;
+
 pub trait IOMap {
     fn len(&self) -> usize;
 
@@ -20,19 +24,89 @@ pub trait IOMap {
     fn write(&mut self, _offset: usize, _data: u64, _len: MemOperationSize) {
         panic!("Write should not happen");
     }
+
+    /// Whether reads depend on the host (clock, input, other threads).
+    /// Such reads are logged by --record and served from the log by --replay.
+    fn nondeterministic(&self) -> bool {
+        false
+    }
+
+    /// Serialize device registers for savevm. Stateless devices keep the default.
+    fn save_state(&self, _w: &mut SnapshotWriter) {}
+
+    fn load_state(&mut self, _r: &mut SnapshotReader) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub enum IODevice {
+    /// Device also touched by other threads (SDL, tokio, CLINT timer), locked on every access
+    Shared(Arc<Mutex<dyn IOMap>>),
+    /// Device only touched from the cpu thread, accessed without locking
+    Local(Box<dyn IOMap>),
+}
+
+impl IODevice {
+    fn len(&self) -> usize {
+        match self {
+            IODevice::Shared(device) => device.lock().unwrap().len(),
+            IODevice::Local(device) => device.len(),
+        }
+    }
+
+    fn nondeterministic(&self) -> bool {
+        match self {
+            IODevice::Shared(device) => device.lock().unwrap().nondeterministic(),
+            IODevice::Local(device) => device.nondeterministic(),
+        }
+    }
+
+    #[inline]
+    fn read(&self, offset: usize, len: MemOperationSize) -> u64 {
+        match self {
+            IODevice::Shared(device) => device.lock().unwrap().read(offset, len),
+            IODevice::Local(device) => device.read(offset, len),
+        }
+    }
+
+    #[inline]
+    fn write(&mut self, offset: usize, data: u64, len: MemOperationSize) {
+        match self {
+            IODevice::Shared(device) => device.lock().unwrap().write(offset, data, len),
+            IODevice::Local(device) => device.write(offset, data, len),
+        }
+    }
+
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        match self {
+            IODevice::Shared(device) => device.lock().unwrap().save_state(w),
+            IODevice::Local(device) => device.save_state(w),
+        }
+    }
+
+    fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        match self {
+            IODevice::Shared(device) => device.lock().unwrap().load_state(r),
+            IODevice::Local(device) => device.load_state(r),
+        }
+    }
 }
 
 pub struct IOMapEntry {
+    name: &'static str,
     left: PAddr,
     right: PAddr,
-    device: Arc<Mutex<dyn IOMap>>,
+    device: IODevice,
+    nondeterministic: bool,
 }
 
 impl IOMapEntry {
-    fn new(left: PAddr, right: PAddr, device: Arc<Mutex<dyn IOMap>>) -> Self {
+    fn new(name: &'static str, left: PAddr, right: PAddr, device: IODevice) -> Self {
         Self {
+            name,
             left,
             right,
+            nondeterministic: device.nondeterministic(),
             device,
         }
     }
@@ -48,7 +122,12 @@ impl IOMapEntry {
 
 pub struct Memory {
     pub pmem: Box<[u8]>,
+    /// sorted by address, never overlapping
     mmio: Vec<IOMapEntry>,
+    /// index of the last matched mmio region, device accesses come in bursts
+    last_mmio: Cell<usize>,
+    /// set by any device access, difftest can't compare such instructions with the reference
+    mmio_accessed: Cell<bool>,
 }
 
 impl Memory {
@@ -57,6 +136,8 @@ impl Memory {
         Self {
             pmem: vec![0u8; CONFIG_MEM_SIZE].into_boxed_slice(),
             mmio: vec![],
+            last_mmio: Cell::new(0),
+            mmio_accessed: Cell::new(false),
         }
     }
 
@@ -64,42 +145,203 @@ impl Memory {
         PMEM_LEFT <= *paddr && *paddr <= *PMEM_RIGHT
     }
 
-    pub fn find_iomap(&self, paddr: &PAddr) -> Option<&IOMapEntry> {
-        for iomap in self.mmio.iter() {
+    #[inline]
+    fn find_iomap_idx(&self, paddr: &PAddr) -> Option<usize> {
+        let last = self.last_mmio.get();
+        if let Some(iomap) = self.mmio.get(last) {
             if iomap.addr_inside(paddr) {
-                return Some(iomap);
+                return Some(last);
+            }
+        }
+        let idx = self.mmio.partition_point(|iomap| iomap.right <= *paddr);
+        match self.mmio.get(idx) {
+            Some(iomap) if iomap.addr_inside(paddr) => {
+                self.last_mmio.set(idx);
+                Some(idx)
             }
+            _ => None,
         }
-        None
+    }
+
+    pub fn find_iomap(&self, paddr: &PAddr) -> Option<&IOMapEntry> {
+        self.find_iomap_idx(paddr).map(|idx| &self.mmio[idx])
     }
 
     pub fn find_iomap_mut(&mut self, paddr: &PAddr) -> Option<&mut IOMapEntry> {
-        for iomap in self.mmio.iter_mut() {
-            if iomap.addr_inside(paddr) {
-                return Some(iomap);
-            }
-        }
-        None
+        self.find_iomap_idx(paddr).map(|idx| &mut self.mmio[idx])
     }
 
-    pub fn add_mmio(&mut self, left: PAddr, device: Arc<Mutex<dyn IOMap>>) {
-        let right = left.clone() + device.lock().unwrap().len() as u64;
-        let io_map = IOMapEntry::new(left, right, device);
-        if Self::in_pmem(&io_map.left) && Self::in_pmem(&io_map.right) {
+    /// Whether a device was accessed since the last call.
+    pub fn take_mmio_accessed(&self) -> bool {
+        self.mmio_accessed.replace(false)
+    }
+
+    /// Map a device which is also used outside the cpu thread.
+    pub fn add_mmio(&mut self, name: &'static str, left: PAddr, device: Arc<Mutex<dyn IOMap>>) {
+        self.insert_iomap(name, left, IODevice::Shared(device))
+    }
+
+    /// Map a device which is only used by the cpu thread. No lock is taken when accessing it.
+    pub fn add_local_mmio(&mut self, name: &'static str, left: PAddr, device: Box<dyn IOMap>) {
+        self.insert_iomap(name, left, IODevice::Local(device))
+    }
+
+    /// [left, right) of the device mapped as `name`.
+    pub fn find_device(&self, name: &str) -> Option<(u64, u64)> {
+        self.mmio
+            .iter()
+            .find(|iomap| iomap.name == name)
+            .map(|iomap| (iomap.left.value(), iomap.right.value()))
+    }
+
+    pub fn device_names(&self) -> Vec<&'static str> {
+        self.mmio.iter().map(|iomap| iomap.name).collect()
+    }
+
+    fn insert_iomap(&mut self, name: &'static str, left: PAddr, device: IODevice) {
+        let right = left.clone() + device.len() as u64;
+        let io_map = IOMapEntry::new(name, left, right, device);
+        if io_map.left <= *PMEM_RIGHT && PMEM_LEFT < io_map.right {
             panic!(
                 "MMIO region ({:#x}, {:#x}) overlaps with pmem",
                 io_map.left, io_map.right
             )
         }
-        if let Some(mmap) = self
-            .find_iomap(&io_map.left)
-            .or(self.find_iomap(&(io_map.right.clone() - 1)))
-        {
-            panic!(
-                "MMIO region ({:#x}, {:#x}) overlaps with other mmio region ({:#x} {:#x})",
-                io_map.left, io_map.right, mmap.left, mmap.right
-            )
+        let idx = self
+            .mmio
+            .partition_point(|iomap| iomap.right <= io_map.left);
+        if let Some(mmap) = self.mmio.get(idx) {
+            if mmap.left < io_map.right {
+                panic!(
+                    "MMIO region ({:#x}, {:#x}) overlaps with other mmio region ({:#x} {:#x})",
+                    io_map.left, io_map.right, mmap.left, mmap.right
+                )
+            }
         }
-        self.mmio.push(io_map)
+        self.mmio.insert(idx, io_map)
+    }
+
+    /// Copy `data` to `paddr` and zero-fill the rest of `memsz` bytes, e.g. an ELF segment and
+    /// its .bss. The whole range has to be inside pmem.
+    pub fn load(&mut self, paddr: u64, data: &[u8], memsz: u64) -> Result<(), String> {
+        let end = paddr
+            .checked_add(memsz)
+            .ok_or(format!("{:#x}+{:#x} overflows", paddr, memsz))?;
+        if data.len() as u64 > memsz {
+            return Err(format!("{:#x} bytes don't fit in {:#x}", data.len(), memsz));
+        }
+        let overlapped = self
+            .mmio
+            .iter()
+            .find(|iomap| iomap.left.value() < end && paddr < iomap.right.value());
+        if let Some(iomap) = overlapped {
+            return Err(format!(
+                "[{:#x}, {:#x}) overlaps the MMIO region of {} [{:#x}, {:#x})",
+                paddr, end, iomap.name, iomap.left, iomap.right
+            ));
+        }
+        if paddr < PMEM_LEFT.value() || end - 1 > PMEM_RIGHT.value() {
+            return Err(format!(
+                "[{:#x}, {:#x}) is outside pmem [{:#x}, {:#x}]",
+                paddr, end, PMEM_LEFT, *PMEM_RIGHT
+            ));
+        }
+        let ofs = PAddr::new(paddr).to_host_mem_arr_index();
+        self.pmem[ofs..ofs + data.len()].copy_from_slice(data);
+        self.pmem[ofs + data.len()..ofs + memsz as usize].fill(0);
+        Ok(())
+    }
+
+    /// Save pmem (all-zero pages are skipped) and the state of every device.
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        let pages: Vec<(usize, &[u8])> = self
+            .pmem
+            .chunks(SNAPSHOT_PAGE_SIZE)
+            .enumerate()
+            .filter(|(_, page)| page.iter().any(|&b| b != 0))
+            .collect();
+        w.put_u64(pages.len() as u64);
+        for (idx, page) in pages {
+            w.put_u64(idx as u64);
+            w.put_bytes(page);
+        }
+
+        w.put_u64(self.mmio.len() as u64);
+        for iomap in self.mmio.iter() {
+            let mut device_w = SnapshotWriter::new();
+            iomap.device.save_state(&mut device_w);
+            w.put_u64(iomap.left.value());
+            w.put_bytes(&device_w.into_bytes());
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.pmem.fill(0);
+        let page_cnt = r.get_u64()?;
+        for _ in 0..page_cnt {
+            let ofs = r.get_u64()? as usize * SNAPSHOT_PAGE_SIZE;
+            if ofs + SNAPSHOT_PAGE_SIZE > self.pmem.len() {
+                return Err(format!("pmem page {:#x} out of range", ofs));
+            }
+            r.get_bytes_into(&mut self.pmem[ofs..ofs + SNAPSHOT_PAGE_SIZE])?;
+        }
+
+        let device_cnt = r.get_u64()?;
+        for _ in 0..device_cnt {
+            let left = PAddr::new(r.get_u64()?);
+            let mut device_r = SnapshotReader::from_bytes(r.get_bytes()?.to_vec());
+            match self.mmio.iter_mut().find(|iomap| iomap.left == left) {
+                Some(iomap) => iomap
+                    .device
+                    .load_state(&mut device_r)
+                    .map_err(|e| format!("device at {:#x}: {}", left, e))?,
+                None => return Err(format!("no device mapped at {:#x}", left)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::isa::riscv64::vaddr::MemOperationSize;
+    use crate::memory::paddr::PAddr;
+    use crate::memory::{IOMap, Memory};
+
+    struct Dummy(usize);
+
+    impl IOMap for Dummy {
+        fn len(&self) -> usize {
+            self.0
+        }
+        fn read(&self, offset: usize, _len: MemOperationSize) -> u64 {
+            offset as u64
+        }
+    }
+
+    #[test]
+    fn find_iomap() {
+        let mut memory = Memory::new();
+        memory.add_local_mmio("dummy", PAddr::new(0x3000), Box::new(Dummy(0x1000)));
+        memory.add_local_mmio("dummy", PAddr::new(0x1000), Box::new(Dummy(0x10)));
+        memory.add_local_mmio("dummy", PAddr::new(0x1010), Box::new(Dummy(0x10)));
+        assert!(memory.find_iomap(&PAddr::new(0xfff)).is_none());
+        assert!(memory.find_iomap(&PAddr::new(0x1020)).is_none());
+        assert_eq!(
+            memory.read(&PAddr::new(0x1018), MemOperationSize::Byte),
+            Some(8)
+        );
+        assert_eq!(
+            memory.read(&PAddr::new(0x3ffc), MemOperationSize::Byte),
+            Some(0xffc)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn overlapped_iomap() {
+        let mut memory = Memory::new();
+        memory.add_local_mmio("dummy", PAddr::new(0x1004), Box::new(Dummy(0x4)));
+        memory.add_local_mmio("dummy", PAddr::new(0x1000), Box::new(Dummy(0x10)));
     }
 }
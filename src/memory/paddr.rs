@@ -1,4 +1,5 @@
 use crate::isa::riscv64::vaddr::MemOperationSize;
+use crate::device::replay;
 use crate::memory::Memory;
 use crate::utils::configs::{CONFIG_MEM_BASE, CONFIG_MEM_SIZE};
 use lazy_static::lazy_static;
@@ -88,13 +89,14 @@ impl Memory {
             return Some(len.read_sized(ptr));
         }
         if let Some(iomap) = self.find_iomap(paddr) {
-            return Some(
-                iomap
-                    .device
-                    .lock()
-                    .unwrap()
-                    .read(iomap.paddr_to_device_mem_idx(paddr), len),
-            );
+            self.mmio_accessed.set(true);
+            let offset = iomap.paddr_to_device_mem_idx(paddr);
+            if iomap.nondeterministic {
+                return Some(replay::observe_mmio(paddr.0, || {
+                    iomap.device.read(offset, len)
+                }));
+            }
+            return Some(iomap.device.read(offset, len));
         }
         warn!("MEM & IO READ ERR: {:#x}", paddr.0);
         None
@@ -121,11 +123,9 @@ impl Memory {
 
         match self.find_iomap_mut(paddr) {
             Some(iomap) => {
-                iomap
-                    .device
-                    .lock()
-                    .unwrap()
-                    .write(iomap.paddr_to_device_mem_idx(paddr), data, len);
+                let offset = iomap.paddr_to_device_mem_idx(paddr);
+                iomap.device.write(offset, data, len);
+                self.mmio_accessed.set(true);
                 Ok(())
             }
             None => {
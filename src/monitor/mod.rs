@@ -1,15 +1,18 @@
 pub mod sdb;
 
+use crate::isa::riscv64::csr::MCauseCode;
 use crate::memory::Memory;
-use crate::utils::configs::{CONFIG_IMAGE_BASE, CONFIG_MEM_SIZE};
+use crate::monitor::sdb::difftest::RefBackend;
+use crate::utils::configs::{CONFIG_IMAGE_BASE, CONFIG_MEM_BASE, CONFIG_MEM_SIZE};
+use crate::utils::elf::{ElfFile, EM_RISCV, PT_LOAD};
 use clap::{Parser, ValueEnum};
 use log::{info, LevelFilter};
 use simplelog::{SimpleLogger, WriteLogger};
 use std::fs::File;
-use std::io::Read;
 use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 #[derive(Default)]
 pub struct Args {
@@ -29,6 +32,114 @@ pub struct Args {
     #[arg(short, long)]
     pub difftest: bool,
 
+    /// reference model for difftest
+    #[arg(long, value_name = "MODEL", default_value = "qemu")]
+    pub(crate) difftest_ref: RefBackend,
+
+    /// commit log in spike's --log-commits format, read by --difftest-ref trace
+    #[arg(long, value_name = "FILE")]
+    pub(crate) difftest_trace: Option<String>,
+
+    /// qemu binary used as the difftest reference
+    #[arg(long, value_name = "PATH", default_value = "/opt/qemu/bin/qemu-system-riscv64")]
+    pub(crate) qemu: String,
+
+    /// machine arguments for the difftest qemu, the firmware, image and gdb options are added
+    #[arg(
+        long,
+        value_name = "ARGS",
+        allow_hyphen_values = true,
+        default_value = "-M virt -m 256M -nographic"
+    )]
+    pub(crate) qemu_args: String,
+
+    /// gdb port of the difftest qemu
+    #[arg(long, value_name = "PORT", default_value_t = 1234)]
+    pub(crate) difftest_port: u16,
+
+    /// in batch mode, compare with the difftest reference every N instructions
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub(crate) difftest_interval: u64,
+
+    /// csrs compared with the difftest reference after every instruction
+    #[arg(
+        long,
+        value_name = "CSR,...",
+        value_delimiter = ',',
+        default_value = "mstatus,mtvec,mepc,mcause,mtval,medeleg,mideleg,mie,satp,stvec,sepc,scause,stval"
+    )]
+    pub(crate) difftest_csrs: Vec<String>,
+
+    /// memory range compared with the difftest reference after every instruction, repeatable
+    #[arg(long, value_name = "VADDR:LEN", value_parser = parse_mem_range)]
+    pub(crate) difftest_mem: Vec<(u64, u64)>,
+
+    /// restore machine state from a snapshot created by savevm
+    #[arg(long, value_name = "FILE", conflicts_with = "difftest")]
+    pub(crate) restore: Option<String>,
+
+    /// log nondeterministic inputs (device reads, time, interrupts) with instruction count to FILE
+    #[arg(long, value_name = "FILE")]
+    pub(crate) record: Option<String>,
+
+    /// feed inputs recorded by --record from FILE instead of the host
+    #[arg(long, value_name = "FILE", conflicts_with = "record")]
+    pub(crate) replay: Option<String>,
+
+    /// take an in-memory checkpoint every N instructions, enables reverse debugging in sdb
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "difftest"
+    )]
+    pub(crate) checkpoint_interval: Option<u64>,
+
+    /// wait for gdb to attach on PORT and let it drive the cpu instead of sdb
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["batch", "difftest"])]
+    pub(crate) gdb: Option<u16>,
+
+    /// run the sdb commands in FILE first, with --batch only them
+    #[arg(long, value_name = "FILE", conflicts_with = "gdb")]
+    pub(crate) sdb_script: Option<String>,
+
+    /// keep the last N instructions and traps, dumped on panic, invalid instructions and sdb bt
+    #[arg(long, value_name = "N", default_value_t = 64)]
+    pub(crate) itrace: usize,
+
+    /// also dump the instruction trace when trapping with one of these causes, e.g. IllegalInst
+    #[arg(long, value_name = "CAUSE,...", value_delimiter = ',', value_parser = parse_mcause)]
+    pub(crate) itrace_dump_on: Vec<MCauseCode>,
+
+    /// trace memory accesses to FILE, or to a ring buffer of N entries with ring:N
+    #[arg(long, value_name = "FILE|ring:N")]
+    pub(crate) mtrace: Option<String>,
+
+    /// only trace matching accesses, repeatable: paddr=LO-HI, vaddr=LO+LEN, dev=uart, priv=M, type=RW
+    #[arg(long, value_name = "KIND=VALUE", requires = "mtrace")]
+    pub(crate) mtrace_filter: Vec<String>,
+
+    /// load function symbols from ELF files, FILE@OFFSET adds OFFSET to every symbol
+    #[arg(long, value_name = "FILE[@OFFSET],...", value_delimiter = ',')]
+    pub(crate) symbols: Vec<String>,
+
+    /// trace function calls, returns and traps to FILE, or to the log without FILE
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "-")]
+    pub(crate) ftrace: Option<String>,
+
+    /// write retired instructions to FILE in spike's --log-commits format, gzipped if FILE ends with .gz
+    #[arg(long, value_name = "FILE")]
+    pub(crate) commit_log: Option<String>,
+
+    /// only log instructions START (inclusive) to END (exclusive) to the commit log, END may be omitted
+    #[arg(long, value_name = "START:END", value_parser = parse_inst_window, requires = "commit_log")]
+    pub(crate) commit_log_window: Option<(u64, u64)>,
+
     /// run with batch mode
     #[arg(short, long)]
     pub batch: bool,
@@ -44,9 +155,46 @@ pub struct Args {
     #[arg(long)]
     pub log_level: LogLevel,
 
+    /// derive mtime/time from instruction count instead of host time, N instructions per microsecond
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub icount: Option<u64>,
+
     /// close the created terminal after n seconds. If not provided, term won't automatically close.
     #[arg(long)]
     pub(crate) term_timeout: Option<u64>,
+
+    /// wfi retires at once instead of waiting, for the nemu difftest reference which never
+    /// gets an interrupt
+    #[arg(skip)]
+    pub(crate) wfi_nop: bool,
+}
+
+fn parse_mem_range(s: &str) -> Result<(u64, u64), String> {
+    let parse = |s: &str| match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    let (addr, len) = s.split_once(':').ok_or("expected VADDR:LEN")?;
+    let addr = parse(addr).map_err(|e| format!("bad address {}: {}", addr, e))?;
+    let len = parse(len).map_err(|e| format!("bad length {}: {}", len, e))?;
+    Ok((addr, len))
+}
+
+fn parse_mcause(s: &str) -> Result<MCauseCode, String> {
+    MCauseCode::from_str(s).map_err(|_| format!("unknown trap cause {}", s))
+}
+
+fn parse_inst_window(s: &str) -> Result<(u64, u64), String> {
+    let (start, end) = s.split_once(':').ok_or("expected START:END")?;
+    let start = start.parse().map_err(|e| format!("bad start {}: {}", start, e))?;
+    let end = match end {
+        "" => u64::MAX,
+        end => end.parse().map_err(|e| format!("bad end {}: {}", end, e))?,
+    };
+    if start >= end {
+        return Err("the window is empty".to_string());
+    }
+    Ok((start, end))
 }
 
 #[derive(ValueEnum, Debug, Default, Copy, Clone)]
@@ -86,23 +234,92 @@ pub fn init_log(args: &Args) {
     .expect("Failed to create logger.");
 }
 
-pub(crate) fn load_firmware(firm_file: &String, has_img: bool, memory: &mut Memory) -> usize {
-    let path = Path::new(firm_file);
-    let mut f = File::open(path).unwrap();
-    let size = f.metadata().unwrap().len();
-    if (has_img && size > CONFIG_IMAGE_BASE as u64) || size > CONFIG_MEM_SIZE as u64 {
-        panic!("Firm too large ({} or {:#x} bytes).", size, size);
+/// A firmware or image file copied into pmem.
+pub(crate) struct Loaded {
+    pub(crate) size: usize,
+    /// Set for ELF files, which start at their entry and bring their own symbols.
+    pub(crate) elf: Option<ElfFile>,
+}
+
+/// Load the PT_LOAD segments of `elf` at their physical addresses and zero-fill their .bss.
+/// Every segment has to fit in the `limit` bytes at `base`, like a raw binary.
+fn load_elf(
+    path: &str,
+    elf: ElfFile,
+    base: usize,
+    limit: usize,
+    memory: &mut Memory,
+) -> Result<Loaded, String> {
+    if elf.machine != EM_RISCV {
+        return Err(format!("{}: not a RISC-V ELF file (e_machine {})", path, elf.machine));
+    }
+    let left = CONFIG_MEM_BASE.value() + base as u64;
+    let right = left + limit as u64;
+    let mut size = 0;
+    for seg in elf.segments.iter().filter(|s| s.typ == PT_LOAD && s.memsz > 0) {
+        let end = seg.paddr.checked_add(seg.memsz);
+        if seg.paddr < left || end.is_none_or(|end| end > right) {
+            return Err(format!(
+                "{}: segment [{:#x}, +{:#x}) is outside [{:#x}, {:#x})",
+                path, seg.paddr, seg.memsz, left, right
+            ));
+        }
+        let data = elf
+            .segment_data(seg)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        memory
+            .load(seg.paddr, data, seg.memsz)
+            .map_err(|e| format!("{}: segment at {:#x}: {}", path, seg.paddr, e))?;
+        size += seg.memsz as usize;
     }
-    f.read(&mut memory.pmem).unwrap()
+    info!("{}: ELF loaded, entry {:#x}", path, elf.entry);
+    Ok(Loaded {
+        size,
+        elf: Some(elf),
+    })
 }
 
-pub(crate) fn load_img(img_file: &String, memory: &mut Memory) -> usize {
-    info!("loading image {}", img_file);
-    let path = Path::new(img_file);
-    let mut f = File::open(path).unwrap();
-    let size = f.metadata().unwrap().len();
-    if size > (CONFIG_MEM_SIZE - CONFIG_IMAGE_BASE) as u64 {
-        panic!("Image too large ({} or {:#x} bytes).", size, size);
+/// Load a raw binary at `base`, at most `limit` bytes.
+fn load_raw(path: &str, base: usize, limit: usize, memory: &mut Memory) -> Result<Loaded, String> {
+    let data = std::fs::read(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    if ElfFile::is_elf(&data) {
+        let elf = ElfFile::parse(data).map_err(|e| format!("{}: {}", path, e))?;
+        return load_elf(path, elf, base, limit, memory);
+    }
+    if data.len() > limit {
+        return Err(format!(
+            "{} too large ({} or {:#x} bytes).",
+            path,
+            data.len(),
+            data.len()
+        ));
     }
-    f.read(&mut memory.pmem[CONFIG_IMAGE_BASE..]).unwrap()
+    memory.pmem[base..base + data.len()].copy_from_slice(&data);
+    Ok(Loaded {
+        size: data.len(),
+        elf: None,
+    })
+}
+
+pub(crate) fn load_firmware(
+    firm_file: &str,
+    has_img: bool,
+    memory: &mut Memory,
+) -> Result<Loaded, String> {
+    let limit = if has_img {
+        CONFIG_IMAGE_BASE
+    } else {
+        CONFIG_MEM_SIZE
+    };
+    load_raw(firm_file, 0, limit, memory)
+}
+
+pub(crate) fn load_img(img_file: &str, memory: &mut Memory) -> Result<Loaded, String> {
+    info!("loading image {}", img_file);
+    load_raw(
+        img_file,
+        CONFIG_IMAGE_BASE,
+        CONFIG_MEM_SIZE - CONFIG_IMAGE_BASE,
+        memory,
+    )
 }
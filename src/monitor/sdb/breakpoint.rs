@@ -0,0 +1,520 @@
+//! Breakpoints and watchpoints of sdb, numbered together like in gdb.
+
+use crate::isa::riscv64::watch::{AccessWatch, WatchKind};
+use crate::isa::Isa;
+use crate::monitor::sdb::eval::{eval, eval_expr, parse, Expr};
+use crate::Emulator;
+use log::info;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+
+enum Kind {
+    Break {
+        addr: u64,
+        physical: bool,
+        /// only stop in this privilege mode
+        privilege: Option<&'static str>,
+    },
+    Watch {
+        expr: Expr,
+        prev_val: i64,
+    },
+    /// checked by the MMU, the id of the watch is the point's number
+    Access(AccessWatch),
+}
+
+struct StopPoint {
+    kind: Kind,
+    /// the location or expression as typed
+    what: String,
+    enabled: bool,
+    temporary: bool,
+    cond: Option<(Expr, String)>,
+    /// hits left to ignore
+    ignore: u64,
+    hits: u64,
+    /// sdb commands run when it stops
+    commands: Vec<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct StopPoints {
+    points: BTreeMap<u32, StopPoint>,
+    next_idx: u32,
+    /// addresses of enabled breakpoints, so the pc is checked with a lookup
+    vaddrs: HashSet<u64>,
+    paddrs: HashSet<u64>,
+    watching: bool,
+    /// access watchpoints not handed to the cpu yet
+    pending_access: Option<Vec<AccessWatch>>,
+    /// commands of the points that stopped, to run next
+    hit_commands: Vec<String>,
+}
+
+fn point_name(kind: &Kind, temporary: bool) -> &'static str {
+    match (kind, temporary) {
+        (Kind::Watch { .. }, _) => "Watchpoint",
+        (Kind::Access(watch), _) => match watch.kind {
+            WatchKind::Write => "Hardware watchpoint",
+            WatchKind::Read => "Hardware read watchpoint",
+            WatchKind::Access => "Hardware access (read/write) watchpoint",
+        },
+        (_, true) => "Temporary breakpoint",
+        (_, false) => "Breakpoint",
+    }
+}
+
+/// A code address, where a bare number is hex like `b` always took it.
+pub(crate) fn parse_location<T: Isa>(
+    emulator: &mut Emulator<T>,
+    location: &str,
+) -> Result<u64, String> {
+    let hex = u64::from_str_radix(location, 16).ok();
+    match hex.filter(|_| location.starts_with(|c: char| c.is_ascii_digit())) {
+        Some(addr) => Ok(addr),
+        None => eval(location, emulator).map(|addr| addr as u64),
+    }
+}
+
+fn parse_num(s: &str) -> Result<u32, String> {
+    s.parse()
+        .map_err(|_| format!("bad breakpoint number {}", s))
+}
+
+impl StopPoints {
+    fn sync(&mut self) {
+        self.vaddrs.clear();
+        self.paddrs.clear();
+        self.watching = false;
+        let mut access = Vec::new();
+        for point in self.points.values().filter(|p| p.enabled) {
+            match point.kind {
+                Kind::Break {
+                    addr,
+                    physical: false,
+                    ..
+                } => {
+                    self.vaddrs.insert(addr);
+                }
+                Kind::Break { addr, .. } => {
+                    self.paddrs.insert(addr);
+                }
+                Kind::Watch { .. } => self.watching = true,
+                Kind::Access(watch) => access.push(watch),
+            }
+        }
+        self.pending_access = Some(access);
+    }
+
+    /// Hand changed access watchpoints to the cpu, before it executes again.
+    pub(crate) fn arm<T: Isa>(&mut self, cpu: &mut T) {
+        if let Some(access) = self.pending_access.take() {
+            cpu.isa_set_watches(access);
+        }
+    }
+
+    fn insert(
+        &mut self,
+        mut kind: Kind,
+        what: String,
+        temporary: bool,
+        cond: Option<(Expr, String)>,
+    ) {
+        self.next_idx += 1;
+        if let Kind::Access(watch) = &mut kind {
+            watch.id = self.next_idx;
+        }
+        info!("{} {}: {}", point_name(&kind, temporary), self.next_idx, what);
+        self.points.insert(
+            self.next_idx,
+            StopPoint {
+                kind,
+                what,
+                enabled: true,
+                temporary,
+                cond,
+                ignore: 0,
+                hits: 0,
+                commands: Vec::new(),
+            },
+        );
+        self.sync();
+    }
+
+    /// `[/FLAGS] ADDR [if COND]`, FLAGS has p for a physical address and M, S or U to only
+    /// stop in that mode.
+    pub(crate) fn break_command<T: Isa>(
+        &mut self,
+        emulator: &mut Emulator<T>,
+        args: &str,
+        temporary: bool,
+    ) -> Result<(), String> {
+        let (mut physical, mut privilege) = (false, None);
+        let mut args = args.trim();
+        if let Some(rest) = args.strip_prefix('/') {
+            let (flags, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            for flag in flags.chars() {
+                match flag {
+                    'p' => physical = true,
+                    'M' => privilege = Some("M"),
+                    'S' => privilege = Some("S"),
+                    'U' => privilege = Some("U"),
+                    _ => return Err(format!("unknown breakpoint flag {}", flag)),
+                }
+            }
+            args = rest.trim_start();
+        }
+        let (location, cond) = match args.split_once(" if ") {
+            Some((location, cond)) => (location.trim(), Some(cond.trim())),
+            None => (args, None),
+        };
+        if location.is_empty() {
+            return Err("Argument required (breakpoint address).".to_string());
+        }
+        let addr = parse_location(emulator, location)?;
+        let cond = cond
+            .map(|c| parse(c).map(|expr| (expr, c.to_string())))
+            .transpose()?;
+        let mut what = format!("{:#x}", addr);
+        if physical {
+            what += " (physical)";
+        }
+        if let Some(privilege) = privilege {
+            write!(what, " in {} mode", privilege).unwrap();
+        }
+        let kind = Kind::Break {
+            addr,
+            physical,
+            privilege,
+        };
+        self.insert(kind, what, temporary, cond);
+        Ok(())
+    }
+
+    pub(crate) fn add_watch(&mut self, expr: Expr, expr_str: &str, prev_val: i64) {
+        let kind = Kind::Watch { expr, prev_val };
+        self.insert(kind, expr_str.to_string(), false, None);
+    }
+
+    /// `[/LEN] *LOC` of `watch`, `rwatch` or `awatch`: stop right after an instruction accesses
+    /// the location, or LEN bytes from it. `watch` of anything else watches the value instead.
+    pub(crate) fn watch_command<T: Isa>(
+        &mut self,
+        emulator: &mut Emulator<T>,
+        args: &str,
+        kind: WatchKind,
+    ) -> Result<(), String> {
+        let mut args = args.trim();
+        let mut len = None;
+        if let Some(rest) = args.strip_prefix('/') {
+            let (n, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            len = Some(n.parse().map_err(|_| format!("bad length {}", n))?);
+            args = rest.trim_start();
+        }
+        let expr = parse(args)?;
+        let Expr::Deref { ty, physical, addr } = &expr else {
+            if kind != WatchKind::Write || len.is_some() {
+                return Err("Expression cannot be watched by address, use *(TYPE*)ADDR".into());
+            }
+            let prev_val = eval_expr(&expr, emulator)?;
+            self.add_watch(expr, args, prev_val);
+            return Ok(());
+        };
+        let watch = AccessWatch {
+            id: 0,
+            kind,
+            addr: eval_expr(addr, emulator)? as u64,
+            len: len.unwrap_or(ty.len()),
+            physical: *physical,
+        };
+        self.insert(Kind::Access(watch), args.to_string(), false, None);
+        Ok(())
+    }
+
+    fn get_mut(&mut self, idx: &str) -> Result<&mut StopPoint, String> {
+        let idx = parse_num(idx)?;
+        self.points
+            .get_mut(&idx)
+            .ok_or(format!("No breakpoint number {}", idx))
+    }
+
+    /// `delete [N...]`, everything without numbers.
+    pub(crate) fn delete(&mut self, args: &str) -> Result<(), String> {
+        if args.trim().is_empty() {
+            self.points.clear();
+        }
+        // parse everything first, a bad number leaves all points alone
+        let idxs = args
+            .split_whitespace()
+            .map(parse_num)
+            .collect::<Result<Vec<_>, _>>()?;
+        for idx in idxs {
+            if self.points.remove(&idx).is_none() {
+                info!("No breakpoint number {}", idx);
+            }
+        }
+        self.sync();
+        Ok(())
+    }
+
+    /// `enable [N...]` or `disable [N...]`, everything without numbers.
+    pub(crate) fn set_enabled<T: Isa>(
+        &mut self,
+        emulator: &mut Emulator<T>,
+        args: &str,
+        enabled: bool,
+    ) -> Result<(), String> {
+        if args.trim().is_empty() {
+            self.points.values_mut().for_each(|p| p.enabled = enabled);
+        }
+        // check every number first, so a bad one changes nothing
+        for idx in args.split_whitespace() {
+            self.get_mut(idx)?;
+        }
+        for idx in args.split_whitespace() {
+            self.get_mut(idx)?.enabled = enabled;
+        }
+        self.sync();
+        // a watchpoint shouldn't fire for changes made while it was disabled
+        self.refresh(emulator);
+        Ok(())
+    }
+
+    /// `ignore N COUNT`
+    pub(crate) fn ignore(&mut self, args: &str) -> Result<(), String> {
+        let (idx, count) = args
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or("Argument required (a breakpoint number and a count).")?;
+        let count = count
+            .trim()
+            .parse()
+            .map_err(|_| format!("bad count {}", count.trim()))?;
+        self.get_mut(idx)?.ignore = count;
+        info!(
+            "Will ignore next {} crossings of breakpoint {}.",
+            count, idx
+        );
+        Ok(())
+    }
+
+    /// `cond N [EXPR]`, without EXPR the breakpoint becomes unconditional.
+    pub(crate) fn cond(&mut self, args: &str) -> Result<(), String> {
+        let args = args.trim();
+        let (idx, expr) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let expr = expr.trim();
+        let cond = match expr {
+            "" => None,
+            expr => Some((parse(expr)?, expr.to_string())),
+        };
+        self.get_mut(idx)?.cond = cond;
+        Ok(())
+    }
+
+    /// `commands [N]` with the lines up to `end`, for the last point without N.
+    pub(crate) fn set_commands(&mut self, idx: &str, lines: Vec<String>) -> Result<(), String> {
+        let point = match idx.trim() {
+            "" => self
+                .points
+                .get_mut(&self.next_idx)
+                .ok_or("No breakpoints specified.")?,
+            idx => self.get_mut(idx)?,
+        };
+        point.commands = lines;
+        Ok(())
+    }
+
+    /// Commands of the points that stopped since the last call.
+    pub(crate) fn take_hit_commands(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.hit_commands)
+    }
+
+    /// The table of `info break` or, with `watch`, of `info watch`.
+    pub(crate) fn info(&self, watch: bool) -> String {
+        let mut res = String::from("Num  Type           Enb Hits What");
+        let points = self
+            .points
+            .iter()
+            .filter(|(_, p)| !matches!(p.kind, Kind::Break { .. }) == watch);
+        for (idx, point) in points {
+            let typ = match (&point.kind, point.temporary) {
+                (Kind::Watch { .. }, _) => "watchpoint",
+                (Kind::Access(watch), _) => match watch.kind {
+                    WatchKind::Write => "hw watchpoint",
+                    WatchKind::Read => "read watchpoint",
+                    WatchKind::Access => "acc watchpoint",
+                },
+                (_, true) => "tbreakpoint",
+                (_, false) => "breakpoint",
+            };
+            let enb = if point.enabled { 'y' } else { 'n' };
+            write!(
+                res,
+                "\n{:<4} {:<14} {:<3} {:<4} {}",
+                idx, typ, enb, point.hits, point.what
+            )
+            .unwrap();
+            if let Some((_, cond)) = &point.cond {
+                write!(res, "\n\tstop only if {}", cond).unwrap();
+            }
+            if point.ignore > 0 {
+                write!(res, "\n\tignore next {} hits", point.ignore).unwrap();
+            }
+            for line in &point.commands {
+                write!(res, "\n\t  {}", line).unwrap();
+            }
+        }
+        res
+    }
+
+    /// Watchpoint values and accesses are stale after the machine state jumps.
+    pub(crate) fn refresh<T: Isa>(&mut self, emulator: &mut Emulator<T>) {
+        emulator.cpu.isa_take_watch_hit();
+        for point in self.points.values_mut() {
+            if let Kind::Watch { expr, prev_val } = &mut point.kind {
+                if let Ok(val) = eval_expr(expr, emulator) {
+                    *prev_val = val;
+                }
+            }
+        }
+    }
+
+    /// Whether a watchpoint changed or a breakpoint is hit. `report` prints which one and
+    /// counts the hit, without it ignore counts and temporary breakpoints are left alone.
+    pub(crate) fn check<T: Isa>(&mut self, emulator: &mut Emulator<T>, report: bool) -> bool {
+        // (number, what to report)
+        let mut hits = Vec::new();
+        if self.watching {
+            for (idx, point) in self.points.iter_mut().filter(|(_, p)| p.enabled) {
+                let Kind::Watch { expr, prev_val } = &mut point.kind else {
+                    continue;
+                };
+                match eval_expr(expr, emulator) {
+                    Ok(val) if val == *prev_val => {}
+                    Ok(val) => {
+                        let msg = format!(
+                            "Watchpoint {}: {}\nOld value = {}\nNew value = {}",
+                            idx, point.what, prev_val, val
+                        );
+                        *prev_val = val;
+                        hits.push((*idx, msg));
+                    }
+                    Err(err) => hits.push((*idx, err)),
+                }
+            }
+        }
+        if let Some(hit) = emulator.cpu.isa_take_watch_hit() {
+            if let Some(point) = self.points.get(&hit.id) {
+                let name = point_name(&point.kind, false);
+                let mut msg = format!("{} {}: {}", name, hit.id, point.what);
+                let (op, dir) = if hit.write { ("store", "to") } else { ("load", "from") };
+                write!(msg, "\n{}-byte {} {} {:#x}", hit.size, op, dir, hit.vaddr).unwrap();
+                if hit.paddr != hit.vaddr {
+                    write!(msg, " (paddr {:#x})", hit.paddr).unwrap();
+                }
+                match (hit.write, hit.old) {
+                    (false, _) => write!(msg, "\nValue = {:#x}", hit.value),
+                    (true, Some(old)) => {
+                        write!(msg, "\nOld value = {:#x}\nNew value = {:#x}", old, hit.value)
+                    }
+                    (true, None) => write!(msg, "\nNew value = {:#x}", hit.value),
+                }
+                .unwrap();
+                hits.push((hit.id, msg));
+            }
+        }
+        let pc = emulator.cpu.isa_get_pc();
+        let paddr = match self.paddrs.is_empty() {
+            true => None,
+            false => emulator.cpu.isa_get_pc_paddr(),
+        };
+        if self.vaddrs.contains(&pc) || paddr.is_some_and(|p| self.paddrs.contains(&p)) {
+            let privilege = emulator.cpu.isa_priv_name();
+            for (idx, point) in self.points.iter().filter(|(_, p)| p.enabled) {
+                let Kind::Break {
+                    addr,
+                    physical,
+                    privilege: only_in,
+                } = point.kind
+                else {
+                    continue;
+                };
+                let at = if physical { paddr } else { Some(pc) };
+                if at == Some(addr) && only_in.is_none_or(|p| p == privilege) {
+                    let name = match point.temporary {
+                        true => "Temporary breakpoint",
+                        false => "Breakpoint",
+                    };
+                    hits.push((*idx, format!("{} {}: {}", name, idx, point.what)));
+                }
+            }
+        }
+
+        let mut pause = false;
+        for (idx, msg) in hits {
+            let point = self.points.get_mut(&idx).unwrap();
+            if let Some((cond, cond_str)) = &point.cond {
+                match eval_expr(cond, emulator) {
+                    Ok(0) => continue,
+                    Ok(_) => {}
+                    Err(err) if report => info!("Error in condition {}: {}", cond_str, err),
+                    Err(_) => {}
+                }
+            }
+            // gdb counts the ignored crossings as hits too
+            if report {
+                point.hits += 1;
+            }
+            if point.ignore > 0 {
+                if report {
+                    point.ignore -= 1;
+                }
+                continue;
+            }
+            pause = true;
+            if !report {
+                continue;
+            }
+            info!("{}", msg);
+            self.hit_commands.extend(point.commands.iter().cloned());
+            if point.temporary {
+                self.points.remove(&idx);
+                self.sync();
+            }
+        }
+        pause
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_and_lookup_sets() {
+        let mut stops = StopPoints::default();
+        for (addr, physical) in [(0x80000000, false), (0x1000, true)] {
+            let kind = Kind::Break {
+                addr,
+                physical,
+                privilege: None,
+            };
+            stops.insert(kind, format!("{:#x}", addr), false, None);
+        }
+        assert!(stops.vaddrs.contains(&0x80000000) && stops.paddrs.contains(&0x1000));
+
+        stops.cond("1 $a0 == 1").unwrap();
+        stops.ignore("2 3").unwrap();
+        assert!(stops.cond("3 1").is_err());
+        let info = stops.info(false);
+        assert!(info.contains("stop only if $a0 == 1") && info.contains("ignore next 3 hits"));
+        assert_eq!(stops.info(true).lines().count(), 1);
+
+        assert!(stops.delete("1 x").is_err());
+        assert!(stops.vaddrs.contains(&0x80000000));
+        stops.delete("2").unwrap();
+        assert!(stops.paddrs.is_empty());
+        stops.delete("").unwrap();
+        assert!(stops.vaddrs.is_empty());
+    }
+}
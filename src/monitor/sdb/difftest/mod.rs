@@ -0,0 +1,258 @@
+use crate::isa::Isa;
+use crate::monitor::Args;
+use crate::utils::cfg_if_feat;
+use cfg_if::cfg_if;
+use clap::ValueEnum;
+
+cfg_if! {
+    if #[cfg(feature="difftest")] {
+        mod nemu;
+        mod qemu;
+        mod trace;
+
+        use log::{error, info};
+        use std::fmt::Write;
+        use crate::isa::riscv64::vaddr::{MemOperationSize, VAddr};
+        use nemu::NemuRef;
+        use qemu::QemuRef;
+        use trace::TraceRef;
+
+        /// A reference model nemu is compared with, one instruction at a time.
+        pub trait RefModel {
+            /// Execute one instruction.
+            fn step(&mut self) -> Result<(), String>;
+            /// Called instead of `step` for an instruction that accessed devices or
+            /// nondeterministic csrs, its effects are synced from nemu afterwards. References
+            /// with devices of their own execute it anyway.
+            fn skip(&mut self) -> Result<(), String> {
+                self.step()
+            }
+            /// GPRs followed by pc.
+            fn read_regs(&mut self) -> Vec<u64>;
+            /// None if the reference doesn't know the csr, it isn't compared then.
+            fn read_csr(&mut self, name: &str) -> Option<u64>;
+            /// None if the reference can't tell, the range isn't compared then.
+            fn read_mem(&mut self, addr: u64, len: u64) -> Option<Vec<u8>>;
+            /// Overwrite GPRs and pc.
+            fn write_regs(&mut self, regs: &[u64]);
+            /// Csrs the reference doesn't know may be ignored.
+            fn write_csr(&mut self, name: &str, val: u64);
+            /// Only needed by references that don't execute skipped instructions.
+            fn write_mem(&mut self, _addr: u64, _data: &[u8]) {}
+            fn exit(&mut self) {}
+        }
+
+        /// Changed when a skipped instruction traps, synced with the compared csrs.
+        const TRAP_CSRS: [&str; 8] =
+            ["mstatus", "mepc", "mcause", "mtval", "sepc", "scause", "stval", "mip"];
+
+        pub struct DifftestContext {
+            reference: Box<dyn RefModel>,
+            csrs: Vec<String>,
+            mem: Vec<(u64, u64)>,
+        }
+    } else {
+        pub struct DifftestContext {}
+    }
+}
+
+impl DifftestContext {
+    pub fn exit(&mut self) {
+        cfg_if_feat!("difftest", {
+            self.reference.exit();
+        });
+    }
+}
+
+#[derive(ValueEnum, Debug, Default, Copy, Clone)]
+pub enum RefBackend {
+    /// qemu-system-riscv64 driven over its gdb stub
+    #[default]
+    Qemu,
+    /// a second nemu instance in this process, e.g. the interpreter checking an optimized build
+    Nemu,
+    /// a commit log in spike's --log-commits format, see --difftest-trace
+    Trace,
+}
+
+/// How to run the reference and what to compare, from the --qemu* and --difftest-* options.
+#[allow(dead_code)]
+pub struct DifftestConfig {
+    pub(crate) reference: RefBackend,
+    pub(crate) qemu_bin: String,
+    /// machine arguments, the firmware, image and gdb options are added
+    pub(crate) qemu_args: Vec<String>,
+    pub(crate) port: u16,
+    /// commit log read by the trace backend
+    pub(crate) trace: Option<String>,
+    /// loaded at CONFIG_MEM_BASE + CONFIG_IMAGE_BASE, like nemu does
+    pub(crate) image: Option<String>,
+    /// compared in addition to GPRs and pc
+    pub(crate) csrs: Vec<String>,
+    /// (vaddr, len) ranges
+    pub(crate) mem: Vec<(u64, u64)>,
+}
+
+/// Machine state at the reset vector, applied to both nemu and the reference.
+#[allow(dead_code)]
+pub struct DifftestInfo {
+    pub(crate) reset_vec: u64,
+    /// (gdb register number, value)
+    pub(crate) reset_regs: Vec<(usize, u64)>,
+}
+
+impl DifftestContext {
+    pub fn init<T: Isa + 'static>(
+        _info: DifftestInfo,
+        _config: DifftestConfig,
+        _binary: &String,
+        _args: &Args,
+    ) -> Self {
+        cfg_if! {
+            if #[cfg(feature="difftest")] {
+                let reference: Box<dyn RefModel> = match _config.reference {
+                    RefBackend::Qemu => Box::new(QemuRef::new(&_info, &_config, _binary)),
+                    RefBackend::Nemu => Box::new(NemuRef::<T>::new(&_config, _binary, _args)),
+                    RefBackend::Trace => {
+                        let path = _config
+                            .trace
+                            .as_ref()
+                            .expect("the trace reference needs --difftest-trace");
+                        Box::new(TraceRef::new(&_info, path))
+                    }
+                };
+                info!("Difftest enabled, reference: {:?}", _config.reference);
+                Self {
+                    reference,
+                    csrs: _config.csrs,
+                    mem: _config.mem,
+                }
+            } else {
+                Self {}
+            }
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature="difftest")] {
+        impl DifftestContext {
+            /// Advance the reference by one instruction, after nemu has executed it. Syncs the
+            /// reference if the instruction accessed devices or nemu took an interrupt after
+            /// it, else compares if `compare`.
+            pub fn step<T: Isa>(&mut self, cpu: &mut T, compare: bool) -> Result<(), String> {
+                let skip = cpu.isa_difftest_take_skip();
+                let interrupted = cpu.isa_difftest_take_interrupt();
+                if skip {
+                    self.reference.skip()?;
+                } else {
+                    self.reference.step()?;
+                }
+                if skip || interrupted {
+                    self.sync_from(cpu);
+                    return Ok(());
+                }
+                if compare {
+                    self.check(cpu)?;
+                }
+                Ok(())
+            }
+
+            /// Copy GPRs, pc, the csrs a trap changes, the compared csrs and memory ranges and
+            /// the privilege mode into the reference, after an instruction whose result
+            /// legitimately differs, like NEMU's `difftest_skip_ref`.
+            pub fn sync_from<T: Isa>(&mut self, cpu: &mut T) {
+                let regs: Vec<u64> = (0..cpu.isa_gdb_reg_count())
+                    .map(|regno| cpu.isa_gdb_read_reg(regno).unwrap_or(0))
+                    .collect();
+                self.reference.write_regs(&regs);
+                let csrs = TRAP_CSRS.iter().copied().chain(self.csrs.iter().map(String::as_str));
+                // the privilege mode last, writing mstatus may change how it translates
+                for name in csrs.chain(["priv"]) {
+                    match cpu.isa_get_reg_by_name(name) {
+                        Ok(val) => self.reference.write_csr(name, val),
+                        Err(err) => error!("{}: {}", name, err),
+                    }
+                }
+                for &(addr, len) in &self.mem {
+                    let data: Option<Vec<u8>> = (addr..addr + len)
+                        .map(|a| {
+                            cpu.peek_vaddr(&VAddr::new(a), MemOperationSize::Byte)
+                                .ok()
+                                .map(|b| b as u8)
+                        })
+                        .collect();
+                    if let Some(data) = data {
+                        self.reference.write_mem(addr, &data);
+                    }
+                }
+            }
+
+            /// Compare GPRs, pc, the selected csrs and memory ranges with the reference.
+            /// On mismatch, the error lists every differing item side by side.
+            pub fn check<T: Isa>(&mut self, cpu: &mut T) -> Result<(), String> {
+                let regs = self.reference.read_regs();
+                cpu.isa_difftest_check_regs(&regs)?;
+
+                let mut diff = String::new();
+                for name in &self.csrs {
+                    let Some(reference) = self.reference.read_csr(name) else {
+                        continue;
+                    };
+                    let local = cpu.isa_get_reg_by_name(name).ok();
+                    if local != Some(reference) {
+                        let local = local.map_or("-".to_string(), |v| format!("{:#x}", v));
+                        let reference = format!("{:#x}", reference);
+                        writeln!(diff, "{:<12}{:<22}{:<22}", name, local, reference).unwrap();
+                    }
+                }
+                if !diff.is_empty() {
+                    let header = format!("{:<12}{:<22}{:<22}\n", "csr", "local", "difftest");
+                    diff.insert_str(0, &header);
+                }
+
+                let mut mem_diff = String::new();
+                for &(addr, len) in &self.mem {
+                    let Some(reference) = self.reference.read_mem(addr, len) else {
+                        continue;
+                    };
+                    for row in (0..len).step_by(8) {
+                        let row_len = (len - row).min(8);
+                        let local: Vec<Option<u8>> = (row..row + row_len)
+                            .map(|i| {
+                                cpu.peek_vaddr(&VAddr::new(addr + i), MemOperationSize::Byte)
+                                    .ok()
+                                    .map(|b| b as u8)
+                            })
+                            .collect();
+                        let remote: Vec<Option<u8>> = (row..row + row_len)
+                            .map(|i| reference.get(i as usize).copied())
+                            .collect();
+                        if local != remote {
+                            let fmt = |bytes: &Vec<Option<u8>>| {
+                                bytes
+                                    .iter()
+                                    .map(|b| b.map_or("--".to_string(), |b| format!("{:02x}", b)))
+                                    .collect::<Vec<_>>()
+                                    .join(" ")
+                            };
+                            let (local, remote) = (fmt(&local), fmt(&remote));
+                            writeln!(mem_diff, "{:#018x}  {}  |  {}", addr + row, local, remote)
+                                .unwrap();
+                        }
+                    }
+                }
+
+                if !mem_diff.is_empty() {
+                    writeln!(diff, "memory: local | difftest").unwrap();
+                    diff.push_str(&mem_diff);
+                }
+                if diff.is_empty() {
+                    Ok(())
+                } else {
+                    Err(format!("state mismatch at pc {:#x}:\n{}", cpu.isa_get_pc(), diff))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::device::interrupt::InterruptBits;
+use crate::device::{inst_count, set_inst_count};
+use crate::isa::riscv64::vaddr::{MemOperationSize, VAddr};
+use crate::isa::Isa;
+use crate::memory::Memory;
+use crate::monitor::sdb::difftest::{DifftestConfig, RefModel};
+use crate::monitor::{load_firmware, load_img, Args};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// A second cpu in this process, with its own memory and no devices or timer. Instructions
+/// accessing devices aren't executed by it, their results are synced from nemu.
+pub struct NemuRef<T: Isa> {
+    cpu: T,
+}
+
+impl<T: Isa> NemuRef<T> {
+    pub fn new(config: &DifftestConfig, binary: &str, args: &Args) -> Self {
+        // the reference would consume and log replay events of the checked cpu
+        if args.record.is_some() || args.replay.is_some() {
+            panic!("the nemu difftest reference can't be used with --record or --replay");
+        }
+        let mut memory = Memory::new();
+        if let Some(path) = &config.image {
+            load_img(path, &mut memory).unwrap_or_else(|e| panic!("{}", e));
+        }
+        let firmware = load_firmware(binary, config.image.is_some(), &mut memory)
+            .unwrap_or_else(|e| panic!("{}", e));
+        let mut cpu = T::new(
+            Arc::new(AtomicBool::new(false)),
+            memory,
+            Arc::new(InterruptBits::new(0)),
+            &Args {
+                commit_log: None,
+                // glob_timer belongs to the checked cpu, the reference must not take its interrupt
+                icount: None,
+                // nemu's interrupts reach the reference by syncing
+                wfi_nop: true,
+                ..args.clone()
+            },
+        );
+        if let Some(elf) = &firmware.elf {
+            cpu.isa_set_reg_by_name("pc", elf.entry).unwrap();
+        }
+        cpu.isa_difftest_init();
+        Self { cpu }
+    }
+}
+
+impl<T: Isa> RefModel for NemuRef<T> {
+    fn step(&mut self) -> Result<(), String> {
+        // the instruction counter is global, only the checked cpu may advance it
+        let count = inst_count();
+        // a halted reference is noticed by the next comparison
+        self.cpu.isa_exec_once();
+        set_inst_count(count);
+        Ok(())
+    }
+
+    fn skip(&mut self) -> Result<(), String> {
+        // without devices the access would trap, the state is synced from nemu instead
+        Ok(())
+    }
+
+    fn read_regs(&mut self) -> Vec<u64> {
+        (0..self.cpu.isa_gdb_reg_count())
+            .map(|regno| self.cpu.isa_gdb_read_reg(regno).unwrap_or(0))
+            .collect()
+    }
+
+    fn read_csr(&mut self, name: &str) -> Option<u64> {
+        self.cpu.isa_get_reg_by_name(name).ok()
+    }
+
+    fn read_mem(&mut self, addr: u64, len: u64) -> Option<Vec<u8>> {
+        (addr..addr + len)
+            .map(|a| {
+                self.cpu
+                    .read_vaddr(&VAddr::new(a), MemOperationSize::Byte)
+                    .ok()
+                    .map(|b| b as u8)
+            })
+            .collect()
+    }
+
+    fn write_regs(&mut self, regs: &[u64]) {
+        for (regno, val) in regs.iter().enumerate() {
+            let _ = self.cpu.isa_gdb_write_reg(regno, *val);
+        }
+    }
+
+    fn write_csr(&mut self, name: &str, val: u64) {
+        let _ = self.cpu.isa_set_reg_by_name(name, val);
+    }
+
+    fn write_mem(&mut self, addr: u64, data: &[u8]) {
+        for (a, byte) in (addr..).zip(data) {
+            let _ = self
+                .cpu
+                .write_vaddr(&VAddr::new(a), *byte as u64, MemOperationSize::Byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::riscv64::RISCV64;
+    use crate::monitor::sdb::difftest::RefBackend;
+    use crate::utils::tests::emulator_lock;
+
+    #[test]
+    fn steps_through_wfi() {
+        let _guard = emulator_lock();
+        // wfi; addi a0, a0, 1
+        let program: Vec<u8> = [0x10500073u32, 0x00150513]
+            .iter()
+            .flat_map(|inst| inst.to_le_bytes())
+            .collect();
+        let path = std::env::temp_dir().join(format!("nemu-ref-wfi-{}.bin", std::process::id()));
+        std::fs::write(&path, program).unwrap();
+        let config = DifftestConfig {
+            reference: RefBackend::Nemu,
+            qemu_bin: String::new(),
+            qemu_args: Vec::new(),
+            port: 0,
+            trace: None,
+            image: None,
+            csrs: Vec::new(),
+            mem: Vec::new(),
+        };
+        let mut reference =
+            NemuRef::<RISCV64>::new(&config, path.to_str().unwrap(), &Args::default());
+        std::fs::remove_file(&path).unwrap();
+
+        let a0 = reference.read_regs()[10];
+        reference.step().unwrap();
+        reference.step().unwrap();
+        let regs = reference.read_regs();
+        assert_eq!(regs[10], a0 + 1);
+        assert_eq!(regs[32], 0x80000008);
+    }
+}
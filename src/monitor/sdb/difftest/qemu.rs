@@ -0,0 +1,101 @@
+use crate::monitor::sdb::difftest::{DifftestConfig, DifftestInfo, RefModel};
+use crate::monitor::sdb::gdb_interface::GdbContext;
+use crate::utils::configs::{CONFIG_IMAGE_BASE, CONFIG_MEM_BASE};
+use crate::utils::elf::ElfFile;
+use log::{error, info};
+use std::collections::HashMap;
+use std::process::{Child, Command, Stdio};
+use std::{thread, time};
+
+/// qemu started with `-S`, stepped through its gdb stub.
+pub struct QemuRef {
+    qemu_proc: Child,
+    gdb_ctx: GdbContext,
+    /// csr names and their register numbers in qemu's gdb stub
+    csrs: HashMap<String, usize>,
+}
+
+impl QemuRef {
+    pub fn new(info: &DifftestInfo, config: &DifftestConfig, binary: &str) -> Self {
+        let mut qemu_cmd = Command::new(&config.qemu_bin);
+        qemu_cmd
+            .args(&config.qemu_args)
+            .args(["-gdb", format!("tcp::{}", config.port).as_str(), "-S"])
+            .args(["-bios", binary]);
+        if let Some(image) = &config.image {
+            // qemu places ELF images by their program headers itself
+            let loader = if ElfFile::is_elf_file(image) {
+                format!("loader,file={}", image)
+            } else {
+                let addr = CONFIG_MEM_BASE.value() + CONFIG_IMAGE_BASE as u64;
+                format!("loader,file={},addr={:#x}", image, addr)
+            };
+            qemu_cmd.args(["-device", loader.as_str()]);
+        }
+        let qemu_proc = qemu_cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("couldn't spawn {}: {}", config.qemu_bin, e));
+        info!("qemu spawned with pid {}.", qemu_proc.id());
+        thread::sleep(time::Duration::from_millis(200));
+
+        let mut gdb_ctx = GdbContext::new(config.port);
+        let reg_numbers = gdb_ctx.reg_numbers();
+        let mut csrs = HashMap::new();
+        for name in &config.csrs {
+            match reg_numbers.get(name) {
+                Some(regno) => {
+                    csrs.insert(name.clone(), *regno);
+                }
+                None => error!("qemu doesn't expose csr {}, not compared", name),
+            }
+        }
+        gdb_ctx.continue_to_addr(info.reset_vec);
+        for &(regno, val) in &info.reset_regs {
+            gdb_ctx.write_reg(regno, val);
+        }
+        Self {
+            qemu_proc,
+            gdb_ctx,
+            csrs,
+        }
+    }
+}
+
+impl RefModel for QemuRef {
+    fn step(&mut self) -> Result<(), String> {
+        self.gdb_ctx.step();
+        Ok(())
+    }
+
+    fn read_regs(&mut self) -> Vec<u64> {
+        self.gdb_ctx.read_regs_64()
+    }
+
+    fn read_csr(&mut self, name: &str) -> Option<u64> {
+        let regno = *self.csrs.get(name)?;
+        self.gdb_ctx.read_reg(regno)
+    }
+
+    fn read_mem(&mut self, addr: u64, len: u64) -> Option<Vec<u8>> {
+        // unreadable bytes show up as differences
+        Some(self.gdb_ctx.read_mem(addr, len).unwrap_or_default())
+    }
+
+    fn write_regs(&mut self, regs: &[u64]) {
+        self.gdb_ctx.write_regs_64(regs);
+    }
+
+    fn write_csr(&mut self, name: &str, val: u64) {
+        if let Some(regno) = self.csrs.get(name) {
+            self.gdb_ctx.write_reg(*regno, val);
+        }
+    }
+
+    fn exit(&mut self) {
+        if let Err(err) = self.qemu_proc.kill() {
+            error!("{}", err);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::monitor::sdb::difftest::{DifftestInfo, RefModel};
+use log::warn;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
+
+/// One retired instruction of a commit log.
+#[derive(Debug, Default, PartialEq)]
+struct Commit {
+    pc: u64,
+    regs: Vec<(usize, u64)>,
+    csrs: Vec<(String, u64)>,
+    /// stores as (addr, value, size in bytes)
+    stores: Vec<(u64, u64, u64)>,
+}
+
+/// Parse a line of spike's `--log-commits` output, e.g.
+/// `core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000`.
+/// Writes to registers other than GPRs and csrs are skipped.
+fn parse_commit(line: &str) -> Result<Commit, String> {
+    let parse_hex = |s: &str| {
+        let hex = s
+            .strip_prefix("0x")
+            .ok_or(format!("expected a hex number: {}", s))?;
+        u64::from_str_radix(hex, 16).map_err(|e| format!("{}: {}", s, e))
+    };
+    let (_, rest) = line.split_once(':').ok_or("expected `core N:`")?;
+    let mut tokens = rest.split_whitespace();
+    let _priv = tokens.next().ok_or("missing privilege")?;
+    let pc = parse_hex(tokens.next().ok_or("missing pc")?)?;
+    let _insn = tokens.next().ok_or("missing instruction")?;
+    let mut commit = Commit {
+        pc,
+        ..Default::default()
+    };
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let addr = parse_hex(tokens.next().ok_or("missing memory address")?)?;
+            // loads only log the address
+            let mut peek = tokens.clone();
+            if let Some(val) = peek.next().filter(|t| t.starts_with("0x")) {
+                let size = (val.len() as u64 - 2) / 2;
+                commit.stores.push((addr, parse_hex(val)?, size));
+                tokens = peek;
+            }
+            continue;
+        }
+        let val = parse_hex(tokens.next().ok_or(format!("missing value of {}", token))?)?;
+        if let Some(csr) = token.strip_prefix('c').and_then(|t| t.split_once('_')) {
+            commit.csrs.push((csr.1.to_string(), val));
+        } else if let Some(Ok(regno)) = token.strip_prefix('x').map(str::parse::<usize>) {
+            if regno != 0 {
+                commit.regs.push((regno, val));
+            }
+        }
+    }
+    Ok(commit)
+}
+
+/// Replays a recorded commit log. Registers and csrs are shadowed from the logged writes, so
+/// csrs are only compared once the log has written them, and memory only where it has stored.
+pub struct TraceRef {
+    path: String,
+    lines: Lines<BufReader<File>>,
+    lineno: usize,
+    /// the instruction executed by the next step
+    next: Option<Commit>,
+    regs: [u64; 32],
+    pc: u64,
+    csrs: HashMap<String, u64>,
+    mem: HashMap<u64, u8>,
+}
+
+impl TraceRef {
+    pub fn new(info: &DifftestInfo, path: &String) -> Self {
+        let file = File::open(path).unwrap_or_else(|e| panic!("couldn't open {}: {}", path, e));
+        let mut trace = Self {
+            path: path.clone(),
+            lines: BufReader::new(file).lines(),
+            lineno: 0,
+            next: None,
+            regs: [0; 32],
+            pc: 0,
+            csrs: HashMap::new(),
+            mem: HashMap::new(),
+        };
+        trace.next = trace.read_commit();
+        // skip the reference's boot code, keeping what it left in registers
+        while let Some(commit) = &trace.next {
+            if commit.pc == info.reset_vec {
+                break;
+            }
+            trace.step().unwrap();
+        }
+        match &trace.next {
+            Some(commit) => trace.pc = commit.pc,
+            None => panic!(
+                "{} never reaches the reset vector {:#x}",
+                path, info.reset_vec
+            ),
+        }
+        for &(regno, val) in &info.reset_regs {
+            if (1..32).contains(&regno) {
+                trace.regs[regno] = val;
+            }
+        }
+        trace
+    }
+
+    fn read_commit(&mut self) -> Option<Commit> {
+        for line in self.lines.by_ref() {
+            self.lineno += 1;
+            let line = line.unwrap_or_else(|e| panic!("couldn't read {}: {}", self.path, e));
+            if !line.starts_with("core") {
+                continue;
+            }
+            match parse_commit(&line) {
+                Ok(commit) => return Some(commit),
+                Err(err) => warn!("{}:{}: {}, skipped", self.path, self.lineno, err),
+            }
+        }
+        None
+    }
+}
+
+impl RefModel for TraceRef {
+    fn step(&mut self) -> Result<(), String> {
+        let commit = self
+            .next
+            .take()
+            .ok_or(format!("{} ended at line {}", self.path, self.lineno))?;
+        for (regno, val) in commit.regs {
+            self.regs[regno] = val;
+        }
+        for (name, val) in commit.csrs {
+            self.csrs.insert(name, val);
+        }
+        for (addr, val, size) in commit.stores {
+            for i in 0..size {
+                self.mem.insert(addr + i, (val >> (i * 8)) as u8);
+            }
+        }
+        self.next = self.read_commit();
+        if let Some(next) = &self.next {
+            self.pc = next.pc;
+        }
+        Ok(())
+    }
+
+    fn read_regs(&mut self) -> Vec<u64> {
+        let mut regs = self.regs.to_vec();
+        regs.push(self.pc);
+        regs
+    }
+
+    fn read_csr(&mut self, name: &str) -> Option<u64> {
+        self.csrs.get(name).copied()
+    }
+
+    fn read_mem(&mut self, addr: u64, len: u64) -> Option<Vec<u8>> {
+        (addr..addr + len)
+            .map(|a| self.mem.get(&a).copied())
+            .collect()
+    }
+
+    fn write_regs(&mut self, regs: &[u64]) {
+        for (regno, val) in regs.iter().enumerate().take(32).skip(1) {
+            self.regs[regno] = *val;
+        }
+        // pc comes from the log, a different one shows up as a mismatch
+    }
+
+    fn write_csr(&mut self, name: &str, val: u64) {
+        self.csrs.insert(name.to_string(), val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_spike_commits() {
+        let commit =
+            parse_commit("core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000")
+                .unwrap();
+        assert_eq!(commit.pc, 0x80000000);
+        assert_eq!(commit.regs, vec![(5, 0x80000000)]);
+
+        let commit = parse_commit(
+            "core   0: 3 0x0000000080000010 (0x30529073) c773_mtvec 0x0000000080000040",
+        )
+        .unwrap();
+        assert_eq!(commit.csrs, vec![("mtvec".to_string(), 0x80000040)]);
+
+        let commit = parse_commit(
+            "core   0: 3 0x0000000080000020 (0x00b52023) mem 0x0000000080001000 0x00000007",
+        )
+        .unwrap();
+        assert_eq!(commit.stores, vec![(0x80001000, 7, 4)]);
+
+        let commit = parse_commit(
+            "core   0: 3 0x0000000080000024 (0x00052583) x11 0x0000000000000007 mem 0x0000000080001000",
+        )
+        .unwrap();
+        assert_eq!(commit.regs, vec![(11, 7)]);
+        assert!(commit.stores.is_empty());
+    }
+}
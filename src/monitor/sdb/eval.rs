@@ -1,156 +1,383 @@
-use crate::isa::riscv64::vaddr::MemOperationSize::DWORD;
+use crate::isa::riscv64::vaddr::MemOperationSize;
 use crate::isa::riscv64::vaddr::VAddr;
 use crate::isa::Isa;
+use crate::memory::paddr::PAddr;
+use crate::utils::symbols::symbols;
 use crate::Emulator;
 use chumsky::prelude::*;
 use chumsky::Parser;
+use std::fmt::{Display, Formatter};
 
 /*
 <expr> ::= <decimal-number>
   | <hexadecimal-number>    # 以"0x"开头
-  | <reg_name>              # 以"$"开头
+  | <reg_name>              # 以"$"开头, 寄存器, pc 或 CSR
+  | <name>                  # ELF 符号, 找不到时为寄存器或 CSR
   | "(" <expr> ")"
-  | <expr> "+" <expr>
-  | <expr> "-" <expr>
-  | <expr> "*" <expr>
-  | <expr> "/" <expr>
-  | <expr> "==" <expr>
-  | <expr> "!=" <expr>
-  | <expr> "&&" <expr>
-  | "*" <expr>              # 指针解引用
+  | <unop> <expr>           # - ~ !
+  | "(" <type> ")" <expr>   # 截断或扩展, type 为 u8..u64, i8..i64
+  | "*" <expr>              # 64 位解引用
+  | "*(" <type> "*)" <expr>        # 按类型解引用
+  | "*(phys " <type> "*)" <expr>   # 物理内存
+  | <expr> <binop> <expr>   # 优先级同 C: * / %, + -, << >>, < <= > >=, == !=, &, ^, |, &&, ||
  */
 
+/// A value and whether it is signed, like C the result of an operation is unsigned as soon as
+/// one operand is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Value {
+    pub bits: u64,
+    pub signed: bool,
+}
+
+impl Value {
+    fn unsigned(bits: u64) -> Self {
+        Self {
+            bits,
+            signed: false,
+        }
+    }
+
+    /// Literals are signed unless they only fit in a u64.
+    fn literal(bits: u64) -> Self {
+        Self {
+            bits,
+            signed: bits <= i64::MAX as u64,
+        }
+    }
+
+    fn truth(b: bool) -> Self {
+        Self {
+            bits: b as u64,
+            signed: true,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.signed {
+            true => write!(f, "{:#x} ({})", self.bits, self.bits as i64),
+            false => write!(f, "{:#x} ({})", self.bits, self.bits),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Type {
+    size: MemOperationSize,
+    signed: bool,
+}
+
+impl Type {
+    const U64: Type = Type {
+        size: MemOperationSize::QWORD,
+        signed: false,
+    };
+
+    fn from_name(name: &str) -> Option<Self> {
+        let size = match &name[1..] {
+            "8" => MemOperationSize::Byte,
+            "16" => MemOperationSize::WORD,
+            "32" => MemOperationSize::DWORD,
+            "64" => MemOperationSize::QWORD,
+            _ => return None,
+        };
+        match &name[..1] {
+            "u" => Some(Self {
+                size,
+                signed: false,
+            }),
+            "i" => Some(Self { size, signed: true }),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.size as u64
+    }
+
+    /// Truncate `bits` to the type and extend it back to 64 bits.
+    fn cast(self, bits: u64) -> Value {
+        let shift = 64 - self.size as u32 * 8;
+        let bits = match self.signed {
+            true => ((bits << shift) as i64 >> shift) as u64,
+            false => bits << shift >> shift,
+        };
+        Value {
+            bits,
+            signed: self.signed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UnOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BinOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
 #[derive(Debug)]
 pub(crate) enum Expr {
-    Num(i64),
+    Num(Value),
     Reg(String),
-    Neg(Box<Expr>),
-    Deref(Box<Expr>),
-    Add(Box<Expr>, Box<Expr>),
-    Sub(Box<Expr>, Box<Expr>),
-    Mul(Box<Expr>, Box<Expr>),
-    Div(Box<Expr>, Box<Expr>),
-    EQ(Box<Expr>, Box<Expr>),
-    NEQ(Box<Expr>, Box<Expr>),
-    AND(Box<Expr>, Box<Expr>),
+    Name(String),
+    Unary(UnOp, Box<Expr>),
+    Cast(Type, Box<Expr>),
+    Deref {
+        ty: Type,
+        physical: bool,
+        addr: Box<Expr>,
+    },
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+type Boxed<'a, T> = BoxedParser<'a, char, T, Simple<char>>;
+
+/// One precedence level: `operand (op operand)*`, left associative.
+fn binary<'a>(operand: Boxed<'a, Expr>, ops: &[(&'static str, BinOp)]) -> Boxed<'a, Expr> {
+    let op = choice(
+        ops.iter()
+            .map(|&(s, op)| just(s).to(op))
+            .collect::<Vec<_>>(),
+    )
+    .padded();
+    operand
+        .clone()
+        .then(op.then(operand).repeated())
+        .foldl(|lhs, (op, rhs)| Expr::Binary(op, Box::new(lhs), Box::new(rhs)))
+        .boxed()
+}
+
+fn ty<'a>() -> Boxed<'a, Type> {
+    text::ident()
+        .try_map(|name: String, span| {
+            Type::from_name(&name).ok_or(Simple::custom(span, format!("unknown type {}", name)))
+        })
+        .padded()
+        .boxed()
 }
 
 fn parser() -> impl Parser<char, Expr, Error = Simple<char>> {
     recursive(|expr| {
+        let hex_digits = filter(|c: &char| c.is_ascii_hexdigit())
+            .repeated()
+            .at_least(1)
+            .collect::<String>();
         let hex = just("0x")
-            .ignore_then(text::int(16))
-            .map(|s: String| Expr::Num(i64::from_str_radix(s.as_str(), 16).unwrap()));
+            .ignore_then(hex_digits)
+            .try_map(|s: String, span| {
+                u64::from_str_radix(&s, 16).map_err(|e| Simple::custom(span, e.to_string()))
+            });
+        let int = filter(|c: &char| c.is_ascii_digit())
+            .repeated()
+            .at_least(1)
+            .collect::<String>()
+            .try_map(|s: String, span| {
+                s.parse::<u64>()
+                    .map_err(|e| Simple::custom(span, e.to_string()))
+            });
+        let num = hex.or(int).map(|n| Expr::Num(Value::literal(n)));
 
-        let int = text::int(10).map(|s: String| Expr::Num(s.parse().unwrap()));
+        // symbols may contain dots, like foo.part.0
+        let ident = filter(|c: &char| c.is_ascii_alphabetic() || *c == '_')
+            .chain(
+                filter(|c: &char| c.is_ascii_alphanumeric() || *c == '_' || *c == '.').repeated(),
+            )
+            .collect::<String>();
+        let reg = just('$').ignore_then(ident).map(Expr::Reg);
+        let name = ident.map(Expr::Name);
 
-        let reg = just('$')
-            .ignore_then(text::ident())
-            .map(|s: String| Expr::Reg(s))
-            .padded();
+        let atom = choice((num, reg, name, expr.delimited_by(just('('), just(')'))))
+            .padded()
+            .boxed();
 
-        let atom = hex
-            .or(int)
-            .or(reg)
-            .or(expr.delimited_by(just('('), just(')')))
-            .padded();
+        let unary = recursive(|unary| {
+            let typed_deref = just('*')
+                .padded()
+                .ignore_then(
+                    just("phys")
+                        .padded()
+                        .or_not()
+                        .then(ty())
+                        .then_ignore(just('*').padded())
+                        .delimited_by(just('('), just(')')),
+                )
+                .then(unary.clone())
+                .map(|((phys, ty), addr)| Expr::Deref {
+                    ty,
+                    physical: phys.is_some(),
+                    addr: Box::new(addr),
+                });
+            let deref = just('*')
+                .padded()
+                .ignore_then(unary.clone())
+                .map(|addr| Expr::Deref {
+                    ty: Type::U64,
+                    physical: false,
+                    addr: Box::new(addr),
+                });
+            let cast = ty()
+                .delimited_by(just('('), just(')'))
+                .padded()
+                .then(unary.clone())
+                .map(|(ty, e)| Expr::Cast(ty, Box::new(e)));
+            let op = choice((
+                just('-').to(UnOp::Neg),
+                just('!').to(UnOp::Not),
+                just('~').to(UnOp::BitNot),
+            ))
+            .padded()
+            .then(unary)
+            .map(|(op, e)| Expr::Unary(op, Box::new(e)));
+            choice((typed_deref, deref, cast, op, atom))
+        })
+        .boxed();
 
-        let op = |c| just(c).padded();
-        let op_str = |s| just(s).padded();
+        use BinOp::*;
+        let product = binary(unary, &[("*", Mul), ("/", Div), ("%", Rem)]);
+        let sum = binary(product, &[("+", Add), ("-", Sub)]);
+        let shift = binary(sum, &[("<<", Shl), (">>", Shr)]);
+        let relation = binary(shift, &[("<=", Le), (">=", Ge), ("<", Lt), (">", Gt)]);
+        let equality = binary(relation, &[("==", Eq), ("!=", Ne)]);
+        // a lone & or | fails on the second one of && or ||, which is then tried below
+        let bit_and = binary(equality, &[("&", BitAnd)]);
+        let bit_xor = binary(bit_and, &[("^", BitXor)]);
+        let bit_or = binary(bit_xor, &[("|", BitOr)]);
+        let and = binary(bit_or, &[("&&", And)]);
+        binary(and, &[("||", Or)])
+    })
+    .then_ignore(end())
+}
 
-        let neg = op('-')
-            .repeated()
-            .then(atom.clone())
-            .foldr(|_op, rhs| Expr::Neg(Box::new(rhs)));
+fn binop(op: BinOp, a: Value, b: Value) -> Result<Value, String> {
+    let signed = a.signed && b.signed;
+    let (x, y) = (a.bits, b.bits);
+    let arith = |bits| Value { bits, signed };
+    let less = match signed {
+        true => (x as i64) < (y as i64),
+        false => x < y,
+    };
+    Ok(match op {
+        BinOp::Add => arith(x.wrapping_add(y)),
+        BinOp::Sub => arith(x.wrapping_sub(y)),
+        BinOp::Mul => arith(x.wrapping_mul(y)),
+        BinOp::Div | BinOp::Rem if y == 0 => return Err("Division by zero".to_string()),
+        BinOp::Div if signed => arith((x as i64).wrapping_div(y as i64) as u64),
+        BinOp::Div => arith(x / y),
+        BinOp::Rem if signed => arith((x as i64).wrapping_rem(y as i64) as u64),
+        BinOp::Rem => arith(x % y),
+        // like the hardware, only the low 6 bits of the amount count
+        BinOp::Shl => Value {
+            bits: x.wrapping_shl(y as u32),
+            ..a
+        },
+        BinOp::Shr if a.signed => Value {
+            bits: (x as i64).wrapping_shr(y as u32) as u64,
+            ..a
+        },
+        BinOp::Shr => Value {
+            bits: x.wrapping_shr(y as u32),
+            ..a
+        },
+        BinOp::Lt => Value::truth(less),
+        BinOp::Le => Value::truth(less || x == y),
+        BinOp::Gt => Value::truth(!less && x != y),
+        BinOp::Ge => Value::truth(!less),
+        BinOp::Eq => Value::truth(x == y),
+        BinOp::Ne => Value::truth(x != y),
+        BinOp::BitAnd => arith(x & y),
+        BinOp::BitXor => arith(x ^ y),
+        BinOp::BitOr => arith(x | y),
+        BinOp::And | BinOp::Or => unreachable!("short-circuited by eval_value"),
+    })
+}
 
-        let deref = op('*')
-            .repeated()
-            .then(atom)
-            .foldr(|_op, rhs| Expr::Deref(Box::new(rhs)));
-
-        let unary = neg.or(deref);
-
-        let product = unary
-            .clone()
-            .then(
-                op('*')
-                    .to(Expr::Mul as fn(_, _) -> _)
-                    .or(op('/').to(Expr::Div as fn(_, _) -> _))
-                    .then(unary)
-                    .repeated(),
-            )
-            .foldl(|lhs, (op, rhs)| op(Box::new(lhs), Box::new(rhs)));
-
-        let sum = product
-            .clone()
-            .then(
-                op('+')
-                    .to(Expr::Add as fn(_, _) -> _)
-                    .or(op('-').to(Expr::Sub as fn(_, _) -> _))
-                    .then(product)
-                    .repeated(),
-            )
-            .foldl(|lhs, (op, rhs)| op(Box::new(lhs), Box::new(rhs)));
-
-        let comp = sum
-            .clone()
-            .then(
-                op_str("==")
-                    .to(Expr::EQ as fn(_, _) -> _)
-                    .or(op_str("!=").to(Expr::NEQ as fn(_, _) -> _))
-                    .then(sum)
-                    .repeated(),
-            )
-            .foldl(|lhs, (op, rhs)| op(Box::new(lhs), Box::new(rhs)));
-
-        let and = comp
-            .clone()
-            .then(
-                op_str("&&")
-                    .to(Expr::AND as fn(_, _) -> _)
-                    .then(comp)
-                    .repeated(),
-            )
-            .foldl(|lhs, (op, rhs)| op(Box::new(lhs), Box::new(rhs)));
-        and
+pub fn eval_value<T: Isa>(expr: &Expr, emulator: &mut Emulator<T>) -> Result<Value, String> {
+    Ok(match expr {
+        Expr::Num(x) => *x,
+        Expr::Reg(name) => Value::unsigned(emulator.cpu.isa_get_reg_by_name(name)?),
+        // a symbol shadows a register or CSR of the same name, `$name` reaches those
+        Expr::Name(name) => match symbols().addr_of(name) {
+            Some(addr) => Value::unsigned(addr),
+            None => Value::unsigned(
+                emulator
+                    .cpu
+                    .isa_get_reg_by_name(name)
+                    .map_err(|_| format!("No symbol, register or CSR \"{}\"", name))?,
+            ),
+        },
+        Expr::Unary(op, a) => {
+            let a = eval_value(a, emulator)?;
+            match op {
+                UnOp::Neg => Value {
+                    bits: a.bits.wrapping_neg(),
+                    ..a
+                },
+                UnOp::BitNot => Value { bits: !a.bits, ..a },
+                UnOp::Not => Value::truth(a.bits == 0),
+            }
+        }
+        Expr::Cast(ty, a) => ty.cast(eval_value(a, emulator)?.bits),
+        Expr::Deref { ty, physical, addr } => {
+            let addr = eval_value(addr, emulator)?.bits;
+            let bits = match physical {
+                true => emulator.cpu.peek_paddr(&PAddr::new(addr), ty.size),
+                false => emulator.cpu.peek_vaddr(&VAddr::new(addr), ty.size),
+            }
+            .map_err(|err| format!("Cannot access memory at {:#x}: {}", addr, err))?;
+            ty.cast(bits)
+        }
+        Expr::Binary(BinOp::And, a, b) => {
+            Value::truth(eval_value(a, emulator)?.bits != 0 && eval_value(b, emulator)?.bits != 0)
+        }
+        Expr::Binary(BinOp::Or, a, b) => {
+            Value::truth(eval_value(a, emulator)?.bits != 0 || eval_value(b, emulator)?.bits != 0)
+        }
+        Expr::Binary(op, a, b) => {
+            let a = eval_value(a, emulator)?;
+            binop(*op, a, eval_value(b, emulator)?)?
+        }
     })
-    .then_ignore(end())
 }
 
 pub fn eval_expr<T: Isa>(expr: &Expr, emulator: &mut Emulator<T>) -> Result<i64, String> {
-    match expr {
-        Expr::Num(x) => Ok(*x),
-        Expr::Reg(x) => emulator
-            .cpu
-            .isa_get_reg_by_name(x.as_str())
-            .map(|v| v as i64),
-        Expr::Deref(a) => {
-            let addr = eval_expr(a, emulator)? as u64;
-            emulator
-                .cpu
-                .read_vaddr(&VAddr::new(addr), DWORD)
-                .map(|x| x as i64)
-        }
-        Expr::Neg(a) => Ok(-eval_expr(a, emulator)?),
-        Expr::Add(a, b) => Ok(eval_expr(a, emulator)? + eval_expr(b, emulator)?),
-        Expr::Sub(a, b) => Ok(eval_expr(a, emulator)? - eval_expr(b, emulator)?),
-        Expr::Mul(a, b) => Ok(eval_expr(a, emulator)? * eval_expr(b, emulator)?),
-        Expr::Div(a, b) => Ok(eval_expr(a, emulator)? / eval_expr(b, emulator)?),
-        Expr::EQ(a, b) => Ok(i64::from(
-            eval_expr(a, emulator)? == eval_expr(b, emulator)?,
-        )),
-        Expr::NEQ(a, b) => Ok(i64::from(
-            eval_expr(a, emulator)? != eval_expr(b, emulator)?,
-        )),
-        Expr::AND(a, b) => Ok(i64::from(
-            eval_expr(a, emulator)? != 0 && eval_expr(b, emulator)? != 0,
-        )),
-    }
+    eval_value(expr, emulator).map(|v| v.bits as i64)
 }
 
+/// Errors point at where parsing failed:
+/// ```text
+/// $a0 + * 3
+///         ^ found '3' but expected ...
+/// ```
 pub fn parse(expr: &str) -> Result<Expr, String> {
-    parser().parse(expr).map_err(|err| {
-        err.iter()
-            .fold(String::new(), |err_str, it| err_str + &it.to_string())
+    parser().parse(expr).map_err(|errs| {
+        let err = errs.iter().max_by_key(|e| e.span().start).unwrap();
+        let col = expr.chars().take(err.span().start).count();
+        format!("{}\n{}^ {}", expr, " ".repeat(col), err)
     })
 }
 
@@ -158,54 +385,32 @@ pub fn eval<T: Isa>(expr: &str, emulator: &mut Emulator<T>) -> Result<i64, Strin
     eval_expr(&parse(expr)?, emulator)
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use crate::monitor::sdb::eval::{eval, eval_expr, parser};
-//     use crate::utils::tests::fake_emulator;
-//     use chumsky::Parser;
-//     //
-//     //     #[test]
-//     //     fn mem_test() {
-//     // let emulator = fake_emulator();
-//     // let test_addr: VAddr = (CONFIG_MBASE + 8).into();
-//     // emulator.
-//     //     .memory
-//     //     .borrow_mut()
-//     //     .write(&test_addr, 114514, QWORD);
-//     // let exp = "*0x80000008".to_string();
-//     // assert_eq!(
-//     //     eval_expr(&parser().parse(exp).unwrap(), &emulator).unwrap(),
-//     //     114514
-//     // );
-//     // }
-//
-//     #[test]
-//     fn calc_test() {
-//         let exp = "100 * 0xa - ((1 + 1) + 2) -- 1".to_string();
-//         let mut emulator = fake_emulator();
-//         assert_eq!(
-//             eval_expr(&parser().parse(exp).unwrap(), &mut emulator).unwrap(),
-//             997
-//         );
-//     }
-//
-//     #[test]
-//     fn comp_test() {
-//         let exp = "1 * 2 == 3 - 1 && 0x10 != 10".to_string();
-//         let mut emulator = fake_emulator();
-//         assert_eq!(
-//             eval_expr(&parser().parse(exp).unwrap(), &mut emulator).unwrap(),
-//             1
-//         );
-//     }
-//
-//     #[test]
-//     fn err_test() {
-//         let exp = "aaa * bbb".to_string();
-//         let mut emulator = fake_emulator();
-//         match eval(&exp, &mut emulator) {
-//             Ok(_) => assert!(false),
-//             Err(err) => println!("ERR: {}", err),
-//         }
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precedence_and_signedness() {
+        let Ok(Expr::Binary(BinOp::Or, lhs, _)) = parse("1 + 2 * 3 & 4 || *(phys u8*)main") else {
+            panic!("bad parse");
+        };
+        assert!(matches!(*lhs, Expr::Binary(BinOp::BitAnd, ..)));
+        assert!(parse("1 && 2 & 3 << 4 >= 5").is_ok());
+
+        let minus_one = Value::literal(u64::MAX).bits;
+        let (neg, big) = (Value::truth(true), Value::literal(minus_one));
+        let neg = binop(BinOp::Sub, Value::literal(0), neg).unwrap();
+        assert!(!big.signed && neg.signed && neg.bits == minus_one);
+        assert_eq!(binop(BinOp::Lt, neg, Value::literal(0)).unwrap().bits, 1);
+        assert_eq!(binop(BinOp::Lt, big, Value::literal(0)).unwrap().bits, 0);
+        assert_eq!(
+            binop(BinOp::Shr, neg, Value::literal(60)).unwrap().bits,
+            minus_one
+        );
+        assert_eq!(Type::from_name("i8").unwrap().cast(0x80).bits as i64, -128);
+        assert!(binop(BinOp::Div, neg, Value::literal(0)).is_err());
+
+        let err = parse("$a0 + * 3)").unwrap_err();
+        assert_eq!(err.lines().nth(1).unwrap().find('^'), Some(9));
+    }
+}
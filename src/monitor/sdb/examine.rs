@@ -0,0 +1,269 @@
+//! gdb style `x/NFU expr`, `xp/NFU expr` and `set` commands.
+
+use crate::isa::riscv64::vaddr::{MemOperationSize, VAddr};
+use crate::isa::Isa;
+use crate::memory::paddr::PAddr;
+use crate::monitor::sdb::eval::eval;
+use crate::utils::symbols::symbols;
+use crate::Emulator;
+use std::fmt::Write;
+
+/// Count, format and unit of `x`. Like in gdb, format and unit stick until changed.
+#[derive(Clone, Copy)]
+pub(crate) struct XFormat {
+    count: usize,
+    fmt: char,
+    unit: MemOperationSize,
+}
+
+impl Default for XFormat {
+    fn default() -> Self {
+        Self {
+            count: 1,
+            fmt: 'x',
+            unit: MemOperationSize::DWORD,
+        }
+    }
+}
+
+fn parse_unit(c: char) -> Option<MemOperationSize> {
+    match c {
+        'b' => Some(MemOperationSize::Byte),
+        'h' => Some(MemOperationSize::WORD),
+        'w' => Some(MemOperationSize::DWORD),
+        'g' => Some(MemOperationSize::QWORD),
+        _ => None,
+    }
+}
+
+/// Split `/NFU rest` into the format and the rest. The count is always reset to 1.
+fn parse_format(args: &str, last: XFormat) -> Result<(XFormat, &str), String> {
+    let mut format = XFormat { count: 1, ..last };
+    let Some(spec) = args.strip_prefix('/') else {
+        return Ok((format, args));
+    };
+    let (spec, rest) = spec.split_once(char::is_whitespace).unwrap_or((spec, ""));
+    let digits = spec
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(spec.len());
+    if digits > 0 {
+        format.count = spec[..digits]
+            .parse()
+            .map_err(|e| format!("bad count {}: {}", &spec[..digits], e))?;
+    }
+    let mut unit = None;
+    for c in spec[digits..].chars() {
+        match c {
+            'x' | 'd' | 'c' | 'i' | 's' => format.fmt = c,
+            _ => unit = Some(parse_unit(c).ok_or(format!("unknown format letter {}", c))?),
+        }
+    }
+    format.unit = match unit {
+        Some(unit) => unit,
+        None if format.fmt == 'c' => MemOperationSize::Byte,
+        None => format.unit,
+    };
+    Ok((format, rest))
+}
+
+fn read<T: Isa>(cpu: &T, physical: bool, addr: u64, len: MemOperationSize) -> Result<u64, String> {
+    if physical {
+        cpu.peek_paddr(&PAddr::new(addr), len)
+    } else {
+        cpu.peek_vaddr(&VAddr::new(addr), len)
+    }
+}
+
+fn label(addr: u64, physical: bool) -> String {
+    match symbols().lookup(addr) {
+        Some(_) if !physical => format!("{:#x} <{}>", addr, symbols().format(addr)),
+        _ => format!("{:#x}", addr),
+    }
+}
+
+fn escape(byte: u8) -> String {
+    match byte {
+        b'\n' => "\\n".to_string(),
+        b'\t' => "\\t".to_string(),
+        b'"' | b'\'' | b'\\' => format!("\\{}", byte as char),
+        b' '..=b'~' => (byte as char).to_string(),
+        _ => format!("\\{:03o}", byte),
+    }
+}
+
+fn format_unit(fmt: char, unit: MemOperationSize, val: u64) -> String {
+    let bits = unit as u32 * 8;
+    match fmt {
+        'd' => ((val << (64 - bits)) as i64 >> (64 - bits)).to_string(),
+        'c' => format!("{} '{}'", val as u8 as i8, escape(val as u8)),
+        _ => format!("{:#0width$x}", val, width = unit as usize * 2 + 2),
+    }
+}
+
+/// Run `x/NFU expr`, or `xp/NFU expr` on physical memory with `physical`. Faults are reported
+/// per address and don't stop the dump.
+pub(crate) fn examine<T: Isa>(
+    emulator: &mut Emulator<T>,
+    args: &str,
+    physical: bool,
+    last: &mut XFormat,
+) -> Result<String, String> {
+    let (format, expr) = parse_format(args.trim_start(), *last)?;
+    if expr.trim().is_empty() {
+        return Err("Argument required (starting display address).".to_string());
+    }
+    let mut addr = eval(expr, emulator)? as u64;
+    *last = format;
+    let cpu = &mut emulator.cpu;
+    let mut out = String::new();
+    match format.fmt {
+        'i' => {
+            for _ in 0..format.count {
+                // the low bits of the first halfword tell compressed instructions apart
+                let inst = read(cpu, physical, addr, MemOperationSize::WORD).and_then(|half| {
+                    match half & 0b11 {
+                        0b11 => read(cpu, physical, addr, MemOperationSize::DWORD),
+                        _ => Ok(half),
+                    }
+                });
+                match inst {
+                    Ok(inst) => {
+                        let text = cpu.isa_disassemble(inst as u32, addr);
+                        writeln!(out, "{}:\t{:08x}\t{}", label(addr, physical), inst, text)
+                            .unwrap();
+                        addr += if inst & 0b11 == 0b11 { 4 } else { 2 };
+                    }
+                    Err(err) => {
+                        writeln!(out, "{}:\t{}", label(addr, physical), err).unwrap();
+                        addr += 2;
+                    }
+                }
+            }
+        }
+        's' => {
+            for _ in 0..format.count {
+                let start = addr;
+                let mut s = String::new();
+                let mut fault = None;
+                while s.len() < 200 {
+                    match read(cpu, physical, addr, MemOperationSize::Byte) {
+                        Ok(0) => break,
+                        Ok(byte) => s += &escape(byte as u8),
+                        Err(err) => {
+                            fault = Some(err);
+                            break;
+                        }
+                    }
+                    addr += 1;
+                }
+                addr += 1;
+                match fault {
+                    Some(err) if s.is_empty() => {
+                        writeln!(out, "{}:\t{}", label(start, physical), err)
+                    }
+                    Some(err) => writeln!(out, "{}:\t\"{}\" ({})", label(start, physical), s, err),
+                    None => writeln!(out, "{}:\t\"{}\"", label(start, physical), s),
+                }
+                .unwrap();
+            }
+        }
+        _ => {
+            let per_line = match format.unit {
+                MemOperationSize::DWORD => 4,
+                MemOperationSize::QWORD => 2,
+                _ => 8,
+            };
+            let mut in_line = 0;
+            for _ in 0..format.count {
+                match read(cpu, physical, addr, format.unit) {
+                    Ok(val) => {
+                        if in_line == 0 {
+                            write!(out, "{}:", label(addr, physical)).unwrap();
+                        }
+                        write!(out, "\t{}", format_unit(format.fmt, format.unit, val)).unwrap();
+                        in_line += 1;
+                        if in_line == per_line {
+                            out.push('\n');
+                            in_line = 0;
+                        }
+                    }
+                    Err(err) => {
+                        if in_line != 0 {
+                            out.push('\n');
+                            in_line = 0;
+                        }
+                        writeln!(out, "{}:\t{}", label(addr, physical), err).unwrap();
+                    }
+                }
+                addr += format.unit as u64;
+            }
+        }
+    }
+    Ok(out.trim_end().to_string())
+}
+
+/// The first `=` that isn't part of `==`, `!=`, `<=` or `>=`.
+fn split_assign(s: &str) -> Option<(&str, &str)> {
+    let bytes = s.as_bytes();
+    let pos = (0..bytes.len()).find(|&i| {
+        bytes[i] == b'='
+            && bytes.get(i + 1) != Some(&b'=')
+            && (i == 0 || !b"=!<>".contains(&bytes[i - 1]))
+    })?;
+    Some((&s[..pos], &s[pos + 1..]))
+}
+
+/// Run `set/U *addr = val` (unit as in `x`, g by default like `*` reads) or `set $reg = val`.
+pub(crate) fn set<T: Isa>(emulator: &mut Emulator<T>, args: &str) -> Result<String, String> {
+    let args = args.trim_start();
+    let (unit, args) = match args.strip_prefix('/') {
+        Some(spec) => {
+            let c = spec.chars().next().unwrap_or(' ');
+            let unit = parse_unit(c).ok_or(format!("unknown unit {}", c))?;
+            (unit, &spec[c.len_utf8()..])
+        }
+        None => (MemOperationSize::QWORD, args),
+    };
+    let (lhs, rhs) = split_assign(args).ok_or("expected set *addr = val or set $reg = val")?;
+    let val = eval(rhs, emulator)? as u64;
+    let lhs = lhs.trim();
+    if let Some(reg) = lhs.strip_prefix('$') {
+        emulator.cpu.isa_set_reg_by_name(reg, val)?;
+        Ok(format!("${} = {:#x}", reg, val))
+    } else if let Some(addr) = lhs.strip_prefix('*') {
+        let addr = eval(addr, emulator)? as u64;
+        emulator.cpu.write_vaddr(&VAddr::new(addr), val, unit)?;
+        Ok(format!("{:#x} = {:#x}", addr, unit.read_val(val)))
+    } else {
+        Err(format!("can't assign to {}", lhs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_and_assignments() {
+        let (format, rest) = parse_format("/16xb $sp", XFormat::default()).unwrap();
+        assert_eq!((format.count, format.fmt, rest), (16, 'x', "$sp"));
+        assert!(format.unit == MemOperationSize::Byte);
+        // format and unit stick, the count doesn't
+        let (format, _) = parse_format("/d 0x80000000", format).unwrap();
+        assert!(format.count == 1 && format.fmt == 'd' && format.unit == MemOperationSize::Byte);
+        assert!(parse_format("/4z 0", format).is_err());
+
+        assert_eq!(format_unit('d', MemOperationSize::WORD, 0xfffe), "-2");
+        assert_eq!(
+            format_unit('x', MemOperationSize::DWORD, 0x13),
+            "0x00000013"
+        );
+        assert_eq!(format_unit('c', MemOperationSize::Byte, 0x41), "65 'A'");
+
+        assert_eq!(
+            split_assign("*($sp + 8) = 1 == 1"),
+            Some(("*($sp + 8) ", " 1 == 1"))
+        );
+        assert_eq!(split_assign("$a0 != 1"), None);
+    }
+}
@@ -1,8 +1,10 @@
+use std::collections::HashMap;
 use std::net::TcpStream;
 use num::range_step;
 use crate::monitor::sdb::gdb_interface::protocol::{gdb_decode_hex, recv_packet, send_packet};
 
 mod protocol;
+pub mod stub;
 
 #[allow(dead_code)]
 pub struct GdbContext {
@@ -11,8 +13,8 @@ pub struct GdbContext {
 
 #[allow(dead_code)]
 impl GdbContext {
-    pub fn new() -> Self {
-        let conn = TcpStream::connect("127.0.0.1:1234").unwrap();
+    pub fn new(port: u16) -> Self {
+        let conn = TcpStream::connect(("127.0.0.1", port)).unwrap();
         Self {
             conn
         }
@@ -56,6 +58,116 @@ impl GdbContext {
         self.rm_breakpoint(addr);
     }
 
+    /// Read one register with a `p` packet, None if the stub rejects the number.
+    pub fn read_reg(&mut self, regno: usize) -> Option<u64> {
+        self.send(format!("p{:x}", regno).as_str());
+        let raw = self.receive();
+        if raw.len() < 16 {
+            return None;
+        }
+        let mut val = 0u64;
+        for j in range_step(0, 16, 2) {
+            let byte = gdb_decode_hex(raw[j], raw[j + 1])?;
+            val |= (byte as u64) << (4 * j)
+        }
+        Some(val)
+    }
+
+    /// Overwrite the registers of a `g` packet with a `G` packet.
+    pub fn write_regs_64(&mut self, regs: &[u64]) {
+        let mut packet = String::from("G");
+        for reg in regs {
+            for byte in reg.to_le_bytes() {
+                packet.push_str(&format!("{:02x}", byte));
+            }
+        }
+        self.talk(packet.as_str());
+    }
+
+    /// Write one register with a `P` packet.
+    pub fn write_reg(&mut self, regno: usize, val: u64) {
+        let mut packet = format!("P{:x}=", regno);
+        for byte in val.to_le_bytes() {
+            packet.push_str(&format!("{:02x}", byte));
+        }
+        self.talk(packet.as_str());
+    }
+
+    /// Read memory with an `m` packet, the stub may return fewer bytes than asked for.
+    pub fn read_mem(&mut self, addr: u64, len: u64) -> Option<Vec<u8>> {
+        self.send(format!("m{:x},{:x}", addr, len).as_str());
+        let raw = self.receive();
+        if raw.first() == Some(&b'E') && raw.len() == 3 {
+            return None;
+        }
+        raw.chunks(2)
+            .map(|c| gdb_decode_hex(c[0], *c.get(1)?))
+            .collect()
+    }
+
+    /// Fetch a whole `qXfer:features:read` annex.
+    fn read_features(&mut self, annex: &str) -> String {
+        let mut res = vec!();
+        loop {
+            let offset = res.len();
+            self.send(format!("qXfer:features:read:{}:{:x},{:x}", annex, offset, 0x800).as_str());
+            let raw = self.receive();
+            let Some((&kind, data)) = raw.split_first() else {
+                break;
+            };
+            // binary reply, '}' escapes the next byte
+            let mut escaped = false;
+            for &b in data {
+                if escaped {
+                    res.push(b ^ 0x20);
+                    escaped = false;
+                } else if b == b'}' {
+                    escaped = true;
+                } else {
+                    res.push(b);
+                }
+            }
+            if kind != b'm' {
+                break;
+            }
+        }
+        String::from_utf8_lossy(&res).into_owned()
+    }
+
+    /// Map register names to the numbers used in `p` packets, from the stub's target description.
+    pub fn reg_numbers(&mut self) -> HashMap<String, usize> {
+        fn attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+            let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+            let len = tag[start..].find('"')?;
+            Some(&tag[start..start + len])
+        }
+
+        let target = self.read_features("target.xml");
+        let mut annexes = vec!();
+        for tag in target.split('<').filter(|t| t.starts_with("xi:include")) {
+            if let Some(href) = attr(tag, "href") {
+                annexes.push(href.to_string());
+            }
+        }
+        let mut docs = vec!(target);
+        for annex in annexes {
+            docs.push(self.read_features(&annex));
+        }
+
+        let mut res = HashMap::new();
+        let mut next = 0;
+        for doc in docs {
+            for tag in doc.split('<').filter(|t| t.starts_with("reg ")) {
+                let regno = attr(tag, "regnum").and_then(|n| n.parse().ok()).unwrap_or(next);
+                if let Some(name) = attr(tag, "name") {
+                    res.insert(name.to_string(), regno);
+                }
+                next = regno + 1;
+            }
+        }
+        res
+    }
+
     pub fn read_regs_64(&mut self) -> Vec<u64> {
         self.send("g");
         let raw = self.receive();
@@ -97,7 +209,7 @@ mod tests {
 
     #[test]
     fn it_works() {
-        let mut ctx = GdbContext::new();
+        let mut ctx = GdbContext::new(1234);
         let pc = 0x80000000;
         ctx.continue_to_addr(pc);
         // pc = ctx.step(pc);
@@ -31,40 +31,61 @@ pub fn gdb_decode_hex(msb: u8, lsb: u8) -> Option<u8> {
 //     val
 // }
 
-fn read_checked(conn: &mut TcpStream, buf: &mut [u8]) {
-    if conn.read(buf).unwrap() == 0 {
-        panic!("qemu connection terminated")
+pub fn gdb_encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// None if the peer closed the connection.
+fn read_checked(conn: &mut TcpStream, buf: &mut [u8]) -> Option<()> {
+    match conn.read(buf) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(()),
     }
 }
 
-fn read_until_ack(conn: &mut TcpStream) -> bool {
+fn read_until_ack(conn: &mut TcpStream) -> Option<bool> {
     let mut buf = [0u8];
     loop {
-        read_checked(conn, &mut buf);
+        read_checked(conn, &mut buf)?;
         match buf[0] as char {
-            '+' => return true,
-            '-' => return false,
+            '+' => return Some(true),
+            '-' => return Some(false),
             _ => {}
         }
     }
 }
 
-pub fn send_packet(conn: &mut TcpStream, command: &str) {
+/// Returns false if the peer closed the connection.
+pub fn try_send_packet(conn: &mut TcpStream, command: &str) -> bool {
     let mut sum: u8 = 0;
     for c in command.chars() {
         sum = sum.wrapping_add(c as u8);
     }
     let msg = format!("${}#{:02X}", command, sum);
     loop {
-        conn.write_all(msg.as_bytes()).unwrap();
-        conn.flush().unwrap();
-        if read_until_ack(conn) {
-            break;
+        if conn.write_all(msg.as_bytes()).is_err() || conn.flush().is_err() {
+            return false;
+        }
+        match read_until_ack(conn) {
+            Some(true) => return true,
+            Some(false) => {}
+            None => return false,
         }
     }
 }
 
+pub fn send_packet(conn: &mut TcpStream, command: &str) {
+    if !try_send_packet(conn, command) {
+        panic!("qemu connection terminated")
+    }
+}
+
 pub fn recv_packet(conn: &mut TcpStream) -> Vec<u8> {
+    try_recv_packet(conn).unwrap_or_else(|| panic!("qemu connection terminated"))
+}
+
+/// None if the peer closed the connection.
+pub fn try_recv_packet(conn: &mut TcpStream) -> Option<Vec<u8>> {
 // pub fn recv_packet(conn: &mut BufReader<TcpStream>, conn_write: &mut TcpStream) -> Vec<u8> {
     // let mut buf = vec!();
     // let mut checksum_buf = [0u8; 2];
@@ -98,14 +119,14 @@ pub fn recv_packet(conn: &mut TcpStream) -> Vec<u8> {
     loop {
         let mut res = vec!();
         loop { // read until '$'
-            read_checked(conn, &mut buf);
+            read_checked(conn, &mut buf)?;
             if buf[0] as char == '$' {
                 break;
             }
         }
         let mut sum = 0u8;
         loop { // read until '#'
-            read_checked(conn, &mut buf);
+            read_checked(conn, &mut buf)?;
             if buf[0] as char == '#' {
                 break;
             }
@@ -113,20 +134,20 @@ pub fn recv_packet(conn: &mut TcpStream) -> Vec<u8> {
             sum = sum.wrapping_add(buf[0]);
         }
         // checksum
-        read_checked(conn, &mut buf);
+        read_checked(conn, &mut buf)?;
         let msb = buf[0];
-        read_checked(conn, &mut buf);
+        read_checked(conn, &mut buf)?;
         let lsb = buf[0];
         let checksum = gdb_decode_hex(msb, lsb);
         let ok = checksum.is_some_and(|cs| cs == sum);
         // send ack/nack
         match ok {
-            true => conn.write_all(b"+").unwrap(),
-            false => conn.write_all(b"-").unwrap(),
+            true => conn.write_all(b"+").ok()?,
+            false => conn.write_all(b"-").ok()?,
         }
-        conn.flush().unwrap();
+        conn.flush().ok()?;
         if ok {
-            return res;
+            return Some(res);
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,364 @@
+//! GDB remote serial protocol server (`--gdb PORT`), the counterpart of [`super::GdbContext`].
+
+use crate::device::inst_count;
+use crate::isa::riscv64::vaddr::MemOperationSize::Byte;
+use crate::isa::riscv64::vaddr::VAddr;
+use crate::isa::riscv64::watch::{AccessWatch, WatchKind};
+use crate::isa::Isa;
+use crate::monitor::sdb::exec_once;
+use crate::monitor::sdb::gdb_interface::protocol::{
+    gdb_decode_hex, gdb_encode_hex, try_recv_packet, try_send_packet,
+};
+use crate::Emulator;
+use log::{error, info};
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read};
+use std::net::{TcpListener, TcpStream};
+
+/// Instructions executed between two polls for a Ctrl-C from gdb.
+const INTERRUPT_POLL_INTERVAL: u64 = 0x1000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+enum StopReason {
+    Signal(u8),
+    /// the kind of the watchpoint and the accessed address
+    Watch(WatchKind, u64),
+    Exited(u8),
+    Disconnected,
+}
+
+struct GdbStub {
+    conn: TcpStream,
+    breakpoints: HashSet<u64>,
+    /// checked by the MMU, numbered by position
+    watchpoints: Vec<AccessWatch>,
+    detached: bool,
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn decode_hex_bytes(hex: &[u8]) -> Option<Vec<u8>> {
+    hex.chunks(2)
+        .map(|c| gdb_decode_hex(c[0], *c.get(1)?))
+        .collect()
+}
+
+/// Registers travel as little-endian byte strings.
+fn decode_reg(hex: &[u8]) -> Option<u64> {
+    let bytes = decode_hex_bytes(hex)?;
+    let bytes: [u8; 8] = bytes.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Stops at the first byte that isn't in pmem, gdb's reads must not touch devices.
+fn read_mem<T: Isa>(cpu: &T, addr: u64, len: u64) -> Vec<u8> {
+    let mut bytes = vec![];
+    for a in addr..addr.wrapping_add(len) {
+        match cpu.peek_vaddr(&VAddr::new(a), Byte) {
+            Ok(b) => bytes.push(b as u8),
+            Err(_) => break,
+        }
+    }
+    bytes
+}
+
+impl GdbStub {
+    fn send(&mut self, reply: &str) -> bool {
+        try_send_packet(&mut self.conn, reply)
+    }
+
+    /// Checks for a Ctrl-C (a raw 0x03 byte) without blocking.
+    fn interrupted(&mut self) -> Option<StopReason> {
+        if self.conn.set_nonblocking(true).is_err() {
+            return Some(StopReason::Disconnected);
+        }
+        let mut buf = [0u8];
+        let res = match self.conn.read(&mut buf) {
+            Ok(0) => Some(StopReason::Disconnected),
+            Ok(_) if buf[0] == 0x03 => Some(StopReason::Signal(SIGINT)),
+            Ok(_) => None,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            Err(_) => Some(StopReason::Disconnected),
+        };
+        let _ = self.conn.set_nonblocking(false);
+        res
+    }
+
+    fn arm_watchpoints<T: Isa>(&mut self, cpu: &mut T) {
+        for (id, wp) in self.watchpoints.iter_mut().enumerate() {
+            wp.id = id as u32;
+        }
+        cpu.isa_set_watches(self.watchpoints.clone());
+    }
+
+    /// Run until a breakpoint, watchpoint, Ctrl-C or halt. Steps once if `step`.
+    fn resume<T: Isa>(&mut self, emulator: &mut Emulator<T>, step: bool) -> StopReason {
+        loop {
+            let (not_halt, _, sdl_quit) = exec_once(emulator);
+            if !not_halt {
+                return StopReason::Exited(emulator.cpu.isa_get_exit_code());
+            }
+            if sdl_quit {
+                return StopReason::Exited(0);
+            }
+            if let Some(hit) = emulator.cpu.isa_take_watch_hit() {
+                let kind = self.watchpoints[hit.id as usize].kind;
+                return StopReason::Watch(kind, hit.vaddr);
+            }
+            if step || self.breakpoints.contains(&emulator.cpu.isa_get_pc()) {
+                return StopReason::Signal(SIGTRAP);
+            }
+            if inst_count().is_multiple_of(INTERRUPT_POLL_INTERVAL) {
+                if let Some(reason) = self.interrupted() {
+                    return reason;
+                }
+            }
+        }
+    }
+
+    fn stop_reply(reason: &StopReason) -> String {
+        match reason {
+            StopReason::Signal(sig) => format!("S{:02x}", sig),
+            StopReason::Watch(kind, addr) => {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+            }
+            StopReason::Exited(code) => format!("W{:02x}", code),
+            StopReason::Disconnected => String::new(),
+        }
+    }
+
+    /// Z/z packets, `type,addr,kind`.
+    fn update_point<T: Isa>(&mut self, cpu: &mut T, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(typ), Some(addr), Some(kind)) = (
+            fields.next(),
+            fields.next().and_then(parse_hex),
+            fields.next().and_then(|k| parse_hex(k.split(';').next()?)),
+        ) else {
+            return "E01".to_string();
+        };
+        match (typ, insert) {
+            ("0" | "1", true) => {
+                self.breakpoints.insert(addr);
+            }
+            ("0" | "1", false) => {
+                self.breakpoints.remove(&addr);
+            }
+            ("2" | "3" | "4", _) => {
+                let watch = AccessWatch {
+                    id: 0,
+                    kind: match typ {
+                        "2" => WatchKind::Write,
+                        "3" => WatchKind::Read,
+                        _ => WatchKind::Access,
+                    },
+                    addr,
+                    len: kind,
+                    physical: false,
+                };
+                self.watchpoints.retain(|wp| {
+                    (wp.kind, wp.addr, wp.len) != (watch.kind, watch.addr, watch.len)
+                });
+                if insert {
+                    self.watchpoints.push(watch);
+                }
+                self.arm_watchpoints(cpu);
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    fn handle_query<T: Isa>(&self, cpu: &T, query: &str) -> String {
+        if query.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+".to_string()
+        } else if let Some(args) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, len)) = args.split_once(',') else {
+                return "E01".to_string();
+            };
+            let (Some(offset), Some(len)) = (parse_hex(offset), parse_hex(len)) else {
+                return "E01".to_string();
+            };
+            let xml = cpu.isa_gdb_target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = (start + len as usize).min(xml.len());
+            let prefix = if end == xml.len() { 'l' } else { 'm' };
+            format!("{}{}", prefix, &xml[start..end])
+        } else if query == "Attached" {
+            "1".to_string()
+        } else if query == "C" {
+            "QC1".to_string()
+        } else if query == "fThreadInfo" {
+            "m1".to_string()
+        } else if query == "sThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    /// Handles one packet. Returns the reply, or None when the session ends.
+    fn handle_packet<T: Isa>(
+        &mut self,
+        emulator: &mut Emulator<T>,
+        packet: &str,
+    ) -> Option<String> {
+        let cpu = &mut emulator.cpu;
+        let reply = match packet.as_bytes().first().copied().unwrap_or(b' ') as char {
+            '?' => Self::stop_reply(&StopReason::Signal(SIGTRAP)),
+            'q' => self.handle_query(cpu, &packet[1..]),
+            'H' => "OK".to_string(),
+            'T' => "OK".to_string(),
+            'g' => {
+                let mut regs = String::new();
+                for regno in 0..cpu.isa_gdb_reg_count() {
+                    let val = cpu.isa_gdb_read_reg(regno).unwrap_or(0);
+                    regs.push_str(&gdb_encode_hex(&val.to_le_bytes()));
+                }
+                regs
+            }
+            'G' => {
+                let hex = &packet.as_bytes()[1..];
+                for (regno, chunk) in hex.chunks(16).enumerate() {
+                    if let Some(val) = decode_reg(chunk) {
+                        let _ = cpu.isa_gdb_write_reg(regno, val);
+                    }
+                }
+                "OK".to_string()
+            }
+            'p' => match parse_hex(&packet[1..]).and_then(|n| cpu.isa_gdb_read_reg(n as usize)) {
+                Some(val) => gdb_encode_hex(&val.to_le_bytes()),
+                None => "E01".to_string(),
+            },
+            'P' => {
+                let parsed = packet[1..].split_once('=').and_then(|(regno, val)| {
+                    Some((parse_hex(regno)? as usize, decode_reg(val.as_bytes())?))
+                });
+                match parsed.map(|(regno, val)| cpu.isa_gdb_write_reg(regno, val)) {
+                    Some(Ok(())) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            'm' => {
+                let parsed = packet[1..]
+                    .split_once(',')
+                    .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)));
+                match parsed {
+                    Some((addr, len)) => {
+                        let bytes = read_mem(cpu, addr, len);
+                        if bytes.is_empty() && len > 0 {
+                            "E14".to_string()
+                        } else {
+                            gdb_encode_hex(&bytes)
+                        }
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            'M' => {
+                let parsed = packet[1..].split_once(':').and_then(|(range, data)| {
+                    let (addr, _) = range.split_once(',')?;
+                    Some((parse_hex(addr)?, decode_hex_bytes(data.as_bytes())?))
+                });
+                match parsed {
+                    Some((addr, data)) => {
+                        // refuse device addresses like the reads do
+                        let failed = data.iter().enumerate().any(|(i, b)| {
+                            let vaddr = VAddr::new(addr.wrapping_add(i as u64));
+                            cpu.peek_vaddr(&vaddr, Byte).is_err()
+                                || cpu.write_vaddr(&vaddr, *b as u64, Byte).is_err()
+                        });
+                        if failed { "E14" } else { "OK" }.to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            'Z' => self.update_point(cpu, true, &packet[1..]),
+            'z' => self.update_point(cpu, false, &packet[1..]),
+            'c' => return Some(self.resume_and_reply(emulator, false)),
+            's' => return Some(self.resume_and_reply(emulator, true)),
+            'v' => {
+                if packet == "vCont?" {
+                    "vCont;c;C;s;S".to_string()
+                } else if let Some(actions) = packet.strip_prefix("vCont;") {
+                    // a single hart, so only the first action matters
+                    let step = actions.starts_with('s') || actions.starts_with('S');
+                    return Some(self.resume_and_reply(emulator, step));
+                } else if packet.starts_with("vKill") {
+                    return None;
+                } else {
+                    String::new()
+                }
+            }
+            'k' => return None,
+            'D' => {
+                self.send("OK");
+                self.detached = true;
+                return None;
+            }
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn resume_and_reply<T: Isa>(&mut self, emulator: &mut Emulator<T>, step: bool) -> String {
+        let reason = self.resume(emulator, step);
+        if let StopReason::Exited(code) = reason {
+            info!("Program exited with code {} under gdb", code);
+        }
+        Self::stop_reply(&reason)
+    }
+}
+
+/// Wait for gdb on `port` and serve it until it detaches or the program exits.
+pub fn gdb_stub_loop<T: Isa>(emulator: &mut Emulator<T>, port: u16) -> (u64, u8) {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .unwrap_or_else(|e| panic!("couldn't listen on port {}: {}", port, e));
+    info!("Waiting for gdb on port {}", port);
+    let (conn, peer) = listener.accept().expect("failed to accept gdb connection");
+    info!("gdb connected from {}", peer);
+    let mut stub = GdbStub {
+        conn,
+        breakpoints: HashSet::new(),
+        watchpoints: vec![],
+        detached: false,
+    };
+
+    loop {
+        let Some(packet) = try_recv_packet(&mut stub.conn) else {
+            error!("gdb disconnected");
+            break;
+        };
+        let packet = String::from_utf8_lossy(&packet).into_owned();
+        let Some(reply) = stub.handle_packet(emulator, &packet) else {
+            break;
+        };
+        if !stub.send(&reply) {
+            error!("gdb disconnected");
+            break;
+        }
+        if reply.starts_with('W') {
+            return (inst_count(), emulator.cpu.isa_get_exit_code());
+        }
+    }
+    if !stub.detached {
+        return (inst_count(), 0);
+    }
+    info!("gdb detached, continuing");
+    stub.breakpoints.clear();
+    stub.watchpoints.clear();
+    stub.arm_watchpoints(&mut emulator.cpu);
+    match stub.resume(emulator, false) {
+        StopReason::Exited(code) => (inst_count(), code),
+        _ => (inst_count(), 0),
+    }
+}
@@ -1,29 +1,52 @@
-pub mod difftest_qemu;
+mod breakpoint;
+pub mod difftest;
 pub mod eval;
+mod examine;
 mod gdb_interface;
+mod reverse;
+mod script;
+mod step;
 
+pub use gdb_interface::stub::gdb_stub_loop;
+
+use crate::device::inst_count;
 use crate::isa::riscv64::vaddr::VAddr;
+use crate::isa::riscv64::watch::WatchKind;
 use crate::isa::Isa;
-use crate::monitor::sdb::eval::{eval, eval_expr, parse, Expr};
+use crate::monitor::sdb::breakpoint::{parse_location, StopPoints};
+use crate::monitor::sdb::eval::{eval_expr, eval_value, parse};
+use crate::monitor::sdb::examine::{examine, set, XFormat};
+use crate::monitor::sdb::reverse::Checkpoints;
+use crate::monitor::sdb::script::{substitute_args, Input};
+use crate::monitor::sdb::step::{end_run, parse_count, RunEnd};
 use crate::utils::cfg_if_feat;
 use crate::Emulator;
 use cfg_if::cfg_if;
 use log::{error, info};
-use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
 use std::collections::HashMap;
 
-fn unknown_sdb_command(cmd: &str) {
-    error!(
+fn unknown_sdb_command(cmd: &str) -> String {
+    format!(
         "Unknown command: {}",
         cmd.split_once(' ').unwrap_or((cmd, "")).0
-    );
+    )
+}
+
+/// The arguments of `line` if its command word, up to a space or `/`, is one of `names`.
+fn strip_cmd<'a>(line: &'a str, names: &[&str]) -> Option<&'a str> {
+    let end = line.find([' ', '/']).unwrap_or(line.len());
+    names.contains(&&line[..end]).then_some(&line[end..])
 }
 
-pub struct WatchPoint {
-    expr: Expr,
-    expr_str: String,
-    prev_val: i64,
+/// The kind and arguments of `watch`, `rwatch` or `awatch`.
+fn watch_cmd(line: &str) -> Option<(WatchKind, &str)> {
+    let cmds = [
+        ("watch", WatchKind::Write),
+        ("rwatch", WatchKind::Read),
+        ("awatch", WatchKind::Access),
+    ];
+    cmds.into_iter()
+        .find_map(|(name, kind)| Some((kind, strip_cmd(line, &[name])?)))
 }
 
 #[inline]
@@ -34,51 +57,48 @@ pub fn exec_once<T: Isa>(emulator: &mut Emulator<T>) -> (bool, bool, bool) {
 }
 
 struct DbgContext {
-    watchpoints: HashMap<u32, WatchPoint>,
-    breakpoints: HashMap<u32, u64>,
+    stops: StopPoints,
     inst_count: u64,
-    next_watchpoint_idx: u32,
-    next_breakpoint_idx: u32,
-    fn_trace_enable: bool,
     prev_pc: u64,
+    checkpoints: Option<Checkpoints>,
+    x_format: XFormat,
+    /// `define`d commands and their lines
+    user_commands: HashMap<String, Vec<String>>,
 }
 
 impl DbgContext {
     fn new() -> Self {
         Self {
-            watchpoints: HashMap::new(),
-            breakpoints: HashMap::new(),
+            stops: StopPoints::default(),
             inst_count: 0,
-            next_watchpoint_idx: 0,
-            next_breakpoint_idx: 0,
-            fn_trace_enable: false,
             prev_pc: 0,
+            checkpoints: None,
+            x_format: XFormat::default(),
+            user_commands: HashMap::new(),
         }
     }
     fn exec_once_dbg<T: Isa>(&mut self, emulator: &mut Emulator<T>) -> (bool, bool, bool) {
         cfg_if_feat!("difftest", {
             if emulator.difftest_ctx.is_some() {
-                emulator.difftest_ctx.as_mut().unwrap().gdb_ctx.step();
+                // forget accesses made by the debugger itself
+                emulator.cpu.isa_difftest_take_skip();
             }
         });
 
         let pc = emulator.cpu.isa_get_pc();
 
+        self.stops.arm(&mut emulator.cpu);
         let (not_halt, _, sdl_quit) = exec_once(emulator);
 
         self.prev_pc = pc;
-
-        let mut pause = false;
+        if let Some(checkpoints) = &mut self.checkpoints {
+            checkpoints.maybe_take(&emulator.cpu);
+        }
 
         cfg_if_feat!("difftest", {
-            if emulator.difftest_ctx.is_some() {
-                let difftest_regs = emulator
-                    .difftest_ctx
-                    .as_mut()
-                    .unwrap()
-                    .gdb_ctx
-                    .read_regs_64();
-                let difftest_res = emulator.cpu.isa_difftest_check_regs(&difftest_regs);
+            // the halting instruction isn't compared, the reference would step past the end
+            if let Some(difftest_ctx) = emulator.difftest_ctx.as_mut().filter(|_| not_halt) {
+                let difftest_res = difftest_ctx.step(&mut emulator.cpu, true);
                 if difftest_res.is_err() {
                     info!("{}", difftest_res.err().unwrap());
                     return (false, false, false);
@@ -91,71 +111,84 @@ impl DbgContext {
             }
         });
 
-        for (idx, watchpoint) in self.watchpoints.iter_mut() {
-            let eval_res = eval_expr(&watchpoint.expr, emulator);
-            if eval_res != Ok(watchpoint.prev_val) {
-                match eval_res {
-                    Ok(res) => {
-                        info!("Watchpoint {}: {}", idx, watchpoint.expr_str);
-                        info!("Old value = {}", watchpoint.prev_val);
-                        info!("New value = {}", res);
-                        watchpoint.prev_val = res;
-                    }
-                    Err(err) => info!("{}", err),
-                }
-                pause = true;
-            }
-        }
-        for (idx, breakpoint) in self.breakpoints.iter() {
-            if emulator.cpu.isa_get_pc() == *breakpoint {
-                info!("Breakpoint {}: {:#x}", idx, breakpoint);
-                pause = true;
-                break;
-            }
-        }
-
-        if pause {
-            cfg_if_feat!("difftest", {
-                if emulator.difftest_ctx.is_some() {
-                    emulator
-                        .difftest_ctx
-                        .as_mut()
-                        .unwrap()
-                        .gdb_ctx
-                        .continue_to_addr(emulator.cpu.isa_get_pc());
-                    let difftest_regs = emulator
-                        .difftest_ctx
-                        .as_mut()
-                        .unwrap()
-                        .gdb_ctx
-                        .read_regs_64();
-                    let difftest_res = emulator.cpu.isa_difftest_check_regs(&difftest_regs);
-                    if difftest_res.is_err() {
-                        info!("{}", difftest_res.err().unwrap());
-                        return (false, false, false);
-                    }
-                    info!(
-                        "identical at pc {:#x}, {} inst in total",
-                        emulator.cpu.isa_get_pc(),
-                        self.inst_count
-                    );
-                }
-            });
-        }
+        let pause = self.check_stop(emulator, true);
 
         (not_halt, pause, sdl_quit)
     }
 
-    fn insert_watchpoint(&mut self, wp: WatchPoint, raw_expr: &str) {
-        self.watchpoints.insert(self.next_watchpoint_idx, wp);
-        info!("watchpoint {}: {}", self.next_watchpoint_idx, raw_expr);
-        self.next_watchpoint_idx += 1;
+    /// Whether a watchpoint changed or a breakpoint is hit, `report` prints which one.
+    fn check_stop<T: Isa>(&mut self, emulator: &mut Emulator<T>, report: bool) -> bool {
+        self.stops.check(emulator, report)
     }
 
-    fn insert_breakpoint(&mut self, addr: u64) {
-        self.breakpoints.insert(self.next_breakpoint_idx, addr);
-        info!("breakpoint {}: {:#x}", self.next_breakpoint_idx, addr);
-        self.next_breakpoint_idx += 1;
+    /// Execute one instruction without checking breakpoints, used for re-execution.
+    fn step_silent<T: Isa>(&mut self, emulator: &mut Emulator<T>) -> Result<(), String> {
+        self.stops.arm(&mut emulator.cpu);
+        let (not_halt, _, sdl_quit) = exec_once(emulator);
+        if !not_halt || sdl_quit {
+            return Err(format!("Program stopped at inst {}", inst_count()));
+        }
+        if let Some(checkpoints) = &mut self.checkpoints {
+            checkpoints.maybe_take(&emulator.cpu);
+        }
+        Ok(())
+    }
+
+    /// Move to the point where `target` instructions have been executed.
+    fn goto<T: Isa>(&mut self, emulator: &mut Emulator<T>, target: u64) -> Result<(), String> {
+        if target < inst_count() {
+            let checkpoints = self
+                .checkpoints
+                .as_mut()
+                .ok_or("Reverse execution needs --checkpoint-interval")?;
+            checkpoints.restore_before(&mut emulator.cpu, target)?;
+        }
+        let mut res = Ok(());
+        while res.is_ok() && inst_count() < target {
+            res = self.step_silent(emulator);
+        }
+        self.stops.refresh(emulator);
+        res
+    }
+
+    /// Go back to the last instruction before now that hit a breakpoint or watchpoint.
+    fn reverse_continue<T: Isa>(&mut self, emulator: &mut Emulator<T>) -> Result<(), String> {
+        let target = inst_count();
+        let mut end = target;
+        loop {
+            let earliest = match &self.checkpoints {
+                Some(checkpoints) => checkpoints.earliest(),
+                None => return Err("Reverse execution needs --checkpoint-interval".to_string()),
+            };
+            if end <= earliest {
+                self.goto(emulator, earliest)?;
+                info!("No breakpoint or watchpoint hit since inst {}", earliest);
+                return Ok(());
+            }
+            // scan the segment between the previous checkpoint and `end` for the last hit
+            let start = self
+                .checkpoints
+                .as_mut()
+                .unwrap()
+                .restore_before(&mut emulator.cpu, end - 1)?;
+            self.stops.refresh(emulator);
+            let mut last_hit = None;
+            while inst_count() < end {
+                self.step_silent(emulator)?;
+                // the hit at `target` is where we are stopped now
+                if self.check_stop(emulator, false) && inst_count() < target {
+                    last_hit = Some(inst_count());
+                }
+            }
+            if let Some(hit) = last_hit {
+                // stop right before the hit and execute it again to report it
+                self.goto(emulator, hit - 1)?;
+                self.step_silent(emulator)?;
+                self.check_stop(emulator, true);
+                return Ok(());
+            }
+            end = start;
+        }
     }
 }
 
@@ -168,143 +201,290 @@ impl Drop for DbgContext {
     }
 }
 
-pub fn sdb_loop<T: Isa>(emulator: &mut Emulator<T>) -> (u64, u8) {
-    let mut ctx = DbgContext::new();
-    let mut rl = DefaultEditor::new().unwrap();
-    let mut last_input: Option<String> = None;
-    loop {
-        let readline = rl.readline(format!("({:#x})>> ", emulator.cpu.isa_get_pc()).as_str());
-        match readline {
-            Ok(line) => {
-                rl.add_history_entry(line.as_str()).expect("Rustyline err");
-                let line = if line.len() == 0 {
-                    if let Some(last) = &last_input {
-                        last
-                    } else {
-                        info!("empty line");
-                        continue;
-                    }
-                } else {
-                    last_input = Some(line);
-                    last_input.as_ref().unwrap()
-                };
-                let line = line.trim_start();
-                match line.as_bytes()[0] as char {
-                    'h' => {
-                        if line.starts_with("help") {
-                            info!("help: Display information about all supported commands");
-                            info!("c: Continue the execution of the program");
-                            info!("s: step once");
-                            info!("i: display reg");
-                            info!("p expr: eval(expr)");
-                            info!("w expr: set watchpoint expr");
-                            info!("b addr: set breakpoint addr");
-                            info!("d N: del watchpoint N");
-                            info!("disasm: disassemble current instruction");
-                            info!("q: Exit");
-                        } else {
-                            unknown_sdb_command(line);
-                        }
-                    }
-                    'c' => loop {
-                        ctx.inst_count += 1;
-                        let (not_halt, wp_matched, sdl_quit) = ctx.exec_once_dbg(emulator);
-                        if !not_halt {
-                            return (ctx.inst_count, emulator.cpu.isa_get_exit_code());
-                        }
-                        if sdl_quit {
-                            return (ctx.inst_count, 0);
-                        }
-                        if wp_matched {
-                            break;
-                        }
-                    },
-                    'q' => return (ctx.inst_count, 0),
-                    's' => {
-                        // si
-                        ctx.inst_count += 1;
-                        let (not_halt, _, sdl_quit) = ctx.exec_once_dbg(emulator);
-                        if !not_halt {
-                            return (ctx.inst_count, emulator.cpu.isa_get_exit_code());
-                        }
-                        if sdl_quit {
-                            return (ctx.inst_count, 0);
-                        }
-                    }
-                    'i' => emulator.cpu.isa_reg_display(), // info r(reg) / info w(watchpoint)
-                    'x' => {}                              // x N expr: mem[eval(expr)..N*4]
-                    'p' => match eval(&line[1..], emulator) {
-                        Ok(val) => info!("result: {:#x}", val),
-                        Err(err) => info!("{}", err),
-                    }, // p expr: eval(expr)
-                    'w' => {
-                        let raw_expr = line[1..].trim();
-                        let watchpoint = parse(raw_expr).and_then(|expr| {
-                            let prev_val = eval_expr(&expr, emulator)?;
-                            Ok(WatchPoint {
-                                expr,
-                                expr_str: raw_expr.to_string(),
-                                prev_val,
-                            })
-                        });
-                        match watchpoint {
-                            Ok(watchpoint) => ctx.insert_watchpoint(watchpoint, raw_expr),
-                            Err(err) => info!("{}", err),
-                        }
-                    } // w expr: pause when mem[eval(expr)] changes
-                    'b' => {
-                        if line.starts_with("bt") {
-                            info!("backtrace:");
-                            info!("{}", emulator.cpu.isa_get_backtrace());
-                        } else {
-                            match u64::from_str_radix(line[1..].trim(), 16) {
-                                Ok(addr) => ctx.insert_breakpoint(addr),
-                                Err(err) => info!("{}", err),
-                            }
-                        }
-                    }
-                    'd' => {
-                        if line.starts_with("disasm") {
-                            info!(
-                                "{}",
-                                emulator
-                                    .cpu
-                                    .isa_disassemble_inst(&VAddr::new(emulator.cpu.isa_get_pc()))
-                            );
-                        } else {
-                            match line[1..].parse::<u32>() {
-                                Ok(num) => match ctx.watchpoints.remove(&num) {
-                                    Some(watchpoint) => info!(
-                                        "Watchpoint number {} deleted, expr: {}",
-                                        num, watchpoint.expr_str
-                                    ),
-                                    None => info!("No watchpoint number {}", num),
-                                },
-                                Err(err) => info!("{}", err),
-                            }
-                        }
-                    } // d N: delete watchpoint N
-                    't' => {
-                        ctx.fn_trace_enable = !ctx.fn_trace_enable;
-                        info!("Fn trace enable: {}", ctx.fn_trace_enable);
-                    } // fn trace toggle
-
-                    _ => unknown_sdb_command(line),
-                }
+/// Run one sdb command. Some when sdb should return, with the instruction count and the exit
+/// code of the program.
+fn run_command<T: Isa>(
+    ctx: &mut DbgContext,
+    emulator: &mut Emulator<T>,
+    input: &mut Input,
+    line: &str,
+) -> Result<Option<(u64, u8)>, String> {
+    let (word, args) = line.split_once(' ').unwrap_or((line, ""));
+    if let Some(body) = ctx.user_commands.get(word) {
+        input.expand(substitute_args(body, args))?;
+        return Ok(None);
+    }
+    match line.as_bytes()[0] as char {
+        'h' if line.starts_with("help") => {
+            info!("help: Display information about all supported commands");
+            info!("c: Continue the execution of the program");
+            info!("s [N], si [N]: step N instructions");
+            info!("ni [N]: step N instructions, running calls until they return");
+            info!("finish: run until the current function returns");
+            info!("until addr: run until the pc is addr");
+            info!("run-insts N: run N instructions");
+            info!("stop-at-inst N: run until N instructions have been executed");
+            info!("i: display reg");
+            info!("p expr: eval(expr)");
+            info!("  C operators, symbols, $reg, *(u8*)addr, *(phys u32*)addr");
+            info!("x/NFU expr: examine memory, F is x/d/c/i/s and U is b/h/w/g");
+            info!("xp/NFU expr: examine physical memory");
+            info!("set[/U] *addr = val, set $reg = val: write memory or a reg");
+            info!("w expr: set watchpoint expr");
+            info!("watch[/LEN] *LOC: stop when an instruction stores to LOC");
+            info!("rwatch[/LEN] *LOC, awatch[/LEN] *LOC: stop at loads, or both");
+            info!("b[/p][/M|S|U] addr [if cond]: set breakpoint, p for paddr");
+            info!("tbreak addr [if cond]: set a breakpoint deleted when hit");
+            info!("info b, info w: list breakpoints or watchpoints");
+            info!("info mem, info tlb: list the page table mappings or the TLB entries");
+            info!("vtop vaddr: walk the page table for vaddr");
+            info!("info csr [NAME]: decode the csrs, info priv: privilege mode and traps");
+            info!("d [N...]: delete breakpoints or watchpoints, all without N");
+            info!("disable [N...], enable [N...]: switch breakpoints off or on");
+            info!("ignore N count: don't stop at the next count hits of N");
+            info!("cond N [expr]: only stop at N if expr is true");
+            info!("commands [N] ... end: commands to run when N stops, the last one without N");
+            info!("define NAME ... end: a command running the lines, with $arg0.. and $argc");
+            info!("source FILE: run the commands in FILE");
+            info!("disasm: disassemble current instruction");
+            info!("t: toggle the function call trace");
+            info!("rs: reverse step once");
+            info!("rc: reverse continue to the previous breakpoint or watchpoint");
+            info!("goto N: go to the point where N instructions have been executed");
+            info!("mtrace [on FILE|ring:N, off, filter K=V, clear, dump]: trace memory accesses");
+            info!("savevm FILE: save machine state to FILE");
+            info!("loadvm FILE: restore machine state from FILE");
+            info!("q: Exit");
+        }
+        'c' if line.starts_with("cond") => ctx.stops.cond(&line["cond".len()..])?,
+        'c' if word == "commands" => {
+            let lines = input.read_block()?;
+            ctx.stops.set_commands(args, lines)?;
+        }
+        'c' => {
+            if let RunEnd::Exited(count, code) = ctx.run_until(emulator, |_| false) {
+                return Ok(Some((count, code)));
             }
-            Err(ReadlineError::Interrupted) => {
-                info!("CTRL-C");
-                break;
+        }
+        'd' if word == "define" => {
+            let name = args.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err("Argument required (name of command to define).".to_string());
             }
-            Err(ReadlineError::Eof) => {
-                info!("CTRL-D");
-                break;
+            let body = input.read_block()?;
+            ctx.user_commands.insert(name.to_string(), body);
+        }
+        'q' => return Ok(Some((ctx.inst_count, 0))),
+        _ if watch_cmd(line).is_some() => {
+            let (kind, args) = watch_cmd(line).unwrap();
+            ctx.stops.watch_command(emulator, args, kind)?;
+        }
+        'r' | 's' if strip_cmd(line, &["run-insts", "stop-at-inst"]).is_some() => {
+            let n = args
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| format!("bad instruction count {}", args.trim()))?;
+            let end = match word {
+                "run-insts" => ctx.run_to_inst(emulator, inst_count() + n)?,
+                _ => ctx.run_to_inst(emulator, n)?,
+            };
+            if let Some(exit) = end_run(emulator, end) {
+                return Ok(Some(exit));
             }
+            info!("At inst {}", inst_count());
+        }
+        'r' if line == "rs" => {
+            let now = inst_count();
+            if now == 0 {
+                return Err("Already at the first instruction".to_string());
+            }
+            ctx.goto(emulator, now - 1)?;
+        }
+        'r' if line == "rc" => ctx.reverse_continue(emulator)?,
+        'g' if line.starts_with("goto") => {
+            let target = line["goto".len()..]
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| e.to_string())?;
+            ctx.goto(emulator, target)?;
+            info!("At inst {}", inst_count());
+        }
+        'm' if line.starts_with("mtrace") => {
+            info!("{}", emulator.cpu.isa_mtrace(&line["mtrace".len()..])?);
+        }
+        's' if line.starts_with("savevm") => {
+            let path = line["savevm".len()..].trim();
+            emulator.save_snapshot(path)?;
+            info!("Snapshot saved to {}", path);
+        }
+        's' if word == "source" => input.source(args.trim())?,
+        'l' if line.starts_with("loadvm") => {
+            let path = line["loadvm".len()..].trim();
+            emulator.load_snapshot(path)?;
+            info!("Snapshot {} restored", path);
+        }
+        's' if line.starts_with("set") => info!("{}", set(emulator, &line["set".len()..])?),
+        's' | 'n' => {
+            let (args, over) = match strip_cmd(line, &["s", "si", "stepi"]) {
+                Some(args) => (Some(args), false),
+                None => (strip_cmd(line, &["ni", "nexti"]), true),
+            };
+            let count = parse_count(args.ok_or_else(|| unknown_sdb_command(line))?)?;
+            let end = ctx.step(emulator, count, over);
+            return Ok(end_run(emulator, end));
+        }
+        'f' if strip_cmd(line, &["fin", "finish"]).is_some() => {
+            let end = ctx.finish(emulator)?;
+            return Ok(end_run(emulator, end));
+        }
+        'u' => {
+            let args = strip_cmd(line, &["u", "until"]).ok_or_else(|| unknown_sdb_command(line))?;
+            let end = ctx.until(emulator, args)?;
+            return Ok(end_run(emulator, end));
+        }
+        'i' if line.starts_with("ignore") => ctx.stops.ignore(&line["ignore".len()..])?,
+        'i' if line.starts_with("info") => match line["info".len()..].trim() {
+            "b" | "break" | "breakpoints" => info!("{}", ctx.stops.info(false)),
+            "w" | "watch" | "watchpoints" => info!("{}", ctx.stops.info(true)),
+            "" | "r" | "reg" | "registers" => emulator.cpu.isa_reg_display(),
+            "mem" => info!("{}", emulator.cpu.isa_info_mem()),
+            "tlb" => info!("{}", emulator.cpu.isa_info_tlb()),
+            "csr" => info!("{}", emulator.cpu.isa_info_csr(None)?),
+            "priv" => info!("{}", emulator.cpu.isa_info_priv()),
+            cmd if cmd.starts_with("csr ") => {
+                info!("{}", emulator.cpu.isa_info_csr(Some(cmd[4..].trim()))?)
+            }
+            cmd => return Err(format!("Undefined info command: {}", cmd)),
+        },
+        'i' => emulator.cpu.isa_reg_display(),
+        'e' if line.starts_with("enable") => {
+            let args = &line["enable".len()..];
+            ctx.stops.set_enabled(emulator, args, true)?;
+        }
+        'v' if word == "vtop" => {
+            if args.trim().is_empty() {
+                return Err("Argument required (virtual address).".to_string());
+            }
+            let vaddr = parse_location(emulator, args.trim())?;
+            info!("{}", emulator.cpu.isa_vtop(vaddr));
+        }
+        'x' => {
+            let (physical, args) = match line.strip_prefix("xp") {
+                Some(args) => (true, args),
+                None => (false, &line[1..]),
+            };
+            info!("{}", examine(emulator, args, physical, &mut ctx.x_format)?);
+        } // x/NFU expr, xp/NFU expr: examine memory
+        'p' => {
+            let val = parse(line[1..].trim()).and_then(|e| eval_value(&e, emulator))?;
+            info!("result: {}", val);
+        } // p expr: eval(expr)
+        'w' => {
+            let raw_expr = line[1..].trim();
+            let expr = parse(raw_expr)?;
+            let prev_val = eval_expr(&expr, emulator)?;
+            ctx.stops.add_watch(expr, raw_expr, prev_val);
+        } // w expr: pause when mem[eval(expr)] changes
+        'b' => {
+            if line.starts_with("bt") {
+                info!("backtrace:");
+                info!("{}", emulator.cpu.isa_get_backtrace());
+                info!("itrace:");
+                info!("{}", emulator.cpu.isa_itrace());
+            } else if let Some(args) = strip_cmd(line, &["b", "break"]) {
+                ctx.stops.break_command(emulator, args, false)?;
+            } else {
+                return Err(unknown_sdb_command(line));
+            }
+        }
+        'd' => {
+            if line.starts_with("disasm") {
+                info!(
+                    "{}",
+                    emulator
+                        .cpu
+                        .isa_disassemble_inst(&VAddr::new(emulator.cpu.isa_get_pc()))
+                );
+            } else if let Some(args) = line.strip_prefix("disable") {
+                ctx.stops.set_enabled(emulator, args, false)?;
+            } else if let Some(args) = strip_cmd(line, &["d", "delete"]) {
+                ctx.stops.delete(args)?;
+            } else {
+                return Err(unknown_sdb_command(line));
+            }
+        } // d [N...]: delete breakpoints and watchpoints
+        't' if line.starts_with("tbreak") => {
+            let args = &line["tbreak".len()..];
+            ctx.stops.break_command(emulator, args, true)?;
+        }
+        't' => {
+            info!("Fn trace enable: {}", emulator.cpu.isa_toggle_ftrace());
+        } // fn trace toggle
+
+        _ => return Err(unknown_sdb_command(line)),
+    }
+    Ok(None)
+}
+
+/// Read commands from `--sdb-script` and the terminal. In batch mode only the script runs,
+/// and a failed command ends it with exit code 1.
+pub fn sdb_loop<T: Isa>(emulator: &mut Emulator<T>) -> (u64, u8) {
+    let mut ctx = DbgContext::new();
+    if let Some(interval) = emulator.checkpoint_interval {
+        ctx.checkpoints = Some(Checkpoints::new(interval, &emulator.cpu));
+    }
+    let mut input = Input::new(!emulator.batch);
+    if let Some(script) = &emulator.sdb_script {
+        if let Err(err) = input.source(script) {
+            error!("{}", err);
+            return (0, 1);
+        }
+    }
+    let mut failed = false;
+    while let Some(line) = input.next_line(&format!("({:#x})>> ", emulator.cpu.isa_get_pc())) {
+        match run_command(&mut ctx, emulator, &mut input, &line) {
+            Ok(Some(exit)) => return exit,
+            Ok(None) => input.push_front(ctx.stops.take_hit_commands()),
             Err(err) => {
-                info!("Readline Error: {:?}", err);
-                break;
+                info!("{}", err);
+                failed = true;
+                input.abort();
             }
         }
     }
-    (ctx.inst_count, 0)
+    (ctx.inst_count, (failed && emulator.batch) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tests::fake_emulator;
+
+    #[test]
+    fn reverse_continue_from_a_stop() {
+        // 1: addi a0, a0, 1; j 1b
+        let (_guard, mut emulator) = fake_emulator(&[0x00150513, 0xffdff06f]);
+        let mut ctx = DbgContext::new();
+        ctx.checkpoints = Some(Checkpoints::new(100, &emulator.cpu));
+        let mut input = Input::new(false);
+        for line in ["b 0x80000000", "c", "c", "rc"] {
+            run_command(&mut ctx, &mut emulator, &mut input, line).unwrap();
+        }
+        assert_eq!(inst_count(), 2);
+        assert_eq!(emulator.cpu.isa_get_reg_by_name("a0"), Ok(1));
+    }
+
+    #[test]
+    fn ignored_crossings_are_hits() {
+        let (_guard, mut emulator) = fake_emulator(&[0x00150513, 0xffdff06f]);
+        let mut ctx = DbgContext::new();
+        let mut input = Input::new(false);
+        for line in ["b 0x80000000", "ignore 1 1", "c"] {
+            run_command(&mut ctx, &mut emulator, &mut input, line).unwrap();
+        }
+        assert_eq!(inst_count(), 4);
+        let info = ctx.stops.info(false);
+        let hits = info.lines().find(|l| l.starts_with('1')).unwrap();
+        assert_eq!(hits.split_whitespace().nth(3), Some("2"));
+    }
 }
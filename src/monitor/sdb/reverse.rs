@@ -0,0 +1,97 @@
+//! In-memory checkpoints for reverse debugging.
+//!
+//! A checkpoint is the savevm state of the machine plus the position in the replay log. Going
+//! back to instruction N restores the last checkpoint at or before N and re-executes from there,
+//! with every nondeterministic input served from the replay history.
+
+use crate::device::{inst_count, replay};
+use crate::isa::Isa;
+use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
+use log::info;
+
+/// When full, every other checkpoint is dropped and the interval doubles.
+const MAX_CHECKPOINTS: usize = 32;
+
+struct Checkpoint {
+    inst_count: u64,
+    replay_pos: usize,
+    state: Vec<u8>,
+}
+
+pub struct Checkpoints {
+    checkpoints: Vec<Checkpoint>,
+    interval: u64,
+    next_at: u64,
+}
+
+impl Checkpoints {
+    pub fn new<T: Isa>(interval: u64, cpu: &T) -> Self {
+        replay::enable_history();
+        let mut checkpoints = Self {
+            checkpoints: Vec::new(),
+            interval,
+            next_at: 0,
+        };
+        checkpoints.take(cpu);
+        checkpoints
+    }
+
+    /// Call after every instruction.
+    #[inline]
+    pub fn maybe_take<T: Isa>(&mut self, cpu: &T) {
+        if inst_count() >= self.next_at {
+            self.take(cpu);
+        }
+    }
+
+    fn take<T: Isa>(&mut self, cpu: &T) {
+        let now = inst_count();
+        // checkpoints after now were taken on a future we have just rewound from
+        while self.checkpoints.last().is_some_and(|c| c.inst_count >= now) {
+            self.checkpoints.pop();
+        }
+        let mut w = SnapshotWriter::new();
+        cpu.isa_save_state(&mut w);
+        self.checkpoints.push(Checkpoint {
+            inst_count: now,
+            replay_pos: replay::position(),
+            state: w.into_bytes(),
+        });
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            // keep the first one, the oldest point we can go back to, and the one just taken,
+            // so stepping back from here doesn't replay from far away
+            let last = self.checkpoints.len();
+            let mut idx = 0;
+            self.checkpoints.retain(|_| {
+                idx += 1;
+                idx == 1 || idx == last || idx % 2 == 0
+            });
+            self.interval *= 2;
+            info!(
+                "Checkpoint interval increased to {} instructions",
+                self.interval
+            );
+        }
+        self.next_at = now + self.interval;
+    }
+
+    /// The earliest instruction count that can be reached.
+    pub fn earliest(&self) -> u64 {
+        self.checkpoints[0].inst_count
+    }
+
+    /// Restore the last checkpoint at or before `target`, returns its instruction count.
+    pub fn restore_before<T: Isa>(&mut self, cpu: &mut T, target: u64) -> Result<u64, String> {
+        let idx = self
+            .checkpoints
+            .partition_point(|c| c.inst_count <= target)
+            .checked_sub(1)
+            .ok_or_else(|| format!("No checkpoint before inst {}", target))?;
+        let checkpoint = &self.checkpoints[idx];
+        let mut r = SnapshotReader::from_bytes(checkpoint.state.clone());
+        cpu.isa_load_state(&mut r)?;
+        replay::rewind(checkpoint.replay_pos);
+        self.next_at = checkpoint.inst_count + self.interval;
+        Ok(checkpoint.inst_count)
+    }
+}
@@ -0,0 +1,197 @@
+//! Where sdb commands come from: lines queued by `source`, user-defined commands and
+//! breakpoint command lists, then the terminal unless sdb runs a script in batch mode.
+
+use log::info;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::collections::VecDeque;
+use std::fs;
+
+/// Nesting of user-defined commands, gdb's default `max-user-call-depth`.
+const MAX_USER_CALL_DEPTH: usize = 1024;
+
+pub(super) struct Input {
+    /// lines with the user-defined command nesting they come from
+    queue: VecDeque<(String, usize)>,
+    /// the nesting of the last line
+    depth: usize,
+    /// None in batch mode
+    editor: Option<DefaultEditor>,
+    /// repeated by an empty line at the terminal
+    last: Option<String>,
+}
+
+impl Input {
+    pub(super) fn new(interactive: bool) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            depth: 0,
+            editor: interactive.then(|| DefaultEditor::new().unwrap()),
+            last: None,
+        }
+    }
+
+    /// Queue `lines` before everything already queued.
+    pub(super) fn push_front(&mut self, lines: Vec<String>) {
+        self.push_at(lines, self.depth);
+    }
+
+    /// Queue the body of a user-defined command called by the last line.
+    pub(super) fn expand(&mut self, body: Vec<String>) -> Result<(), String> {
+        if self.depth >= MAX_USER_CALL_DEPTH {
+            return Err("Max user call depth exceeded -- command aborted.".to_string());
+        }
+        self.push_at(body, self.depth + 1);
+        Ok(())
+    }
+
+    fn push_at(&mut self, lines: Vec<String>, depth: usize) {
+        for line in lines.into_iter().rev() {
+            self.queue.push_front((line, depth));
+        }
+    }
+
+    /// `source FILE`
+    pub(super) fn source(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        self.push_front(text.lines().map(str::to_string).collect());
+        Ok(())
+    }
+
+    /// Drop the rest of the scripts after a failed command.
+    pub(super) fn abort(&mut self) {
+        self.queue.clear();
+    }
+
+    /// The next command, None at the end of the input. Queued blank lines and `#` comments
+    /// are skipped, an empty line at the terminal repeats the last command.
+    pub(super) fn next_line(&mut self, prompt: &str) -> Option<String> {
+        while let Some((line, depth)) = self.queue.pop_front() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                self.depth = depth;
+                return Some(line.to_string());
+            }
+        }
+        self.depth = 0;
+        let editor = self.editor.as_mut()?;
+        loop {
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    editor
+                        .add_history_entry(line.as_str())
+                        .expect("Rustyline err");
+                    let line = line.trim();
+                    if !line.is_empty() {
+                        self.last = Some(line.to_string());
+                    }
+                    match &self.last {
+                        Some(last) => return Some(last.clone()),
+                        None => info!("empty line"),
+                    }
+                }
+                Err(ReadlineError::Interrupted) => {
+                    info!("CTRL-C");
+                    return None;
+                }
+                Err(ReadlineError::Eof) => {
+                    info!("CTRL-D");
+                    return None;
+                }
+                Err(err) => {
+                    info!("Readline Error: {:?}", err);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// The lines of a `define` or `commands` block up to its `end`, nested blocks included.
+    pub(super) fn read_block(&mut self) -> Result<Vec<String>, String> {
+        let mut lines = Vec::new();
+        let mut depth = 0;
+        loop {
+            let line = self
+                .next_line(">")
+                .ok_or("End of input inside a block, expected end")?;
+            let word = line.split_whitespace().next().unwrap_or("");
+            match word {
+                "end" if depth == 0 => return Ok(lines),
+                "end" => depth -= 1,
+                "define" | "commands" => depth += 1,
+                _ => {}
+            }
+            lines.push(line);
+        }
+    }
+}
+
+/// Replace `$argc` and `$arg0`.. in the body of a user-defined command.
+pub(super) fn substitute_args(body: &[String], args: &str) -> Vec<String> {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    body.iter()
+        .map(|line| {
+            let mut line = line.replace("$argc", &args.len().to_string());
+            // from the last so $arg1 doesn't replace the start of $arg10
+            for (i, arg) in args.iter().enumerate().rev() {
+                line = line.replace(&format!("$arg{}", i), arg);
+            }
+            line
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_and_arguments() {
+        let mut input = Input::new(false);
+        let lines = [
+            "# comment",
+            "define f",
+            "commands",
+            "p $arg0",
+            "end",
+            "end",
+            "",
+            "c",
+        ];
+        input.push_front(lines.iter().map(|s| s.to_string()).collect());
+        assert_eq!(input.next_line("").as_deref(), Some("define f"));
+        let body = input.read_block().unwrap();
+        assert_eq!(body, ["commands", "p $arg0", "end"]);
+        assert_eq!(input.next_line("").as_deref(), Some("c"));
+        assert_eq!(input.next_line(""), None);
+        assert!(input.read_block().is_err());
+
+        let body = substitute_args(&body, "1 2");
+        assert_eq!(body[1], "p 1");
+    }
+
+    #[test]
+    fn user_call_depth() {
+        let mut input = Input::new(false);
+        input.push_front(vec!["f".to_string(), "g".to_string()]);
+        // f calls itself
+        let mut calls = 0;
+        while input.next_line("").as_deref() == Some("f") {
+            if input.expand(vec!["f".to_string()]).is_err() {
+                input.abort();
+                break;
+            }
+            calls += 1;
+        }
+        assert_eq!(calls, MAX_USER_CALL_DEPTH);
+        assert_eq!(input.next_line(""), None);
+
+        // calls one after another don't nest
+        input.push_front(vec!["f".to_string(); MAX_USER_CALL_DEPTH + 1]);
+        while let Some(line) = input.next_line("") {
+            if line == "f" {
+                input.expand(vec!["g".to_string()]).unwrap();
+            }
+        }
+    }
+}
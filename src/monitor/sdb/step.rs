@@ -0,0 +1,168 @@
+//! Commands that run the program to somewhere: `si`, `ni`, `finish`, `until`, `run-insts` and
+//! `stop-at-inst`. All of them still stop at breakpoints and watchpoints.
+
+use crate::device::inst_count;
+use crate::isa::riscv64::vaddr::{MemOperationSize, VAddr};
+use crate::isa::Isa;
+use crate::monitor::sdb::breakpoint::parse_location;
+use crate::monitor::sdb::DbgContext;
+use crate::utils::symbols::symbols;
+use crate::Emulator;
+use log::info;
+
+/// How a run ended.
+pub(super) enum RunEnd {
+    /// the command got where it was going
+    Done,
+    /// at a breakpoint or watchpoint, which was reported
+    Stopped,
+    /// the program ended, with the instruction count and exit code `sdb_loop` returns
+    Exited(u64, u8),
+}
+
+/// Length of the call at `pc`: a jal, jalr or c.jalr linking ra or t0.
+fn call_len<T: Isa>(cpu: &T, pc: u64) -> Option<u64> {
+    let half = cpu
+        .peek_inst_vaddr(&VAddr::new(pc), MemOperationSize::WORD)
+        .ok()?;
+    if half & 0b11 != 0b11 {
+        // c.jalr rs1, it always links ra
+        let is_call = half & 0xf07f == 0x9002 && (half >> 7) & 0x1f != 0;
+        return is_call.then_some(2);
+    }
+    let inst = cpu
+        .peek_inst_vaddr(&VAddr::new(pc), MemOperationSize::DWORD)
+        .ok()?;
+    let rd = (inst >> 7) & 0x1f;
+    let is_call = matches!(inst & 0x7f, 0x6f | 0x67) && (rd == 1 || rd == 5);
+    is_call.then_some(4)
+}
+
+fn sp<T: Isa>(emulator: &Emulator<T>) -> u64 {
+    emulator.cpu.isa_get_reg_by_name("sp").unwrap_or(0)
+}
+
+/// A count argument, 1 if there is none.
+pub(super) fn parse_count(args: &str) -> Result<u64, String> {
+    match args.trim() {
+        "" => Ok(1),
+        n => n.parse().map_err(|_| format!("bad count {}", n)),
+    }
+}
+
+/// Print where a finished run is, or hand over the exit of the program.
+pub(super) fn end_run<T: Isa>(emulator: &mut Emulator<T>, end: RunEnd) -> Option<(u64, u8)> {
+    match end {
+        RunEnd::Done => {
+            let pc = emulator.cpu.isa_get_pc();
+            let inst = emulator.cpu.isa_disassemble_inst(&VAddr::new(pc));
+            match symbols().lookup(pc) {
+                Some(_) => info!("{:#x} <{}>:\t{}", pc, symbols().format(pc), inst),
+                None => info!("{:#x}:\t{}", pc, inst),
+            }
+            None
+        }
+        RunEnd::Stopped => None,
+        RunEnd::Exited(count, code) => Some((count, code)),
+    }
+}
+
+impl DbgContext {
+    /// Execute until `done` is true after an instruction, a stop point is hit or the program
+    /// ends.
+    pub(super) fn run_until<T: Isa>(
+        &mut self,
+        emulator: &mut Emulator<T>,
+        mut done: impl FnMut(&mut Emulator<T>) -> bool,
+    ) -> RunEnd {
+        loop {
+            self.inst_count += 1;
+            let (not_halt, pause, sdl_quit) = self.exec_once_dbg(emulator);
+            if !not_halt {
+                return RunEnd::Exited(self.inst_count, emulator.cpu.isa_get_exit_code());
+            }
+            if sdl_quit {
+                return RunEnd::Exited(self.inst_count, 0);
+            }
+            if pause {
+                return RunEnd::Stopped;
+            }
+            if done(emulator) {
+                return RunEnd::Done;
+            }
+        }
+    }
+
+    /// `si N`, or with `over` `ni N`, which runs each call until it returns.
+    pub(super) fn step<T: Isa>(
+        &mut self,
+        emulator: &mut Emulator<T>,
+        count: u64,
+        over: bool,
+    ) -> RunEnd {
+        for _ in 0..count {
+            let pc = emulator.cpu.isa_get_pc();
+            let ret = match over {
+                true => call_len(&emulator.cpu, pc).map(|len| pc + len),
+                false => None,
+            };
+            // a recursive call returns to the same address with a lower sp
+            let frame_sp = sp(emulator);
+            let end = self.run_until(emulator, |emulator| match ret {
+                Some(ret) => emulator.cpu.isa_get_pc() == ret && sp(emulator) >= frame_sp,
+                None => true,
+            });
+            if !matches!(end, RunEnd::Done) {
+                return end;
+            }
+        }
+        RunEnd::Done
+    }
+
+    /// `finish`: run until the current function returns and show its return value.
+    pub(super) fn finish<T: Isa>(&mut self, emulator: &mut Emulator<T>) -> Result<RunEnd, String> {
+        let ret = emulator
+            .cpu
+            .isa_return_address()
+            .ok_or("\"finish\" not meaningful in the outermost frame.")?;
+        info!("Run till exit to {:#x} <{}>", ret, symbols().format(ret));
+        let frame_sp = sp(emulator);
+        let end = self.run_until(emulator, |emulator| {
+            emulator.cpu.isa_get_pc() == ret && sp(emulator) >= frame_sp
+        });
+        if matches!(end, RunEnd::Done) {
+            let a0 = emulator.cpu.isa_get_reg_by_name("a0")?;
+            info!("Value returned: $a0 = {:#x} ({})", a0, a0 as i64);
+        }
+        Ok(end)
+    }
+
+    /// `until ADDR`: run until the pc gets to ADDR.
+    pub(super) fn until<T: Isa>(
+        &mut self,
+        emulator: &mut Emulator<T>,
+        args: &str,
+    ) -> Result<RunEnd, String> {
+        let args = args.trim();
+        if args.is_empty() {
+            return Err("Argument required (location to run until).".to_string());
+        }
+        let addr = parse_location(emulator, args)?;
+        Ok(self.run_until(emulator, |emulator| emulator.cpu.isa_get_pc() == addr))
+    }
+
+    /// Run until `target` instructions have been executed, the count `goto` uses.
+    pub(super) fn run_to_inst<T: Isa>(
+        &mut self,
+        emulator: &mut Emulator<T>,
+        target: u64,
+    ) -> Result<RunEnd, String> {
+        if target <= inst_count() {
+            return Err(format!(
+                "Already at inst {}, use goto to go back",
+                inst_count()
+            ));
+        }
+        Ok(self.run_until(emulator, |_| inst_count() >= target))
+    }
+}
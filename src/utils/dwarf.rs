@@ -0,0 +1,198 @@
+//! Call frame information and line tables of an ELF file, for the unwinder.
+
+use crate::utils::elf::ElfFile;
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EhFrame, EndianSlice, LittleEndian, Register, RegisterRule,
+    UnwindContext, UnwindSection,
+};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+type Slice<'a> = EndianSlice<'a, LittleEndian>;
+
+/// Where the caller's value of a register is.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RegRule {
+    /// still in the register
+    Unchanged,
+    /// saved at CFA+offset
+    AtCfa(i64),
+    /// not recoverable, the return address column uses this to end the stack
+    Undefined,
+}
+
+/// How to unwind one frame: the CFA is `cfa_reg + cfa_offset`, `regs` as requested.
+#[derive(Debug)]
+pub struct FrameRule {
+    pub cfa_reg: u16,
+    pub cfa_offset: i64,
+    pub regs: Vec<RegRule>,
+}
+
+struct LineRow {
+    addr: u64,
+    /// index into `files`, u32::MAX ends a sequence
+    file: u32,
+    line: u32,
+}
+
+pub struct DebugInfo {
+    /// added to every address of the file, like for symbols
+    offset: u64,
+    /// (name, addr, data) of .eh_frame and .debug_*
+    sections: Vec<(String, u64, Vec<u8>)>,
+    /// base of pc-relative pointers in .eh_frame
+    text_addr: Option<u64>,
+    /// file names and rows sorted by address
+    lines: OnceLock<(Vec<String>, Vec<LineRow>)>,
+}
+
+impl DebugInfo {
+    /// None if `elf` has neither call frame information nor line tables.
+    pub fn new(elf: &ElfFile, offset: u64) -> Option<Self> {
+        let sections: Vec<_> = elf
+            .sections
+            .iter()
+            .filter(|s| s.name == ".eh_frame" || s.name.starts_with(".debug_"))
+            .filter_map(|s| {
+                let (section, data) = elf.section_data(&s.name)?;
+                Some((section.name.clone(), section.addr, data.to_vec()))
+            })
+            .collect();
+        let useful = [".eh_frame", ".debug_frame", ".debug_line"];
+        if !sections
+            .iter()
+            .any(|(name, ..)| useful.contains(&name.as_str()))
+        {
+            return None;
+        }
+        Some(Self {
+            offset,
+            sections,
+            text_addr: elf.section_data(".text").map(|(section, _)| section.addr),
+            lines: OnceLock::new(),
+        })
+    }
+
+    fn section(&self, name: &str) -> Option<(u64, &[u8])> {
+        self.sections
+            .iter()
+            .find(|(n, ..)| n == name)
+            .map(|(_, addr, data)| (*addr, data.as_slice()))
+    }
+
+    /// Unwind rule at `pc` from .debug_frame or .eh_frame, with a rule for each of `regs`.
+    pub fn frame_rule(&self, pc: u64, regs: &[u16]) -> Option<FrameRule> {
+        let pc = pc.wrapping_sub(self.offset);
+        if let Some((_, data)) = self.section(".debug_frame") {
+            let mut frame = DebugFrame::new(data, LittleEndian);
+            frame.set_address_size(8);
+            if let Some(rule) = find_rule(&frame, BaseAddresses::default(), pc, regs) {
+                return Some(rule);
+            }
+        }
+        let (addr, data) = self.section(".eh_frame")?;
+        let mut frame = EhFrame::new(data, LittleEndian);
+        frame.set_address_size(8);
+        let mut bases = BaseAddresses::default().set_eh_frame(addr);
+        if let Some(text) = self.text_addr {
+            bases = bases.set_text(text);
+        }
+        find_rule(&frame, bases, pc, regs)
+    }
+
+    fn load_lines(&self) -> (Vec<String>, Vec<LineRow>) {
+        let mut files = Vec::new();
+        let mut rows = Vec::new();
+        let load = |id: gimli::SectionId| -> Result<Slice, ()> {
+            let data = self.section(id.name()).map_or(&[][..], |(_, data)| data);
+            Ok(EndianSlice::new(data, LittleEndian))
+        };
+        let Ok(dwarf) = gimli::Dwarf::load(load) else {
+            return (files, rows);
+        };
+        let mut file_ids = HashMap::new();
+        let mut units = dwarf.units();
+        while let Ok(Some(header)) = units.next() {
+            let Ok(unit) = dwarf.unit(header) else {
+                continue;
+            };
+            let Some(program) = unit.line_program.clone() else {
+                continue;
+            };
+            let mut program_rows = program.rows();
+            while let Ok(Some((header, row))) = program_rows.next_row() {
+                if row.end_sequence() {
+                    rows.push(LineRow {
+                        addr: row.address(),
+                        file: u32::MAX,
+                        line: 0,
+                    });
+                    continue;
+                }
+                let Some(name) = row
+                    .file(header)
+                    .and_then(|file| dwarf.attr_string(&unit, file.path_name()).ok())
+                else {
+                    continue;
+                };
+                let name = name.to_string_lossy();
+                let file = *file_ids.entry(name.to_string()).or_insert_with(|| {
+                    files.push(name.to_string());
+                    files.len() as u32 - 1
+                });
+                rows.push(LineRow {
+                    addr: row.address(),
+                    file,
+                    line: row.line().map_or(0, |line| line.get() as u32),
+                });
+            }
+        }
+        rows.sort_by_key(|row| row.addr);
+        (files, rows)
+    }
+
+    /// `file:line` of `pc`, the line tables are parsed on first use.
+    pub fn line(&self, pc: u64) -> Option<String> {
+        let pc = pc.wrapping_sub(self.offset);
+        self.section(".debug_line")?;
+        let (files, rows) = self.lines.get_or_init(|| self.load_lines());
+        let idx = rows.partition_point(|row| row.addr <= pc);
+        let row = rows[..idx].last().filter(|row| row.file != u32::MAX)?;
+        let file = files.get(row.file as usize)?;
+        Some(format!("{}:{}", file, row.line))
+    }
+}
+
+fn find_rule<'a, S: UnwindSection<Slice<'a>>>(
+    frame: &S,
+    bases: BaseAddresses,
+    pc: u64,
+    regs: &[u16],
+) -> Option<FrameRule> {
+    let mut ctx = UnwindContext::new();
+    let row = frame
+        .unwind_info_for_address(&bases, &mut ctx, pc, S::cie_from_offset)
+        .ok()?;
+    let CfaRule::RegisterAndOffset { register, offset } = row.cfa() else {
+        return None;
+    };
+    let mut rules = Vec::with_capacity(regs.len());
+    for &reg in regs {
+        let rule = row
+            .registers()
+            .find(|(r, _)| *r == Register(reg))
+            .map(|(_, rule)| rule);
+        rules.push(match rule {
+            None | Some(RegisterRule::SameValue) => RegRule::Unchanged,
+            Some(RegisterRule::Offset(ofs)) => RegRule::AtCfa(*ofs),
+            Some(RegisterRule::Undefined) => RegRule::Undefined,
+            Some(_) => return None,
+        });
+    }
+    Some(FrameRule {
+        cfa_reg: register.0,
+        cfa_offset: *offset,
+        regs: rules,
+    })
+}
@@ -0,0 +1,257 @@
+//! Minimal reader for 64-bit little endian ELF files: segments, sections and symbols.
+
+use std::fs::File;
+use std::io::Read;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+pub const EM_RISCV: u16 = 243;
+pub const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+pub const STT_FUNC: u8 = 2;
+
+pub struct Segment {
+    pub typ: u32,
+    pub offset: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+pub struct Section {
+    pub name: String,
+    pub typ: u32,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    link: u32,
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    pub typ: u8,
+}
+
+pub struct ElfFile {
+    pub data: Vec<u8>,
+    pub entry: u64,
+    pub machine: u16,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+}
+
+fn bytes_at<const N: usize>(data: &[u8], ofs: usize) -> Result<[u8; N], String> {
+    ofs.checked_add(N)
+        .and_then(|end| data.get(ofs..end))
+        .map(|b| b.try_into().unwrap())
+        .ok_or(format!("truncated at {:#x}", ofs))
+}
+
+fn u16_at(data: &[u8], ofs: usize) -> Result<u16, String> {
+    bytes_at(data, ofs).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], ofs: usize) -> Result<u32, String> {
+    bytes_at(data, ofs).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], ofs: usize) -> Result<u64, String> {
+    bytes_at(data, ofs).map(u64::from_le_bytes)
+}
+
+/// Offset of entry `i` of the table at `table`. It is inside `data`, so adding a field offset
+/// can't overflow.
+fn entry_at(data: &[u8], table: usize, i: usize, entsize: usize) -> Result<usize, String> {
+    i.checked_mul(entsize)
+        .and_then(|ofs| ofs.checked_add(table))
+        .filter(|&ofs| ofs < data.len())
+        .ok_or(format!(
+            "truncated at entry {} of the table at {:#x}",
+            i, table
+        ))
+}
+
+/// The string at `ofs` in the string table at `table`.
+fn str_at(data: &[u8], table: usize, ofs: usize) -> Result<String, String> {
+    let bytes = table
+        .checked_add(ofs)
+        .and_then(|start| data.get(start..))
+        .ok_or(format!(
+            "truncated at string {:#x} of the table at {:#x}",
+            ofs, table
+        ))?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+impl ElfFile {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(ELF_MAGIC)
+    }
+
+    /// Whether the file at `path` starts with the ELF magic, without reading all of it.
+    #[cfg_attr(not(feature = "difftest"), allow(dead_code))]
+    pub fn is_elf_file(path: &str) -> bool {
+        let mut magic = [0u8; 4];
+        File::open(path)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .is_ok_and(|_| Self::is_elf(&magic))
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+        Self::parse(data).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self, String> {
+        if !Self::is_elf(&data) {
+            return Err("not an ELF file".to_string());
+        }
+        if data.get(4) != Some(&ELFCLASS64) || data.get(5) != Some(&ELFDATA2LSB) {
+            return Err("only 64-bit little endian ELF files are supported".to_string());
+        }
+        let machine = u16_at(&data, 0x12)?;
+        let entry = u64_at(&data, 0x18)?;
+        let phoff = u64_at(&data, 0x20)? as usize;
+        let shoff = u64_at(&data, 0x28)? as usize;
+        let phentsize = u16_at(&data, 0x36)? as usize;
+        let phnum = u16_at(&data, 0x38)? as usize;
+        let shentsize = u16_at(&data, 0x3a)? as usize;
+        let shnum = u16_at(&data, 0x3c)? as usize;
+        let shstrndx = u16_at(&data, 0x3e)? as usize;
+
+        let mut segments = Vec::with_capacity(phnum);
+        for i in 0..phnum {
+            let ph = entry_at(&data, phoff, i, phentsize)?;
+            segments.push(Segment {
+                typ: u32_at(&data, ph)?,
+                offset: u64_at(&data, ph + 0x8)?,
+                paddr: u64_at(&data, ph + 0x18)?,
+                filesz: u64_at(&data, ph + 0x20)?,
+                memsz: u64_at(&data, ph + 0x28)?,
+            });
+        }
+
+        let mut sections = Vec::with_capacity(shnum);
+        let mut name_offsets = Vec::with_capacity(shnum);
+        for i in 0..shnum {
+            let sh = entry_at(&data, shoff, i, shentsize)?;
+            name_offsets.push(u32_at(&data, sh)? as usize);
+            sections.push(Section {
+                name: String::new(),
+                typ: u32_at(&data, sh + 0x4)?,
+                addr: u64_at(&data, sh + 0x10)?,
+                offset: u64_at(&data, sh + 0x18)?,
+                size: u64_at(&data, sh + 0x20)?,
+                link: u32_at(&data, sh + 0x28)?,
+            });
+        }
+        if let Some(shstrtab) = sections.get(shstrndx).map(|s| s.offset as usize) {
+            for (section, name_ofs) in sections.iter_mut().zip(name_offsets) {
+                section.name = str_at(&data, shstrtab, name_ofs)?;
+            }
+        }
+
+        Ok(Self {
+            data,
+            entry,
+            machine,
+            segments,
+            sections,
+        })
+    }
+
+    /// File contents of `segment`, without the zero-filled part.
+    pub fn segment_data(&self, segment: &Segment) -> Result<&[u8], String> {
+        let start = segment.offset as usize;
+        start
+            .checked_add(segment.filesz as usize)
+            .and_then(|end| self.data.get(start..end))
+            .ok_or(format!("segment at {:#x} is truncated", segment.paddr))
+    }
+
+    /// Contents of the section called `name`.
+    pub fn section_data(&self, name: &str) -> Option<(&Section, &[u8])> {
+        let section = self.sections.iter().find(|s| s.name == name)?;
+        let start = section.offset as usize;
+        let data = self
+            .data
+            .get(start..start.checked_add(section.size as usize)?)?;
+        Some((section, data))
+    }
+
+    /// Symbols of .symtab, or of .dynsym for stripped files.
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let symtab = self
+            .sections
+            .iter()
+            .find(|s| s.typ == SHT_SYMTAB)
+            .or_else(|| self.sections.iter().find(|s| s.typ == SHT_DYNSYM));
+        let Some(symtab) = symtab else {
+            return Vec::new();
+        };
+        let Some(strtab) = self.sections.get(symtab.link as usize) else {
+            return Vec::new();
+        };
+        let mut symbols = Vec::new();
+        for i in 0..symtab.size as usize / 24 {
+            let Ok(sym) = entry_at(&self.data, symtab.offset as usize, i, 24) else {
+                break;
+            };
+            let (Ok(name), Some(&info), Ok(value), Ok(size)) = (
+                u32_at(&self.data, sym),
+                self.data.get(sym + 4),
+                u64_at(&self.data, sym + 8),
+                u64_at(&self.data, sym + 16),
+            ) else {
+                break;
+            };
+            let Ok(name) = str_at(&self.data, strtab.offset as usize, name as usize) else {
+                break;
+            };
+            symbols.push(Symbol {
+                name,
+                value,
+                size,
+                typ: info & 0xf,
+            });
+        }
+        symbols
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ELF header with one program header at `phoff` and no sections.
+    fn header(phoff: u64) -> Vec<u8> {
+        let mut data = vec![0u8; 0x40];
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[4] = ELFCLASS64;
+        data[5] = ELFDATA2LSB;
+        data[0x20..0x28].copy_from_slice(&phoff.to_le_bytes());
+        data[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        data[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn truncated_tables() {
+        for phoff in [u64::MAX, u64::MAX - 0x10, 0x40] {
+            let err = ElfFile::parse(header(phoff)).err().unwrap();
+            assert!(err.starts_with("truncated"), "{}", err);
+        }
+        assert_eq!(
+            u64_at(&[0; 8], usize::MAX - 4),
+            Err("truncated at 0xfffffffffffffffb".into())
+        );
+        assert!(str_at(b"name\0", usize::MAX, 1).is_err());
+        assert_eq!(str_at(b"\0name\0", 0, 1), Ok("name".to_string()));
+    }
+}
@@ -1,5 +1,9 @@
 pub mod configs;
 pub mod disasm;
+pub mod dwarf;
+pub mod elf;
+pub mod snapshot;
+pub mod symbols;
 
 macro_rules! cfg_if_feat {
     ($feature:literal, $({ $($tokens:tt)* })?) => {
@@ -13,34 +17,55 @@ macro_rules! cfg_if_feat {
 
 pub(crate) use cfg_if_feat;
 
-// #[cfg(test)]
-// pub mod tests {
-//     use crate::device::Devices;
-//     use crate::isa::riscv64::RISCV64;
-//     use crate::isa::Isa;
-//     use crate::memory::Memory;
-//     use crate::monitor::Args;
-//     use crate::Emulator;
-//     use std::sync::atomic::AtomicBool;
-//     use std::sync::Arc;
-//
-//     pub fn fake_emulator() -> Emulator<RISCV64> {
-//         let mut memory = Memory::new(); // init mem
-//         let stopped = Arc::new(AtomicBool::new(false));
-//         let device = Devices::new(stopped.clone(), &mut memory, false); // init device
-//         let cpu = RISCV64::new(
-//             stopped,
-//             memory,
-//             &Args {
-//                 ..Default::default()
-//             },
-//         );
-//         Emulator::<RISCV64> {
-//             cpu,
-//             device,
-//             difftest_ctx: None,
-//             batch: false,
-//             exitcode: 0,
-//         }
-//     }
-// }
+#[cfg(test)]
+pub mod tests {
+    use crate::device::{set_inst_count, Devices};
+    use crate::isa::riscv64::RISCV64;
+    use crate::isa::Isa;
+    use crate::memory::Memory;
+    use crate::monitor::Args;
+    use crate::utils::configs::CONFIG_MEM_BASE;
+    use crate::Emulator;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    static EMULATOR_LOCK: Mutex<()> = Mutex::new(());
+
+    /// The instruction count is global, tests executing instructions hold this to run one at
+    /// a time.
+    pub fn emulator_lock() -> MutexGuard<'static, ()> {
+        let guard = EMULATOR_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_inst_count(0);
+        guard
+    }
+
+    /// An emulator without devices that runs `program` from the start of memory.
+    pub fn fake_emulator(program: &[u32]) -> (MutexGuard<'static, ()>, Emulator<RISCV64>) {
+        let guard = emulator_lock();
+        let mut memory = Memory::new();
+        let image: Vec<u8> = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        memory
+            .load(CONFIG_MEM_BASE.value(), &image, image.len() as u64)
+            .unwrap();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let device = Devices::detached(stopped.clone());
+        let cpu = RISCV64::new(
+            stopped,
+            memory,
+            device.cpu_interrupt_bits.clone(),
+            &Args::default(),
+        );
+        let emulator = Emulator {
+            cpu,
+            device,
+            difftest_ctx: None,
+            batch: true,
+            difftest_interval: 0,
+            checkpoint_interval: None,
+            gdb_port: None,
+            sdb_script: None,
+            exitcode: 0,
+        };
+        (guard, emulator)
+    }
+}
@@ -0,0 +1,145 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"NEMUSNAP";
+const SNAPSHOT_VERSION: u64 = 1;
+
+/// Little endian serializer of machine state, used by savevm/loadvm.
+pub struct SnapshotWriter {
+    buf: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn put_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn put_bool(&mut self, val: bool) {
+        self.buf.push(val as u8);
+    }
+
+    pub fn put_u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// length prefixed
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_u64(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn save(self, path: &str) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| format!("couldn't create {}: {}", path, e))?;
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::fast());
+        encoder
+            .write_all(SNAPSHOT_MAGIC)
+            .and_then(|_| encoder.write_all(&SNAPSHOT_VERSION.to_le_bytes()))
+            .and_then(|_| encoder.write_all(&self.buf))
+            .and_then(|_| encoder.finish())
+            .and_then(|mut w| w.flush())
+            .map_err(|e| format!("couldn't write {}: {}", path, e))
+    }
+}
+
+pub struct SnapshotReader {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl SnapshotReader {
+    pub fn from_bytes(buf: Vec<u8>) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("couldn't open {}: {}", path, e))?;
+        let mut buf = Vec::new();
+        GzDecoder::new(BufReader::new(file))
+            .read_to_end(&mut buf)
+            .map_err(|e| format!("couldn't read {}: {}", path, e))?;
+        let mut res = Self::from_bytes(buf);
+        if res.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err("not a nemu-rust snapshot".to_string());
+        }
+        let version = res.get_u64()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot version {} is not supported (expected {})",
+                version, SNAPSHOT_VERSION
+            ));
+        }
+        Ok(res)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        if self.pos + len > self.buf.len() {
+            return Err("snapshot truncated".to_string());
+        }
+        let res = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(res)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, String> {
+        Ok(self.get_u8()? != 0)
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&[u8], String> {
+        let len = self.get_u64()? as usize;
+        self.take(len)
+    }
+
+    /// copy a length prefixed blob into `dst`, which must have the same length
+    pub fn get_bytes_into(&mut self, dst: &mut [u8]) -> Result<(), String> {
+        let src = self.get_bytes()?;
+        if src.len() != dst.len() {
+            return Err(format!(
+                "snapshot field size mismatch: {} vs {}",
+                src.len(),
+                dst.len()
+            ));
+        }
+        dst.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::snapshot::{SnapshotReader, SnapshotWriter};
+
+    #[test]
+    fn round_trip() {
+        let mut w = SnapshotWriter::new();
+        w.put_u8(0x5a);
+        w.put_bool(true);
+        w.put_u64(0x8000000a00006000);
+        w.put_bytes(&[1, 2, 3]);
+        let mut r = SnapshotReader::from_bytes(w.into_bytes());
+        assert_eq!(r.get_u8(), Ok(0x5a));
+        assert_eq!(r.get_bool(), Ok(true));
+        assert_eq!(r.get_u64(), Ok(0x8000000a00006000));
+        let mut buf = [0u8; 2];
+        assert!(r.get_bytes_into(&mut buf).is_err());
+        assert!(r.get_u8().is_err());
+    }
+}
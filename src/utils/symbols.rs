@@ -0,0 +1,156 @@
+use crate::utils::dwarf::{DebugInfo, FrameRule};
+use crate::utils::elf::{ElfFile, STT_FUNC};
+use log::info;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+static SYMBOLS: OnceLock<SymbolTable> = OnceLock::new();
+
+/// Function symbols of the guest, from --symbols and ELF images. Set once at startup.
+pub fn symbols() -> &'static SymbolTable {
+    SYMBOLS.get_or_init(SymbolTable::default)
+}
+
+pub fn set_symbols(table: SymbolTable) {
+    if SYMBOLS.set(table).is_err() {
+        panic!("symbols are already loaded");
+    }
+}
+
+struct Func {
+    start: u64,
+    end: u64,
+    name: String,
+}
+
+#[derive(Default)]
+pub struct SymbolTable {
+    /// sorted by start
+    funcs: Vec<Func>,
+    /// functions, variables and labels by name, for expressions
+    addrs: HashMap<String, u64>,
+    /// call frame information and line tables of the files that have them
+    debug: Vec<DebugInfo>,
+}
+
+/// `FILE[@OFFSET]`, the offset is added to every symbol and may be negative.
+fn parse_spec(spec: &str) -> Result<(&str, u64), String> {
+    let Some((path, offset)) = spec.rsplit_once('@') else {
+        return Ok((spec, 0));
+    };
+    let (neg, offset) = match offset.strip_prefix('-') {
+        Some(offset) => (true, offset),
+        None => (false, offset),
+    };
+    let offset = match offset.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => offset.parse(),
+    }
+    .map_err(|e| format!("bad offset in {}: {}", spec, e))?;
+    Ok((path, if neg { offset.wrapping_neg() } else { offset }))
+}
+
+impl SymbolTable {
+    pub fn add_elf(&mut self, elf: &ElfFile, offset: u64) -> usize {
+        let mut cnt = 0;
+        for sym in elf.symbols() {
+            // sections and files don't name anything an expression would use
+            if sym.value == 0 || sym.name.is_empty() || sym.typ > STT_FUNC {
+                continue;
+            }
+            let start = sym.value.wrapping_add(offset);
+            self.addrs.entry(sym.name.clone()).or_insert(start);
+            if sym.typ != STT_FUNC {
+                continue;
+            }
+            self.funcs.push(Func {
+                start,
+                end: start.saturating_add(sym.size.max(1)),
+                name: sym.name,
+            });
+            cnt += 1;
+        }
+        self.funcs.sort_by_key(|f| f.start);
+        self.debug.extend(DebugInfo::new(elf, offset));
+        cnt
+    }
+
+    /// Load `FILE[@OFFSET]`.
+    pub fn add_file(&mut self, spec: &str) -> Result<(), String> {
+        let (path, offset) = parse_spec(spec)?;
+        let elf = ElfFile::load(path)?;
+        let cnt = self.add_elf(&elf, offset);
+        info!("{} function symbols loaded from {}", cnt, path);
+        Ok(())
+    }
+
+    /// The function containing `addr` and the offset into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let idx = self.funcs.partition_point(|f| f.start <= addr);
+        let func = &self.funcs[..idx].iter().rev().find(|f| addr < f.end)?;
+        Some((&func.name, addr - func.start))
+    }
+
+    /// Address of the function, variable or label called `name`.
+    pub fn addr_of(&self, name: &str) -> Option<u64> {
+        self.addrs.get(name).copied()
+    }
+
+    /// The function starting at `addr`.
+    pub fn func_at(&self, addr: u64) -> Option<&str> {
+        let idx = self.funcs.partition_point(|f| f.start < addr);
+        self.funcs
+            .get(idx)
+            .filter(|f| f.start == addr)
+            .map(|f| f.name.as_str())
+    }
+
+    /// How to unwind the frame at `pc`, if some file has call frame information for it.
+    pub fn frame_rule(&self, pc: u64, regs: &[u16]) -> Option<FrameRule> {
+        self.debug.iter().find_map(|d| d.frame_rule(pc, regs))
+    }
+
+    /// `file:line` of `pc`, with DWARF line tables.
+    pub fn line(&self, pc: u64) -> Option<String> {
+        self.debug.iter().find_map(|d| d.line(pc))
+    }
+
+    /// `name+0x10`, or the bare address without a symbol.
+    pub fn format(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, ofs)) => format!("{}+{:#x}", name, ofs),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_and_offsets() {
+        assert_eq!(parse_spec("vmlinux@0x10").unwrap(), ("vmlinux", 0x10));
+        assert_eq!(
+            parse_spec("a.elf@-0x10").unwrap().1,
+            0u64.wrapping_sub(0x10)
+        );
+        assert_eq!(parse_spec("fw_jump.elf").unwrap(), ("fw_jump.elf", 0));
+
+        let mut table = SymbolTable::default();
+        for (start, end, name) in [(0x100, 0x200, "outer"), (0x140, 0x150, "inner")] {
+            table.funcs.push(Func {
+                start,
+                end,
+                name: name.to_string(),
+            });
+        }
+        assert_eq!(table.format(0x100), "outer");
+        assert_eq!(table.format(0x144), "inner+0x4");
+        assert_eq!(table.format(0x160), "outer+0x60");
+        assert_eq!(table.format(0x200), "0x200");
+        assert_eq!(table.func_at(0x140), Some("inner"));
+        assert_eq!(table.func_at(0x144), None);
+    }
+}